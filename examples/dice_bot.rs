@@ -0,0 +1,62 @@
+//! Rolls dice with `/roll 2d6` and greets people joining its room.
+//!
+//! ```bash
+//! cargo run --example dice_bot -- 127.0.0.1:8080
+//! ```
+
+use std::io;
+
+use rand::{Rng, rng};
+use yarca::bot::{Bot, Command, Context, Handler, key_from_env};
+
+struct Dice;
+
+impl Handler for Dice {
+    fn on_connect(&mut self, ctx: &mut Context) -> io::Result<()> {
+        println!("Connected as {} in #{}", ctx.nick(), ctx.room());
+        Ok(())
+    }
+
+    fn on_join(&mut self, ctx: &mut Context, nick: &str, _room: &str) -> io::Result<()> {
+        ctx.send(format!("Welcome {nick}! Try /roll 2d6."))
+    }
+
+    fn on_command(&mut self, ctx: &mut Context, command: &Command) -> io::Result<()> {
+        let dice = command.args().next().unwrap_or("1d6");
+        match roll(dice) {
+            Some((rolls, total)) => ctx.reply(command, format!("{dice}: {rolls:?} = {total}")),
+            None => ctx.reply(command, "usage: /roll <count>d<sides>, like 2d6"),
+        }
+    }
+
+    fn on_disconnect(&mut self, error: &io::Error) {
+        eprintln!("Disconnected: {error}, reconnecting...");
+    }
+}
+
+fn roll(dice: &str) -> Option<(Vec<u32>, u32)> {
+    let (count, sides) = dice.split_once('d')?;
+    let count: u32 = if count.is_empty() {
+        1
+    } else {
+        count.parse().ok()?
+    };
+    let sides: u32 = sides.parse().ok()?;
+    if !(1..=20).contains(&count) || !(2..=1000).contains(&sides) {
+        return None;
+    }
+    let rolls: Vec<u32> = (0..count).map(|_| rng().random_range(1..=sides)).collect();
+    let total = rolls.iter().sum();
+    Some((rolls, total))
+}
+
+fn main() -> io::Result<()> {
+    dotenvy::dotenv().ok();
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:8080".into());
+
+    Bot::new(addr, "dicebot", key_from_env("SECRET")?)
+        .command("roll", "Rolls dice, like /roll 2d6")
+        .run(&mut Dice)
+}
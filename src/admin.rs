@@ -0,0 +1,61 @@
+//! Admin protocol, spoken over the server's local Unix socket by `yarca-admin`.
+//!
+//! Each connection sends one [`AdminRequest`] as a JSON line and gets one
+//! [`AdminReply`] back, also as a JSON line. Anyone who can open the socket is
+//! an administrator, so it is only readable and writable by the server's user.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Lists the clients connected here and on linked servers.
+    Clients,
+    /// Disconnects someone connected here.
+    Kick {
+        nick: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Bans a nickname or an IP address, and disconnects whoever it matches.
+    Ban { target: String },
+    /// Sends a system message to everyone connected here.
+    Announce { text: String },
+    /// Reads the config file again, like `SIGHUP`.
+    Reload,
+    /// Returns the metrics, in the Prometheus text format.
+    Stats,
+    /// Reads the clients' key again and disconnects everyone, for them to
+    /// reconnect with the new one.
+    RotateKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminReply {
+    /// What was done, or the stats.
+    Done {
+        text: String,
+    },
+    Clients {
+        clients: Vec<ClientInfo>,
+    },
+    Error {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub nick: String,
+    pub room: String,
+    /// Address of clients connected here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// Linked server of the others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// When they connected, or when the link told us about them.
+    pub connected: DateTime<Utc>,
+}
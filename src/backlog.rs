@@ -0,0 +1,110 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::Path,
+};
+
+use chrono::Utc;
+use yarca::protocol::ServerFrame;
+
+use crate::config::Retention;
+
+/// Recent messages of every room, replayed to the people joining it.
+pub struct Backlog {
+    retention: Retention,
+    rooms: HashMap<String, VecDeque<ServerFrame>>,
+}
+
+impl Backlog {
+    pub fn new(retention: Retention) -> Self {
+        Backlog {
+            retention,
+            rooms: HashMap::new(),
+        }
+    }
+
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        for room in rooms {
+            self.prune(&room);
+        }
+    }
+
+    /// Keeps a `ServerFrame::Message` sent to `room`.
+    pub fn push(&mut self, room: &str, message: ServerFrame) {
+        if self.retention.messages == 0 {
+            return;
+        }
+        self.rooms
+            .entry(room.to_string())
+            .or_default()
+            .push_back(message);
+        self.prune(room);
+    }
+
+    /// Reads the history `save` left, if there is one.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(format!("Cannot read history {}: {e}", path.display())),
+        };
+        self.rooms = serde_json::from_str(&content)
+            .map_err(|e| format!("History {} is corrupted: {e}", path.display()))?;
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        for room in rooms {
+            self.prune(&room);
+        }
+        Ok(())
+    }
+
+    /// Writes every room's history to `path`, replacing it at once so that a
+    /// crash halfway through leaves the previous one.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string(&self.rooms).expect("Frames always serialize.");
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, json)
+            .and_then(|()| fs::rename(&temporary, path))
+            .map_err(|e| format!("Cannot save history {}: {e}", path.display()))
+    }
+
+    /// Highest message ID kept, so that IDs go on from there after a restart.
+    pub fn last_id(&self) -> u64 {
+        self.rooms
+            .values()
+            .flatten()
+            .filter_map(|message| match message {
+                ServerFrame::Message { id, .. } => Some(*id),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Messages of `room` that are still within the retention, oldest first.
+    pub fn replay(&mut self, room: &str) -> impl Iterator<Item = &ServerFrame> {
+        self.prune(room);
+        self.rooms.get(room).into_iter().flatten()
+    }
+
+    fn prune(&mut self, room: &str) {
+        let Some(messages) = self.rooms.get_mut(room) else {
+            return;
+        };
+        let max_age =
+            chrono::Duration::from_std(self.retention.max_age).unwrap_or(chrono::Duration::MAX);
+        let oldest = Utc::now().checked_sub_signed(max_age);
+        while messages.len() > self.retention.messages
+            || messages.front().is_some_and(|message| match message {
+                ServerFrame::Message { ts, .. } => oldest.is_some_and(|oldest| *ts < oldest),
+                _ => false,
+            })
+        {
+            messages.pop_front();
+        }
+        if messages.is_empty() {
+            self.rooms.remove(room);
+        }
+    }
+}
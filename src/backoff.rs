@@ -0,0 +1,111 @@
+//! Delays between reconnection attempts.
+
+use std::time::Duration;
+
+use rand::{Rng, rng};
+
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    max_attempts: Option<u32>,
+    attempt: u32,
+}
+
+impl Backoff {
+    /// `max_attempts` of `None` retries forever.
+    pub fn new(base: Duration, max: Duration, max_attempts: Option<u32>) -> Self {
+        Backoff {
+            base,
+            max,
+            max_attempts,
+            attempt: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Returns the delay before the next attempt, or `None` once the retry limit is reached.
+    /// The delay doubles on every attempt up to `max`, then a random jitter picks a value
+    /// between half of it and the full delay so that clients don't reconnect in lockstep.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| self.attempt >= max) {
+            return None;
+        }
+        let exp = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+
+        let half = exp / 2;
+        let jitter = rng().random_range(0..=half.as_millis() as u64);
+        Some(half + Duration::from_millis(jitter))
+    }
+
+    /// Attempts made so far, out of the limit if there is one.
+    pub fn attempts_label(&self) -> String {
+        match self.max_attempts {
+            Some(max) => format!("{}/{max}", self.attempt),
+            None => format!("{}", self.attempt),
+        }
+    }
+}
+
+impl Default for Backoff {
+    /// Starts at one second, up to a minute, forever.
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(60), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    /// Whether `delay` is the jittered form of `full`, between half of it and all of it.
+    fn jittered(delay: Duration, full: Duration) -> bool {
+        full / 2 <= delay && delay <= full
+    }
+
+    #[test]
+    fn delays_double_up_to_the_max() {
+        let mut backoff = Backoff::new(SECOND, 10 * SECOND, None);
+        for full in [1, 2, 4, 8, 10, 10, 10] {
+            let delay = backoff.next_delay().unwrap();
+            assert!(jittered(delay, full * SECOND), "{delay:?} for {full}s");
+        }
+    }
+
+    #[test]
+    fn gives_up_after_the_max_attempts() {
+        let mut backoff = Backoff::new(SECOND, 10 * SECOND, Some(3));
+        for attempt in 1..=3 {
+            assert!(backoff.next_delay().is_some());
+            assert_eq!(backoff.attempts_label(), format!("{attempt}/3"));
+        }
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.attempts_label(), "3/3");
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(SECOND, 60 * SECOND, Some(2));
+        backoff.next_delay();
+        backoff.next_delay();
+        assert_eq!(backoff.next_delay(), None);
+        backoff.reset();
+        assert_eq!(backoff.attempts_label(), "0/2");
+        assert!(jittered(backoff.next_delay().unwrap(), SECOND));
+    }
+
+    #[test]
+    fn huge_attempt_counts_stay_at_the_max() {
+        let mut backoff = Backoff::new(SECOND, 60 * SECOND, None);
+        backoff.attempt = 100;
+        assert!(jittered(backoff.next_delay().unwrap(), 60 * SECOND));
+    }
+}
@@ -0,0 +1,63 @@
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+const TIMESTAMP_FORMAT: &str = "%H:%M";
+const DAY_FORMAT: &str = "%A %-d %B %Y";
+
+/// Turns the server's UTC timestamps into local time, and keeps track of the
+/// day of the last message so that the scrollback can show when the date changes.
+pub struct Clock {
+    format: Option<String>,
+    last_day: Option<NaiveDate>,
+}
+
+pub type SharedClock = Arc<Mutex<Clock>>;
+
+impl Clock {
+    /// Reads the `strftime` format of `TIMESTAMP_FORMAT`, `off` hiding timestamps.
+    /// An invalid format is returned as a warning and the default is used instead.
+    pub fn from_env() -> (Self, Option<String>) {
+        let mut warning = None;
+        let format = match std::env::var("TIMESTAMP_FORMAT") {
+            Ok(format) if matches!(format.trim(), "" | "off" | "none" | "false") => None,
+            Ok(format) if valid_format(&format) => Some(format),
+            Ok(format) => {
+                warning = Some(format!("Invalid TIMESTAMP_FORMAT \"{format}\"."));
+                Some(TIMESTAMP_FORMAT.to_string())
+            }
+            Err(_) => Some(TIMESTAMP_FORMAT.to_string()),
+        };
+        let clock = Clock {
+            format,
+            last_day: None,
+        };
+        (clock, warning)
+    }
+
+    /// Local time of `ts` in the configured format, `None` when timestamps are off.
+    pub fn stamp(&self, ts: DateTime<Utc>) -> Option<String> {
+        let format = self.format.as_ref()?;
+        Some(ts.with_timezone(&Local).format(format).to_string())
+    }
+
+    /// Separator to show before a message sent at `ts`, if it is from another
+    /// day than the previous one.
+    pub fn day_change(&mut self, ts: DateTime<Utc>) -> Option<String> {
+        let local = ts.with_timezone(&Local);
+        let day = local.date_naive();
+        if self.last_day.is_some_and(|last| last >= day) {
+            return None;
+        }
+        self.last_day = Some(day);
+        Some(format!("── {} ──", local.format(DAY_FORMAT)))
+    }
+}
+
+/// chrono only reports unknown specifiers when the date is formatted.
+fn valid_format(format: &str) -> bool {
+    let mut out = String::new();
+    write!(out, "{}", Utc::now().format(format)).is_ok()
+}
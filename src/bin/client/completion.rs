@@ -0,0 +1,242 @@
+use std::sync::{Arc, Mutex};
+
+use yarca::protocol::CommandInfo;
+
+/// Nicknames, rooms and server commands last announced by the server.
+#[derive(Default)]
+pub struct Roster {
+    pub users: Vec<String>,
+    pub rooms: Vec<String>,
+    /// Slash commands handled by the server or its bots.
+    pub commands: Vec<CommandInfo>,
+}
+
+pub type SharedRoster = Arc<Mutex<Roster>>;
+
+/// Tab completion over commands, nicknames and room names.
+/// Repeated Tabs cycle through the candidates until another key is pressed.
+#[derive(Default)]
+pub struct Completer {
+    cycle: Option<Cycle>,
+}
+
+struct Cycle {
+    start: usize,
+    candidates: Vec<String>,
+    index: usize,
+    /// Line and cursor as they were after the last completion, to detect edits.
+    line: String,
+    cursor: usize,
+}
+
+/// Text to put in place of `line[start..cursor]`.
+pub struct Completion {
+    pub start: usize,
+    pub text: String,
+}
+
+impl Completer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reset(&mut self) {
+        self.cycle = None;
+    }
+
+    /// Completes the word under the cursor, or moves to the next (or previous,
+    /// when `backwards`) candidate if the previous key was also a Tab.
+    pub fn complete(
+        &mut self,
+        line: &str,
+        cursor: usize,
+        commands: &[&str],
+        roster: &Roster,
+        backwards: bool,
+    ) -> Option<Completion> {
+        let cycling = self
+            .cycle
+            .as_ref()
+            .is_some_and(|cycle| cycle.line == line && cycle.cursor == cursor);
+
+        if cycling {
+            let cycle = self.cycle.as_mut()?;
+            let len = cycle.candidates.len();
+            cycle.index = if backwards {
+                (cycle.index + len - 1) % len
+            } else {
+                (cycle.index + 1) % len
+            };
+        } else {
+            let start = line[..cursor]
+                .rfind(char::is_whitespace)
+                .map_or(0, |idx| idx + 1);
+            let word = &line[start..cursor];
+            let candidates = candidates(&line[..start], word, commands, roster);
+            if candidates.is_empty() {
+                self.cycle = None;
+                return None;
+            }
+            let index = if backwards { candidates.len() - 1 } else { 0 };
+            self.cycle = Some(Cycle {
+                start,
+                candidates,
+                index,
+                line: String::new(),
+                cursor: 0,
+            });
+        }
+
+        let cycle = self.cycle.as_ref()?;
+        Some(Completion {
+            start: cycle.start,
+            text: cycle.candidates[cycle.index].clone(),
+        })
+    }
+
+    /// Remembers the line produced by the last completion.
+    pub fn completed(&mut self, line: &str, cursor: usize) {
+        if let Some(cycle) = self.cycle.as_mut() {
+            cycle.line = line.to_string();
+            cycle.cursor = cursor;
+        }
+    }
+
+    /// Candidates of the current cycle, the selected one wrapped in brackets.
+    pub fn hint(&self) -> Option<String> {
+        let cycle = self.cycle.as_ref()?;
+        if cycle.candidates.len() < 2 {
+            return None;
+        }
+        let hint = cycle
+            .candidates
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let c = c.trim_end();
+                if i == cycle.index {
+                    format!("[{c}]")
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        Some(hint)
+    }
+}
+
+fn candidates(before: &str, word: &str, commands: &[&str], roster: &Roster) -> Vec<String> {
+    let first_word = before.trim().is_empty();
+    let previous = before.split_whitespace().next_back();
+
+    let mut found: Vec<String> = if first_word && word.starts_with('/') {
+        let prefix = &word[1..];
+        commands
+            .iter()
+            .filter(|cmd| cmd.starts_with(prefix))
+            .map(|cmd| format!("/{cmd} "))
+            .collect()
+    } else if word.starts_with('#') || previous == Some("/join") {
+        let prefix = word.trim_start_matches('#');
+        roster
+            .rooms
+            .iter()
+            .filter(|room| room.starts_with(prefix))
+            .map(|room| format!("#{room} "))
+            .collect()
+    } else {
+        let (at, prefix) = match word.strip_prefix('@') {
+            Some(prefix) => ("@", prefix),
+            None => ("", word),
+        };
+        let prefix = prefix.to_lowercase();
+        let suffix = if first_word && at.is_empty() {
+            ": "
+        } else {
+            " "
+        };
+        roster
+            .users
+            .iter()
+            .filter(|user| user.to_lowercase().starts_with(&prefix))
+            .map(|user| format!("{at}{user}{suffix}"))
+            .collect()
+    };
+    found.sort();
+    found.dedup();
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster() -> Roster {
+        Roster {
+            users: vec!["alice".into(), "Albert".into(), "bob".into()],
+            rooms: vec!["general".into(), "games".into(), "rust".into()],
+            commands: Vec::new(),
+        }
+    }
+
+    fn texts(line: &str, completer: &mut Completer, backwards: bool) -> Option<String> {
+        let completion =
+            completer.complete(line, line.len(), &["join", "quit"], &roster(), backwards)?;
+        let mut completed = line[..completion.start].to_string();
+        completed.push_str(&completion.text);
+        completer.completed(&completed, completed.len());
+        Some(completed)
+    }
+
+    #[test]
+    fn completes_commands_nicks_and_rooms() {
+        let mut completer = Completer::new();
+        assert_eq!(texts("/jo", &mut completer, false).unwrap(), "/join ");
+        completer.reset();
+        assert_eq!(texts("bo", &mut completer, false).unwrap(), "bob: ");
+        completer.reset();
+        assert_eq!(texts("hi @bo", &mut completer, false).unwrap(), "hi @bob ");
+        completer.reset();
+        assert_eq!(
+            texts("/join ru", &mut completer, false).unwrap(),
+            "/join #rust "
+        );
+        completer.reset();
+        assert_eq!(
+            texts("see #ga", &mut completer, false).unwrap(),
+            "see #games "
+        );
+        completer.reset();
+        assert!(texts("zz", &mut completer, false).is_none());
+    }
+
+    #[test]
+    fn nicks_ignore_case() {
+        let mut completer = Completer::new();
+        assert_eq!(
+            texts("hey al", &mut completer, false).unwrap(),
+            "hey Albert "
+        );
+    }
+
+    #[test]
+    fn tabs_cycle_through_candidates() {
+        let mut completer = Completer::new();
+        let first = texts("hey al", &mut completer, false).unwrap();
+        assert_eq!(first, "hey Albert ");
+        assert_eq!(completer.hint().unwrap(), "[Albert] alice");
+        let second = texts(&first, &mut completer, false).unwrap();
+        assert_eq!(second, "hey alice ");
+        let back = texts(&second, &mut completer, true).unwrap();
+        assert_eq!(back, "hey Albert ");
+    }
+
+    #[test]
+    fn editing_starts_a_new_completion() {
+        let mut completer = Completer::new();
+        texts("#g", &mut completer, false).unwrap();
+        assert_eq!(texts("#ge", &mut completer, false).unwrap(), "#general ");
+        assert!(completer.hint().is_none());
+    }
+}
@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write, stdout},
+    path::{Path, PathBuf},
+};
+
+/// `client.toml`, holding the servers the user connects to.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Profile used when none is given with `--profile`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub addr: String,
+    pub nick: String,
+    #[serde(default)]
+    pub key: KeySource,
+    /// Rooms joined in order after every connection.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rooms: Vec<String>,
+}
+
+/// Where the 32 bytes shared secret is read from.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// Environment variable, `.env` included.
+    Env(String),
+    /// File holding the key, surrounding whitespace ignored.
+    File(PathBuf),
+    Value(String),
+}
+
+impl Default for KeySource {
+    fn default() -> Self {
+        KeySource::Env("SECRET".to_string())
+    }
+}
+
+impl KeySource {
+    pub fn load(&self) -> Result<[u8; 32], String> {
+        let key = match self {
+            KeySource::Env(var) => {
+                std::env::var(var).map_err(|_| format!("{var} must be set in the .env file"))?
+            }
+            KeySource::File(path) => fs::read_to_string(expand_home(path))
+                .map_err(|e| format!("Cannot read key file {}: {e}", path.display()))?
+                .trim()
+                .to_string(),
+            KeySource::Value(key) => key.clone(),
+        };
+        key.as_bytes()
+            .try_into()
+            .map_err(|_| "The key must be exactly 32 bytes long.".to_string())
+    }
+}
+
+impl Config {
+    /// `CONFIG_FILE`, or `<config dir>/yarca/client.toml`.
+    pub fn path() -> Option<PathBuf> {
+        std::env::var("CONFIG_FILE")
+            .ok()
+            .map(PathBuf::from)
+            .or_else(|| dirs::config_dir().map(|dir| dir.join("yarca").join("client.toml")))
+    }
+
+    /// Reads the config file, `None` if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Cannot read {}: {e}", path.display())),
+        };
+        toml::from_str(&content)
+            .map(Some)
+            .map_err(|e| format!("Config {}: {}", path.display(), e.message()))
+    }
+
+    /// Writes the config readable by the user only, since it may hold a key.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = toml::to_string_pretty(self).map_err(io::Error::other)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(content.as_bytes())
+    }
+
+    /// Picks `name`, else the default profile, else the only one, else asks.
+    pub fn select(&self, name: Option<&str>) -> Result<Profile, String> {
+        let name = match name.or(self.default.as_deref()) {
+            Some(name) => name.to_string(),
+            None if self.profiles.len() == 1 => self.profiles.keys().next().unwrap().clone(),
+            None if self.profiles.is_empty() => return Err("No profile in the config.".into()),
+            None => {
+                let names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+                let answer = ask(&format!("Profile ({})", names.join(", ")), None)
+                    .map_err(|e| e.to_string())?;
+                answer.trim().to_string()
+            }
+        };
+        match self.profiles.get(&name) {
+            Some(profile) => Ok(profile.clone()),
+            None => Err(format!("Unknown profile \"{name}\".")),
+        }
+    }
+}
+
+/// Asks for a first profile and writes it to `path`.
+pub fn wizard(path: &Path) -> io::Result<Profile> {
+    println!("No config found, let's create {}.", path.display());
+    let addr = ask("Server's address", Some("127.0.0.1:8080"))?;
+    let nick = loop {
+        let nick = ask("Your username", None)?;
+        if !nick.is_empty() {
+            break nick;
+        }
+    };
+    let key = ask(
+        "Key source: env:<VAR>, file:<path> or the key itself",
+        Some("env:SECRET"),
+    )?;
+    let key = if let Some(var) = key.strip_prefix("env:") {
+        KeySource::Env(var.to_string())
+    } else if let Some(file) = key.strip_prefix("file:") {
+        KeySource::File(PathBuf::from(file))
+    } else {
+        KeySource::Value(key)
+    };
+    let rooms = ask("Rooms to join, comma separated", Some(""))?
+        .split(',')
+        .map(|room| room.trim().trim_start_matches('#').to_string())
+        .filter(|room| !room.is_empty())
+        .collect();
+    let name = ask("Profile name", Some("default"))?;
+
+    let profile = Profile {
+        addr,
+        nick,
+        key,
+        rooms,
+    };
+    let config = Config {
+        default: Some(name.clone()),
+        profiles: BTreeMap::from([(name, profile.clone())]),
+    };
+    config.save(path)?;
+    println!("Saved {}.", path.display());
+    Ok(profile)
+}
+
+fn ask(question: &str, default: Option<&str>) -> io::Result<String> {
+    match default {
+        Some(default) if !default.is_empty() => print!("{question} [{default}]: "),
+        _ => print!("{question}: "),
+    }
+    stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let answer = answer.trim();
+    Ok(match default {
+        Some(default) if answer.is_empty() => default.to_string(),
+        _ => answer.to_string(),
+    })
+}
+
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
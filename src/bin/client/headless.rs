@@ -0,0 +1,325 @@
+use std::{
+    io::{self, BufRead, Write},
+    net::{Shutdown, TcpStream},
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use clap::ValueEnum;
+use yarca::protocol::{ClientFrame, FrameError, FrameReader, ServerFrame, write_frame};
+
+use crate::{config::Profile, too_long, tui::printable};
+
+/// Exit codes of the headless client.
+pub const EXIT_OK: i32 = 0;
+/// The profile or the key is wrong. Invalid arguments exit with 2, like any
+/// command line error.
+pub const EXIT_CONFIG: i32 = 1;
+/// The server could not be reached or the handshake failed.
+pub const EXIT_CONNECT: i32 = 3;
+/// The connection was lost, or closed by the server, before stdin ended.
+pub const EXIT_DISCONNECTED: i32 = 4;
+/// The server turned the client down before welcoming it: wrong key, banned
+/// or taken nickname, server full or speaking another protocol.
+pub const EXIT_REFUSED: i32 = 5;
+
+/// How received frames are written to stdout.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Output {
+    /// `[nick]: message` and system messages, one per line
+    Text,
+    /// Every frame from the server as a JSON object, one per line
+    Json,
+}
+
+/// Client without the terminal UI: every stdin line is sent as a message,
+/// changes room when it is `/join <room>`, or runs a server `/command`. Frames
+/// from the server are written to stdout. Nothing is sent before the server
+/// welcomes the client. Returns the exit code once stdin is closed and the
+/// server has acknowledged it.
+pub fn run(profile: &Profile, key: &[u8; 32], output: Output) -> i32 {
+    session(profile, key, output, io::stdin().lock())
+}
+
+fn session(profile: &Profile, key: &[u8; 32], output: Output, input: impl BufRead) -> i32 {
+    let mut stream = match TcpStream::connect(&profile.addr)
+        .and_then(|mut s| crate::handshake(&mut s, profile, key).map(|()| s))
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {e}", profile.addr);
+            return EXIT_CONNECT;
+        }
+    };
+
+    let read_stream = match stream.try_clone() {
+        Ok(read_stream) => read_stream,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {e}", profile.addr);
+            return EXIT_CONNECT;
+        }
+    };
+    let mut reader = FrameReader::new(read_stream);
+    let mut stdout = io::stdout();
+    if let Err(code) = welcome(&mut reader, key, &mut stdout, output) {
+        return code;
+    }
+
+    let closing = Arc::new(AtomicBool::new(false));
+    let read_closing = closing.clone();
+    let key_copy = *key;
+    let reader = thread::spawn(move || {
+        loop {
+            match reader.read_frame::<ServerFrame>(&key_copy) {
+                Ok(Some(frame)) => {
+                    print_frame(&mut stdout, &frame, output);
+                }
+                Ok(None) => break,
+                Err(e @ FrameError::Io(_)) => {
+                    eprintln!("Connection error: {e}");
+                    break;
+                }
+                Err(e) => eprintln!("Received malformed message: {e}"),
+            }
+        }
+        // Stdin is still open, so the server is the one that went away.
+        if !read_closing.load(Ordering::SeqCst) {
+            eprintln!("Server disconnected.");
+            process::exit(EXIT_DISCONNECTED);
+        }
+    });
+
+    for line in input.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame = match line.strip_prefix('/') {
+            Some(command) => {
+                let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+                match name {
+                    "join" => ClientFrame::Join {
+                        room: args.trim().trim_start_matches('#').to_string(),
+                    },
+                    _ => ClientFrame::Command {
+                        name: name.to_string(),
+                        args: args.trim().to_string(),
+                    },
+                }
+            }
+            None => match too_long(&line) {
+                Some(error) => {
+                    eprintln!("{error}");
+                    continue;
+                }
+                None => ClientFrame::Message {
+                    body: line,
+                    room: None,
+                },
+            },
+        };
+        if let Err(e) = write_frame(&mut stream, &frame, key) {
+            eprintln!("Connection error: {e}");
+            return EXIT_DISCONNECTED;
+        }
+    }
+
+    // The server closes the connection once it has handled everything sent
+    // before, so waiting for that makes sure the last messages went through.
+    closing.store(true, Ordering::SeqCst);
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = reader.join();
+    EXIT_OK
+}
+
+/// Waits for the server to welcome the client, printing what comes before.
+/// Returns the exit code if it turns the client down instead, telling why on
+/// stderr.
+fn welcome(
+    reader: &mut FrameReader<TcpStream>,
+    key: &[u8; 32],
+    stdout: &mut io::Stdout,
+    output: Output,
+) -> Result<(), i32> {
+    loop {
+        match reader.read_frame::<ServerFrame>(key) {
+            Ok(Some(frame @ ServerFrame::Welcome { .. })) => {
+                print_frame(stdout, &frame, output);
+                return Ok(());
+            }
+            // Why the server turns the client down, right before closing.
+            Ok(Some(ServerFrame::System { text })) => eprintln!("{}", printable(&text)),
+            Ok(Some(frame)) => print_frame(stdout, &frame, output),
+            Ok(None) => {
+                eprintln!("The server closed the connection before welcoming the client.");
+                return Err(EXIT_REFUSED);
+            }
+            Err(FrameError::Decrypt) => {
+                eprintln!("Cannot decrypt what the server sent, is the key right?");
+                return Err(EXIT_REFUSED);
+            }
+            Err(e @ FrameError::Io(_)) => {
+                eprintln!("Connection error: {e}");
+                return Err(EXIT_CONNECT);
+            }
+            Err(e) => eprintln!("Received malformed message: {e}"),
+        }
+    }
+}
+
+fn print_frame(stdout: &mut io::Stdout, frame: &ServerFrame, output: Output) {
+    let line = match (output, frame) {
+        (Output::Json, frame) => serde_json::to_string(frame).expect("Frames always serialize."),
+        (Output::Text, ServerFrame::Welcome { room, .. }) => format!("You are now in #{room}."),
+        (Output::Text, ServerFrame::Message { from, body, .. }) => format!("[{from}]: {body}"),
+        (Output::Text, ServerFrame::System { text }) => text.clone(),
+        (Output::Text, ServerFrame::Joined { nick, room }) => format!("{nick} has joined #{room}."),
+        (Output::Text, ServerFrame::Left { nick, room }) => format!("{nick} has left #{room}."),
+        (Output::Text, ServerFrame::FileOffer { from, name, .. }) => {
+            format!("{from} offers {name}, only the terminal client can accept files.")
+        }
+        (
+            Output::Text,
+            ServerFrame::Command { .. }
+            | ServerFrame::Names { .. }
+            | ServerFrame::Users { .. }
+            | ServerFrame::Rooms { .. }
+            | ServerFrame::Commands { .. }
+            | ServerFrame::FileAnswer { .. }
+            | ServerFrame::FileChunk { .. }
+            | ServerFrame::FileCancelled { .. },
+        ) => return,
+    };
+    // Bodies keep their line breaks, and nothing else that drives the terminal.
+    let line = line
+        .split('\n')
+        .map(printable)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut stdout = stdout.lock();
+    let _ = writeln!(stdout, "{line}");
+    // Flushed on every line, for pipes reading as messages arrive.
+    let _ = stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::BufReader, net::TcpListener};
+
+    use yarca::protocol::{MAX_MESSAGE_LEN, PROTOCOL_VERSION, encode_frame};
+
+    use super::*;
+    use crate::config::KeySource;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// Runs a session sending `input` to a server on another thread, which
+    /// reads the hello then calls `serve`. Returns the exit code, and what
+    /// `serve` returned.
+    fn session_with<T: Send + 'static>(
+        input: &str,
+        serve: impl FnOnce(&mut FrameReader<TcpStream>, &mut TcpStream) -> T + Send + 'static,
+    ) -> (i32, T) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let profile = Profile {
+            addr: listener.local_addr().unwrap().to_string(),
+            nick: "ci".to_string(),
+            key: KeySource::default(),
+            rooms: Vec::new(),
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = FrameReader::new(stream.try_clone().unwrap());
+            let hello = reader.read_frame::<ClientFrame>(&KEY).unwrap();
+            assert!(matches!(
+                hello,
+                Some(ClientFrame::Hello {
+                    version: PROTOCOL_VERSION,
+                    ..
+                })
+            ));
+            serve(&mut reader, &mut stream)
+        });
+        let code = session(
+            &profile,
+            &KEY,
+            Output::Text,
+            BufReader::new(input.as_bytes()),
+        );
+        (code, server.join().unwrap())
+    }
+
+    fn system(text: &str) -> String {
+        let frame = ServerFrame::System {
+            text: text.to_string(),
+        };
+        encode_frame(&frame, &KEY)
+    }
+
+    #[test]
+    fn refusals_exit_with_their_code_without_sending() {
+        let (code, sent) = session_with("lost message\n", |reader, stream| {
+            stream
+                .write_all(system("This nickname is taken.").as_bytes())
+                .unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            reader.read_frame::<ClientFrame>(&KEY).unwrap()
+        });
+        assert_eq!(code, EXIT_REFUSED);
+        assert!(sent.is_none());
+    }
+
+    #[test]
+    fn closing_without_a_word_is_a_refusal() {
+        let (code, ()) = session_with("lost message\n", |_, stream| {
+            stream.shutdown(Shutdown::Both).unwrap();
+        });
+        assert_eq!(code, EXIT_REFUSED);
+    }
+
+    #[test]
+    fn messages_are_sent_once_welcomed() {
+        let (code, sent) = session_with("hello\n", |reader, stream| {
+            let welcome = ServerFrame::Welcome {
+                nick: "ci".to_string(),
+                room: "general".to_string(),
+            };
+            stream
+                .write_all(encode_frame(&welcome, &KEY).as_bytes())
+                .unwrap();
+            let sent = reader.read_frame::<ClientFrame>(&KEY).unwrap();
+            // Closed once the client is done, like the server does.
+            assert!(reader.read_frame::<ClientFrame>(&KEY).unwrap().is_none());
+            stream.shutdown(Shutdown::Both).unwrap();
+            sent
+        });
+        assert_eq!(code, EXIT_OK);
+        assert!(matches!(sent, Some(ClientFrame::Message { body, .. }) if body == "hello"));
+    }
+
+    #[test]
+    fn long_messages_are_not_sent() {
+        let input = format!("{}\nhello\n", "x".repeat(MAX_MESSAGE_LEN + 1));
+        let (code, sent) = session_with(&input, |reader, stream| {
+            let welcome = ServerFrame::Welcome {
+                nick: "ci".to_string(),
+                room: "general".to_string(),
+            };
+            stream
+                .write_all(encode_frame(&welcome, &KEY).as_bytes())
+                .unwrap();
+            let sent = reader.read_frame::<ClientFrame>(&KEY).unwrap();
+            assert!(reader.read_frame::<ClientFrame>(&KEY).unwrap().is_none());
+            stream.shutdown(Shutdown::Both).unwrap();
+            sent
+        });
+        assert_eq!(code, EXIT_OK);
+        assert!(matches!(sent, Some(ClientFrame::Message { body, .. }) if body == "hello"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+const HISTORY_SIZE: usize = 1000;
+
+/// Lines the user has sent, oldest first, optionally persisted to a file
+/// so that they survive between sessions.
+pub struct History {
+    entries: Vec<String>,
+    max: usize,
+    path: Option<PathBuf>,
+    position: Option<usize>,
+    draft: String,
+}
+
+/// State of a Ctrl-R reverse incremental search.
+pub struct Search {
+    query: String,
+    found: Option<usize>,
+    failed: bool,
+    original: String,
+}
+
+impl Search {
+    pub fn prompt(&self) -> String {
+        let failed = if self.failed { "failed " } else { "" };
+        format!("({failed}reverse-i-search)`{}': ", self.query)
+    }
+
+    /// Line that was being typed when the search started.
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+}
+
+impl History {
+    /// Builds the history from `HISTORY_SIZE`, `HISTORY_SAVE` and `HISTORY_FILE`,
+    /// loading previous entries from `<config dir>/yarca/history` unless saving is off.
+    pub fn from_env() -> Self {
+        let max = std::env::var("HISTORY_SIZE")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .unwrap_or(HISTORY_SIZE);
+        let save = std::env::var("HISTORY_SAVE")
+            .map(|v| !matches!(v.trim(), "0" | "false" | "no" | "off"))
+            .unwrap_or(true);
+        let path = if save && max > 0 {
+            std::env::var("HISTORY_FILE")
+                .ok()
+                .map(PathBuf::from)
+                .or_else(|| dirs::config_dir().map(|dir| dir.join("yarca").join("history")))
+        } else {
+            None
+        };
+
+        let mut history = History {
+            entries: Vec::new(),
+            max,
+            path,
+            position: None,
+            draft: String::new(),
+        };
+        if let Some(path) = &history.path
+            && let Ok(content) = fs::read_to_string(path)
+        {
+            history.entries = content
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(unescape)
+                .collect();
+            history.truncate();
+        }
+        history
+    }
+
+    /// Records a sent line and saves the history file.
+    pub fn push(&mut self, line: &str) -> io::Result<()> {
+        self.position = None;
+        if line.is_empty() || self.max == 0 || self.entries.last().is_some_and(|l| l == line) {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+        self.truncate();
+        self.save()
+    }
+
+    /// Steps back in history. `current` is kept so that it can be restored by `next`.
+    pub fn prev(&mut self, current: &str) -> Option<&str> {
+        let index = match self.position {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(index) => index - 1,
+        };
+        self.position = Some(index);
+        Some(&self.entries[index])
+    }
+
+    /// Steps forward in history, ending on the line that was being typed.
+    pub fn next(&mut self) -> Option<&str> {
+        let index = self.position?;
+        if index + 1 < self.entries.len() {
+            self.position = Some(index + 1);
+            Some(&self.entries[index + 1])
+        } else {
+            self.position = None;
+            Some(&self.draft)
+        }
+    }
+
+    pub fn start_search(&mut self, current: &str) -> Search {
+        self.position = None;
+        Search {
+            query: String::new(),
+            found: None,
+            failed: false,
+            original: current.to_string(),
+        }
+    }
+
+    /// Extends the query, keeping the current match if it still contains it.
+    pub fn search_push(&self, search: &mut Search, c: char) {
+        search.query.push(c);
+        let before = search.found.map_or(self.entries.len(), |index| index + 1);
+        self.find(search, before);
+    }
+
+    /// Shortens the query and searches again from the newest entry.
+    pub fn search_pop(&self, search: &mut Search) {
+        search.query.pop();
+        search.found = None;
+        self.find(search, self.entries.len());
+    }
+
+    /// Moves to the next older entry that contains the query.
+    pub fn search_older(&self, search: &mut Search) {
+        let before = search.found.unwrap_or(self.entries.len());
+        self.find(search, before);
+    }
+
+    /// Line to show for the current search state.
+    pub fn search_line<'a>(&'a self, search: &'a Search) -> &'a str {
+        match search.found {
+            Some(index) => &self.entries[index],
+            None => &search.original,
+        }
+    }
+
+    fn find(&self, search: &mut Search, before: usize) {
+        if search.query.is_empty() {
+            search.failed = false;
+            return;
+        }
+        match self.entries[..before]
+            .iter()
+            .rposition(|entry| entry.contains(&search.query))
+        {
+            Some(index) => {
+                search.found = Some(index);
+                search.failed = false;
+            }
+            None => search.failed = true,
+        }
+    }
+
+    fn truncate(&mut self) {
+        if self.entries.len() > self.max {
+            let excess = self.entries.len() - self.max;
+            self.entries.drain(..excess);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        for entry in &self.entries {
+            writeln!(file, "{}", escape(entry))?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps multi-line entries on a single line of the history file.
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                entry.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                entry.push('\\');
+                chars.next();
+            }
+            _ => entry.push(c),
+        }
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(entries: &[&str]) -> History {
+        History {
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            max: HISTORY_SIZE,
+            path: None,
+            position: None,
+            draft: String::new(),
+        }
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_file() {
+        for entry in [
+            "plain",
+            "two\nlines",
+            "back\\slash",
+            "\\n literally",
+            "end\\",
+            "\n\\\n",
+        ] {
+            let escaped = escape(entry);
+            assert!(!escaped.contains('\n'), "{escaped:?}");
+            assert_eq!(unescape(&escaped), entry);
+        }
+
+        let dir = std::env::temp_dir().join(format!("yarca-history-{}", std::process::id()));
+        let mut saved = history(&[]);
+        saved.path = Some(dir.join("history"));
+        saved.push("first\nsecond").unwrap();
+        saved.push("C:\\new").unwrap();
+        let content = fs::read_to_string(dir.join("history")).unwrap();
+        let loaded: Vec<String> = content.lines().map(unescape).collect();
+        assert_eq!(loaded, saved.entries);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn browsing_stops_at_both_ends() {
+        assert_eq!(history(&[]).prev("draft"), None);
+        let mut history = history(&["one", "two"]);
+        assert_eq!(history.next(), None);
+        assert_eq!(history.prev("draft"), Some("two"));
+        assert_eq!(history.prev("two"), Some("one"));
+        assert_eq!(history.prev("one"), None);
+        assert_eq!(history.next(), Some("two"));
+        assert_eq!(history.next(), Some("draft"));
+        assert_eq!(history.next(), None);
+    }
+
+    #[test]
+    fn pushing_skips_repeats_and_trims() {
+        let mut history = history(&[]);
+        history.max = 2;
+        for line in ["one", "two", "two", "", "three"] {
+            history.push(line).unwrap();
+        }
+        assert_eq!(history.entries, ["two", "three"]);
+    }
+
+    #[test]
+    fn reverse_search_finds_older_matches() {
+        let mut history = history(&["/join rust", "hello", "/join games", "bye"]);
+        let mut search = history.start_search("typed");
+        assert_eq!(history.search_line(&search), "typed");
+
+        for c in "/jo".chars() {
+            history.search_push(&mut search, c);
+        }
+        assert_eq!(history.search_line(&search), "/join games");
+        history.search_older(&mut search);
+        assert_eq!(history.search_line(&search), "/join rust");
+        history.search_older(&mut search);
+        assert!(search.prompt().starts_with("(failed reverse-i-search)"));
+        assert_eq!(history.search_line(&search), "/join rust");
+
+        history.search_pop(&mut search);
+        assert_eq!(search.prompt(), "(reverse-i-search)`/j': ");
+        assert_eq!(history.search_line(&search), "/join games");
+        history.search_push(&mut search, 'x');
+        assert!(search.prompt().starts_with("(failed"));
+        assert_eq!(search.original(), "typed");
+    }
+}
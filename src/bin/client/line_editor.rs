@@ -0,0 +1,312 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Input line with a cursor, edited with readline-style keys.
+/// The cursor is a byte offset that always sits on a grapheme boundary,
+/// so multi-byte characters and emoji are edited as a single unit.
+#[derive(Default)]
+pub struct LineEditor {
+    buffer: String,
+    cursor: usize,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.buffer
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Replaces the line, putting the cursor at its end.
+    pub fn set(&mut self, text: &str) {
+        self.buffer = text.to_string();
+        self.cursor = self.buffer.len();
+    }
+
+    /// Replaces the text between `start` and the cursor, leaving the cursor after `text`.
+    pub fn replace_before_cursor(&mut self, start: usize, text: &str) {
+        self.buffer.replace_range(start..self.cursor, text);
+        self.cursor = start + text.len();
+    }
+
+    /// Empties the line and returns what it contained.
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Handles an editing key. Returns `false` when the key isn't an editing key.
+    pub fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+
+        match key.code {
+            KeyCode::Char('a') if ctrl => self.cursor = self.line_start(),
+            KeyCode::Char('e') if ctrl => self.cursor = self.line_end(),
+            KeyCode::Char('b') if ctrl => self.cursor = self.prev_boundary(),
+            KeyCode::Char('f') if ctrl => self.cursor = self.next_boundary(),
+            KeyCode::Char('w') if ctrl => self.delete_back_to(self.prev_whitespace_word()),
+            KeyCode::Char('u') if ctrl => self.delete_back_to(self.line_start()),
+            KeyCode::Char('k') if ctrl => self.delete_forward_to(self.line_end()),
+            KeyCode::Char('d') if ctrl => self.delete_forward_to(self.next_boundary()),
+            KeyCode::Char('b') if alt => self.cursor = self.prev_word(),
+            KeyCode::Char('f') if alt => self.cursor = self.next_word(),
+            KeyCode::Char('d') if alt => self.delete_forward_to(self.next_word()),
+            KeyCode::Char(_) if ctrl || alt => return false,
+            KeyCode::Char(c) => self.insert(c),
+            KeyCode::Left if ctrl || alt => self.cursor = self.prev_word(),
+            KeyCode::Right if ctrl || alt => self.cursor = self.next_word(),
+            KeyCode::Left => self.cursor = self.prev_boundary(),
+            KeyCode::Right => self.cursor = self.next_boundary(),
+            KeyCode::Home => self.cursor = self.line_start(),
+            KeyCode::End => self.cursor = self.line_end(),
+            KeyCode::Backspace if ctrl || alt => self.delete_back_to(self.prev_word()),
+            KeyCode::Backspace => self.delete_back_to(self.prev_boundary()),
+            KeyCode::Delete => self.delete_forward_to(self.next_boundary()),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Inserts pasted text at the cursor, normalizing line endings to `\n`.
+    pub fn insert_str(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        self.buffer.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+        if !self.is_boundary(self.cursor) {
+            self.cursor = self.next_boundary();
+        }
+    }
+
+    pub fn insert_newline(&mut self) {
+        self.insert('\n');
+    }
+
+    /// Whether the cursor can move to another line in the given direction.
+    pub fn has_line(&self, up: bool) -> bool {
+        if up {
+            self.buffer[..self.cursor].contains('\n')
+        } else {
+            self.buffer[self.cursor..].contains('\n')
+        }
+    }
+
+    /// Moves the cursor to the line above or below, keeping its column when possible.
+    pub fn move_line(&mut self, up: bool) {
+        let column = self.buffer[self.line_start()..self.cursor]
+            .graphemes(true)
+            .count();
+        let target_start = if up {
+            let end = self.line_start().saturating_sub(1);
+            self.buffer[..end].rfind('\n').map_or(0, |idx| idx + 1)
+        } else {
+            self.line_end() + 1
+        };
+        let target_end = self.buffer[target_start..]
+            .find('\n')
+            .map_or(self.buffer.len(), |idx| target_start + idx);
+        self.cursor = self.buffer[target_start..target_end]
+            .grapheme_indices(true)
+            .nth(column)
+            .map_or(target_end, |(idx, _)| target_start + idx);
+    }
+
+    fn line_start(&self) -> usize {
+        self.buffer[..self.cursor]
+            .rfind('\n')
+            .map_or(0, |idx| idx + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.buffer[self.cursor..]
+            .find('\n')
+            .map_or(self.buffer.len(), |idx| self.cursor + idx)
+    }
+
+    fn insert(&mut self, c: char) {
+        self.buffer.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+        // A combining mark or joiner may have merged with the previous grapheme.
+        if !self.is_boundary(self.cursor) {
+            self.cursor = self.next_boundary();
+        }
+    }
+
+    fn delete_back_to(&mut self, start: usize) {
+        self.buffer.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    fn delete_forward_to(&mut self, end: usize) {
+        self.buffer.replace_range(self.cursor..end, "");
+    }
+
+    fn is_boundary(&self, offset: usize) -> bool {
+        offset == self.buffer.len()
+            || self
+                .buffer
+                .grapheme_indices(true)
+                .any(|(idx, _)| idx == offset)
+    }
+
+    fn prev_boundary(&self) -> usize {
+        self.buffer[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(0, |(idx, _)| idx)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.buffer
+            .grapheme_indices(true)
+            .map(|(idx, g)| idx + g.len())
+            .find(|&end| end > self.cursor)
+            .unwrap_or(self.buffer.len())
+    }
+
+    /// Start of the word before the cursor, where words are runs of alphanumerics.
+    fn prev_word(&self) -> usize {
+        self.buffer[..self.cursor]
+            .split_word_bound_indices()
+            .rev()
+            .find(|(_, w)| is_word(w))
+            .map_or(0, |(idx, _)| idx)
+    }
+
+    /// End of the word after the cursor, where words are runs of alphanumerics.
+    fn next_word(&self) -> usize {
+        self.buffer
+            .split_word_bound_indices()
+            .map(|(idx, w)| (idx + w.len(), w))
+            .find(|&(end, w)| end > self.cursor && is_word(w))
+            .map_or(self.buffer.len(), |(end, _)| end)
+    }
+
+    /// Start of the whitespace-delimited word before the cursor, like readline's Ctrl-W.
+    fn prev_whitespace_word(&self) -> usize {
+        let before = self.buffer[..self.cursor].trim_end();
+        before.rfind(char::is_whitespace).map_or(0, |idx| {
+            idx + before[idx..].chars().next().map_or(0, char::len_utf8)
+        })
+    }
+}
+
+fn is_word(segment: &str) -> bool {
+    segment.chars().any(char::is_alphanumeric)
+}
+
+#[cfg(test)]
+mod tests {
+    use unicode_width::UnicodeWidthStr;
+
+    use super::*;
+
+    fn press(editor: &mut LineEditor, code: KeyCode, modifiers: KeyModifiers) {
+        assert!(editor.handle_key(KeyEvent::new(code, modifiers)));
+    }
+
+    /// Editor holding `text`, typed one character at a time.
+    fn typed(text: &str) -> LineEditor {
+        let mut editor = LineEditor::new();
+        for c in text.chars() {
+            press(&mut editor, KeyCode::Char(c), KeyModifiers::NONE);
+        }
+        editor
+    }
+
+    /// Terminal column of the cursor.
+    fn column(editor: &LineEditor) -> usize {
+        editor.as_str()[..editor.cursor()].width()
+    }
+
+    #[test]
+    fn combining_marks_join_their_letter() {
+        let mut editor = typed("cafe\u{301}");
+        assert_eq!(editor.as_str(), "cafe\u{301}");
+        assert_eq!((editor.cursor(), column(&editor)), (6, 4));
+
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert_eq!((editor.cursor(), column(&editor)), (3, 3));
+        press(&mut editor, KeyCode::Delete, KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), "caf");
+
+        editor.insert_str("e");
+        editor.insert_str("\u{301}s");
+        assert_eq!(editor.as_str(), "cafe\u{301}s");
+        assert_eq!(column(&editor), 5);
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), "cafs");
+    }
+
+    #[test]
+    fn wide_characters_take_two_columns() {
+        let mut editor = typed("日本語");
+        assert_eq!((editor.cursor(), column(&editor)), (9, 6));
+
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert_eq!((editor.cursor(), column(&editor)), (6, 4));
+        press(&mut editor, KeyCode::Char('x'), KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), "日本x語");
+        assert_eq!(column(&editor), 5);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), "日語");
+        assert_eq!(column(&editor), 2);
+
+        press(&mut editor, KeyCode::Char('a'), KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Char('d'), KeyModifiers::CONTROL);
+        assert_eq!(editor.as_str(), "語");
+        assert_eq!(column(&editor), 0);
+        press(&mut editor, KeyCode::End, KeyModifiers::NONE);
+        assert_eq!(column(&editor), 2);
+    }
+
+    #[test]
+    fn emoji_are_edited_whole() {
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let mut editor = typed(&format!("a{family}b👍🏽"));
+        assert_eq!(editor.as_str(), format!("a{family}b👍🏽"));
+
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), format!("a{family}b"));
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        press(&mut editor, KeyCode::Left, KeyModifiers::NONE);
+        assert_eq!((editor.cursor(), column(&editor)), (1, 1));
+        press(&mut editor, KeyCode::Right, KeyModifiers::NONE);
+        assert_eq!(editor.cursor(), 1 + family.len());
+        press(&mut editor, KeyCode::Backspace, KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), "ab");
+
+        press(&mut editor, KeyCode::Char('👍'), KeyModifiers::NONE);
+        assert_eq!((editor.cursor(), column(&editor)), (5, 3));
+        press(&mut editor, KeyCode::Char('\u{1f3fd}'), KeyModifiers::NONE);
+        assert_eq!(editor.as_str(), "a👍🏽b");
+        assert_eq!(editor.cursor(), 9);
+    }
+
+    #[test]
+    fn words_span_scripts() {
+        let mut editor = typed("héllo wörld 世界");
+        // Every ideograph is a word of its own.
+        press(&mut editor, KeyCode::Left, KeyModifiers::CONTROL);
+        assert_eq!(column(&editor), 14);
+        press(&mut editor, KeyCode::Left, KeyModifiers::CONTROL);
+        press(&mut editor, KeyCode::Left, KeyModifiers::CONTROL);
+        assert_eq!(column(&editor), 6);
+        press(&mut editor, KeyCode::Char('w'), KeyModifiers::CONTROL);
+        assert_eq!(editor.as_str(), "wörld 世界");
+        press(&mut editor, KeyCode::Char('k'), KeyModifiers::CONTROL);
+        assert_eq!(editor.as_str(), "");
+    }
+}
@@ -0,0 +1,1088 @@
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use clock::{Clock, SharedClock};
+use completion::{Completer, Roster, SharedRoster};
+use config::{Config, KeySource, Profile};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseEventKind,
+};
+use history::{History, Search};
+use line_editor::LineEditor;
+use mentions::{Mentions, SharedMentions};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::TcpStream,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+use theme::{Role, Span, Theme};
+use transfer::{SharedTransfers, Transfers};
+use tui::{ConnState, SharedTui, TerminalGuard, Tui};
+use unicode_width::UnicodeWidthStr;
+use yarca::backoff::Backoff;
+use yarca::protocol::{
+    ClientFrame, CommandInfo, FrameError, FrameReader, MAX_MESSAGE_LEN, PROTOCOL_VERSION,
+    ServerFrame, write_frame,
+};
+
+mod clock;
+mod completion;
+mod config;
+mod headless;
+mod history;
+mod line_editor;
+mod mentions;
+mod theme;
+mod transfer;
+mod tui;
+
+const RECONNECT_BASE_DELAY: u64 = 1;
+const RECONNECT_MAX_DELAY: u64 = 60;
+const MOUSE_SCROLL_ROWS: usize = 3;
+
+#[derive(PartialEq, Clone)]
+enum ClientEvent {
+    UserInput(String),
+    JoinRoom(String),
+    /// Slash command handled by the server or a bot, with its arguments.
+    ServerCommand(String, String),
+    /// Recipient and path of a file to offer.
+    SendFile(String),
+    /// Accepts, or declines, the offer with the given number, the latest one if empty.
+    AnswerFile(bool, String),
+    /// The server welcomed the connection with the given number, which is
+    /// then known to be accepted.
+    Welcomed(u64),
+    /// The connection with the given number was lost. Events about an older
+    /// connection are stale, and ignored.
+    ServerDisconnected(u64),
+    Custom(Command),
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Command {
+    Help,
+    Addr,
+    Reconnect,
+    Mentions,
+    Quit,
+}
+
+struct InputState {
+    editor: LineEditor,
+    history: History,
+    search: Option<Search>,
+    completer: Completer,
+    roster: SharedRoster,
+}
+
+enum RetryOutcome {
+    Retry,
+    Quit,
+}
+
+/// Reads `RECONNECT_BASE_DELAY`, `RECONNECT_MAX_DELAY` and `RECONNECT_MAX_ATTEMPTS`.
+fn backoff_from_env() -> Backoff {
+    let secs = |name: &str, default: u64| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(default)
+    };
+    let max_attempts = std::env::var("RECONNECT_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .filter(|&n| n > 0);
+
+    Backoff::new(
+        Duration::from_secs(secs("RECONNECT_BASE_DELAY", RECONNECT_BASE_DELAY).max(1)),
+        Duration::from_secs(secs("RECONNECT_MAX_DELAY", RECONNECT_MAX_DELAY).max(1)),
+        max_attempts,
+    )
+}
+
+enum EventError {
+    NotFound,
+    MissingArgument,
+}
+
+impl fmt::Display for ClientEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match self {
+            ClientEvent::UserInput(_) => "User's input",
+            ClientEvent::JoinRoom(_) => "Joins a room",
+            ClientEvent::ServerCommand(..) => "Command handled by the server",
+            ClientEvent::SendFile(_) => "Sends a file to someone or a #room : /send <to> <path>",
+            ClientEvent::AnswerFile(true, _) => "Accepts a file, the latest offer by default",
+            ClientEvent::AnswerFile(false, _) => "Declines a file, the latest offer by default",
+            ClientEvent::Welcomed(_) => "Welcomed by the server",
+            ClientEvent::ServerDisconnected(_) => "No connexion with server",
+            ClientEvent::Custom(cmd) => &(format!("{cmd}")),
+        };
+        f.write_str(desc)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match *self {
+            Command::Help => "Shows available commands",
+            Command::Addr => "Shows server's address",
+            Command::Reconnect => "Retries connecting right away while disconnected",
+            Command::Mentions => "Lists recent messages that mention you",
+            Command::Quit => "Quit chat",
+        };
+        f.write_str(desc)
+    }
+}
+
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match *self {
+            EventError::NotFound => "Command not found",
+            EventError::MissingArgument => "Missing argument",
+        };
+        f.write_str(desc)
+    }
+}
+
+fn commands(cmds_map: &HashMap<&str, ClientEvent>, input: &str) -> Result<ClientEvent, EventError> {
+    let (cmd, args) = input
+        .split_once(' ')
+        .map_or((input, ""), |(cmd, args)| (cmd, args.trim()));
+    match cmds_map.get(cmd) {
+        Some(ClientEvent::JoinRoom(_)) if args.is_empty() => Err(EventError::MissingArgument),
+        Some(ClientEvent::JoinRoom(_)) => Ok(ClientEvent::JoinRoom(args.to_string())),
+        Some(ClientEvent::SendFile(_)) if args.is_empty() => Err(EventError::MissingArgument),
+        Some(ClientEvent::SendFile(_)) => Ok(ClientEvent::SendFile(args.to_string())),
+        Some(ClientEvent::AnswerFile(accept, _)) => {
+            Ok(ClientEvent::AnswerFile(*accept, args.to_string()))
+        }
+        Some(event) => Ok(event.to_owned()),
+        None => Err(EventError::NotFound),
+    }
+}
+
+fn help(ui: &SharedTui, server_commands: &[CommandInfo]) -> io::Result<()> {
+    let cmds = init_hashmap();
+    let mut ui = ui.lock().unwrap();
+    ui.println("Available Commands :")?;
+    for (cmd, desc) in cmds.iter() {
+        ui.println(format!("  {cmd} : {desc}"))?;
+    }
+    if !server_commands.is_empty() {
+        ui.println("Server Commands :")?;
+        for command in server_commands {
+            let usage = match command.usage.as_str() {
+                "" => command.name.clone(),
+                usage => format!("{} {usage}", command.name),
+            };
+            ui.println(format!("  {usage} : {}", command.help))?;
+        }
+    }
+    Ok(())
+}
+
+fn init_hashmap() -> HashMap<&'static str, ClientEvent> {
+    let mut hashmap: HashMap<&'static str, ClientEvent> = HashMap::new();
+    hashmap.insert("quit", ClientEvent::Custom(Command::Quit));
+    hashmap.insert("help", ClientEvent::Custom(Command::Help));
+    hashmap.insert("addr", ClientEvent::Custom(Command::Addr));
+    hashmap.insert("reconnect", ClientEvent::Custom(Command::Reconnect));
+    hashmap.insert("mentions", ClientEvent::Custom(Command::Mentions));
+    hashmap.insert("join", ClientEvent::JoinRoom(String::new()));
+    hashmap.insert("send", ClientEvent::SendFile(String::new()));
+    hashmap.insert("accept", ClientEvent::AnswerFile(true, String::new()));
+    hashmap.insert("decline", ClientEvent::AnswerFile(false, String::new()));
+    hashmap
+}
+
+/// Handles a key while a Ctrl-R search is active. Returns `true` once the key has been
+/// consumed, otherwise the search is accepted and the key is processed as usual.
+fn search_manager(state: &mut InputState, key_event: KeyEvent, ui: &mut Tui) -> bool {
+    let Some(search) = state.search.as_mut() else {
+        return false;
+    };
+    let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+
+    match key_event.code {
+        KeyCode::Char('r') if ctrl => state.history.search_older(search),
+        KeyCode::Char('g') if ctrl => {
+            state.editor.set(search.original());
+            state.search = None;
+        }
+        KeyCode::Esc => {
+            state.editor.set(search.original());
+            state.search = None;
+        }
+        KeyCode::Char(c) if !ctrl => state.history.search_push(search, c),
+        KeyCode::Backspace => state.history.search_pop(search),
+        _ => {
+            state.editor.set(state.history.search_line(search));
+            state.search = None;
+            ui.set_prompt(None).unwrap();
+            return false;
+        }
+    }
+
+    match &state.search {
+        Some(search) => {
+            let line = state.history.search_line(search);
+            ui.set_prompt(Some(&search.prompt())).unwrap();
+            ui.set_input(line, line.len()).unwrap();
+        }
+        None => {
+            ui.set_prompt(None).unwrap();
+            ui.set_input(state.editor.as_str(), state.editor.cursor())
+                .unwrap();
+        }
+    }
+    true
+}
+
+fn input_manager(
+    state: &mut InputState,
+    key_event: KeyEvent,
+    cmds_map: &HashMap<&str, ClientEvent>,
+    ui: &SharedTui,
+) -> Option<ClientEvent> {
+    let mut ui = ui.lock().unwrap();
+    if search_manager(state, key_event, &mut ui) {
+        return None;
+    }
+    let editor = &mut state.editor;
+    let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+
+    if !matches!(key_event.code, KeyCode::Tab | KeyCode::BackTab) {
+        state.completer.reset();
+        ui.set_hint(None).unwrap();
+    }
+
+    match key_event.code {
+        KeyCode::Enter
+            if key_event
+                .modifiers
+                .intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) =>
+        {
+            editor.insert_newline();
+            ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            None
+        }
+        KeyCode::Char('j') if ctrl => {
+            editor.insert_newline();
+            ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            None
+        }
+        KeyCode::Enter => {
+            if !editor.is_empty() {
+                // Kept for the user to shorten, as no server would take it.
+                let body = editor.as_str().trim();
+                if !body.starts_with('/')
+                    && let Some(error) = too_long(body)
+                {
+                    ui.error(error).unwrap();
+                    return None;
+                }
+                let line = editor.take();
+                ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+                let input = line.trim().to_string();
+                // Operator passwords are neither saved nor shown.
+                let secret = input.starts_with("/oper ");
+                if !secret && let Err(e) = state.history.push(&line) {
+                    ui.error(format!("Failed to save history: {e}")).unwrap();
+                }
+                if input.starts_with('/') {
+                    let command = input.trim_start_matches('/');
+                    let echo = if secret { "/oper ***" } else { &input };
+                    ui.println(format!(">>> {echo}")).unwrap();
+                    match commands(cmds_map, command) {
+                        Ok(event) => return Some(event),
+                        Err(EventError::NotFound) => {
+                            let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+                            let roster = state.roster.lock().unwrap();
+                            if roster.commands.iter().any(|c| c.name == name) {
+                                return Some(ClientEvent::ServerCommand(
+                                    name.to_string(),
+                                    args.trim().to_string(),
+                                ));
+                            }
+                            ui.error(format!(
+                                "Command Error (\"{command}\"): {}",
+                                EventError::NotFound
+                            ))
+                            .unwrap();
+                            return None;
+                        }
+                        Err(e) => {
+                            ui.error(format!("Command Error (\"{command}\"): {e}"))
+                                .unwrap();
+                            return None;
+                        }
+                    };
+                }
+                Some(ClientEvent::UserInput(input))
+            } else {
+                None
+            }
+        }
+        KeyCode::Up if editor.has_line(true) => {
+            editor.move_line(true);
+            ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            None
+        }
+        KeyCode::Down if editor.has_line(false) => {
+            editor.move_line(false);
+            ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            None
+        }
+        KeyCode::Up => {
+            if let Some(line) = state.history.prev(editor.as_str()) {
+                editor.set(line);
+                ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            }
+            None
+        }
+        KeyCode::Down => {
+            if let Some(line) = state.history.next() {
+                editor.set(line);
+                ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            }
+            None
+        }
+        KeyCode::Char('r') if ctrl => {
+            let search = state.history.start_search(editor.as_str());
+            ui.set_prompt(Some(&search.prompt())).unwrap();
+            state.search = Some(search);
+            None
+        }
+        KeyCode::Tab | KeyCode::BackTab => {
+            let roster = state.roster.lock().unwrap();
+            let mut commands: Vec<&str> = cmds_map.keys().copied().collect();
+            commands.extend(roster.commands.iter().map(|c| c.name.as_str()));
+            commands.sort();
+            let completion = state.completer.complete(
+                editor.as_str(),
+                editor.cursor(),
+                &commands,
+                &roster,
+                key_event.code == KeyCode::BackTab,
+            );
+            drop(roster);
+            if let Some(completion) = completion {
+                editor.replace_before_cursor(completion.start, &completion.text);
+                state.completer.completed(editor.as_str(), editor.cursor());
+                ui.set_hint(state.completer.hint()).unwrap();
+                ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            }
+            None
+        }
+        KeyCode::PageUp => {
+            let rows = ui.page_size();
+            ui.scroll_up(rows).unwrap();
+            None
+        }
+        KeyCode::PageDown => {
+            let rows = ui.page_size();
+            ui.scroll_down(rows).unwrap();
+            None
+        }
+        _ => {
+            if editor.handle_key(key_event) {
+                ui.set_input(editor.as_str(), editor.cursor()).unwrap();
+            }
+            None
+        }
+    }
+}
+
+/// Why `body` can't be sent, when it is longer than any server takes.
+fn too_long(body: &str) -> Option<String> {
+    (body.len() > MAX_MESSAGE_LEN).then(|| {
+        format!(
+            "Message too long ({} bytes), the limit is {MAX_MESSAGE_LEN}. Send it in parts.",
+            body.len()
+        )
+    })
+}
+
+/// Inserts a bracketed paste as is, so that a multi-line paste becomes a single message.
+fn paste_manager(state: &mut InputState, text: &str, ui: &SharedTui) {
+    let mut ui = ui.lock().unwrap();
+    if let Some(search) = state.search.take() {
+        state.editor.set(state.history.search_line(&search));
+        ui.set_prompt(None).unwrap();
+    }
+    state.completer.reset();
+    ui.set_hint(None).unwrap();
+    state.editor.insert_str(text.trim_end_matches(['\r', '\n']));
+    ui.set_input(state.editor.as_str(), state.editor.cursor())
+        .unwrap();
+}
+
+/// Timestamp, `[nick]: ` and the highlighted body, continuation lines being
+/// indented to line up with the first one.
+fn message_spans(
+    clock: &Clock,
+    ts: DateTime<Utc>,
+    before: &str,
+    from: &str,
+    body: &str,
+    words: &[String],
+) -> Vec<Span> {
+    let mut spans = Vec::new();
+    if let Some(stamp) = clock.stamp(ts) {
+        spans.push(Span::new(format!("{stamp} "), Role::Timestamp));
+    }
+    spans.extend([
+        Span::plain(format!("{before}[")),
+        Span::new(from, Role::Nick),
+        Span::plain("]: "),
+    ]);
+    let indent = " ".repeat(spans.iter().map(|span| span.text.width()).sum());
+    spans.extend(mentions::highlight(
+        &body.replace('\n', &format!("\n{indent}")),
+        words,
+    ));
+    spans
+}
+
+fn show_mentions(ui: &SharedTui, mentions: &SharedMentions, clock: &SharedClock) -> io::Result<()> {
+    let mut mentions = mentions.lock().unwrap();
+    let clock = clock.lock().unwrap();
+    let mut ui = ui.lock().unwrap();
+    if mentions.recent().next().is_none() {
+        ui.system("No mentions yet.")?;
+    }
+    for mention in mentions.recent() {
+        ui.print_spans(message_spans(
+            &clock,
+            mention.ts,
+            &format!("#{} ", mention.room),
+            &mention.from,
+            &mention.body,
+            mentions.words(),
+        ))?;
+    }
+    mentions.mark_read();
+    ui.set_unread_mentions(0)
+}
+
+fn server_frame(
+    frame: ServerFrame,
+    nick: &str,
+    ui: &SharedTui,
+    roster: &SharedRoster,
+    mentions: &SharedMentions,
+    clock: &SharedClock,
+    transfers: &SharedTransfers,
+) -> io::Result<()> {
+    let mut ui = ui.lock().unwrap();
+    match frame {
+        ServerFrame::Welcome { room, .. } => {
+            ui.set_room(&room)?;
+            ui.system(format!("You are now in #{room}."))
+        }
+        ServerFrame::Message {
+            id,
+            ts,
+            from,
+            room,
+            body,
+        } => {
+            let mut mentions = mentions.lock().unwrap();
+            let notification = if from == nick {
+                None
+            } else {
+                mentions.check(id, ts, &from, &room, &body)
+            };
+
+            let mut clock = clock.lock().unwrap();
+            if let Some(separator) = clock.day_change(ts) {
+                ui.print_spans(vec![Span::new(separator, Role::Timestamp)])?;
+            }
+            let spans = message_spans(&clock, ts, "", &from, &body, mentions.words());
+            match notification {
+                Some(sequence) => {
+                    ui.print_highlighted(spans)?;
+                    ui.set_unread_mentions(mentions.unread())?;
+                    ui.notify(&sequence)
+                }
+                None => ui.print_spans(spans),
+            }
+        }
+        ServerFrame::System { text } => ui.system(text),
+        ServerFrame::Joined { nick, room } => ui.system(format!("{nick} has joined #{room}.")),
+        ServerFrame::Left { nick, room } => ui.system(format!("{nick} has left #{room}.")),
+        // Only sent to bots, or to gateways asking for them.
+        ServerFrame::Command { .. } | ServerFrame::Names { .. } => Ok(()),
+        ServerFrame::Users { users } => {
+            roster.lock().unwrap().users = users;
+            Ok(())
+        }
+        ServerFrame::Rooms { rooms } => {
+            roster.lock().unwrap().rooms = rooms;
+            Ok(())
+        }
+        ServerFrame::Commands { commands } => {
+            roster.lock().unwrap().commands = commands;
+            Ok(())
+        }
+        ServerFrame::FileOffer {
+            from,
+            id,
+            name,
+            size,
+            sha256,
+        } => {
+            let offered = transfers
+                .lock()
+                .unwrap()
+                .offered(&from, id, &name, size, &sha256);
+            match offered {
+                Ok(prompt) => ui.system(prompt),
+                Err(e) => ui.error(e),
+            }
+        }
+        ServerFrame::FileAnswer {
+            nick,
+            id,
+            accept,
+            offset,
+        } => {
+            let mut transfers = transfers.lock().unwrap();
+            ui.system(transfers.answered(&nick, id, accept, offset))?;
+            ui.set_transfers(transfers.progress())
+        }
+        ServerFrame::FileChunk {
+            from,
+            id,
+            offset,
+            data,
+        } => {
+            let mut transfers = transfers.lock().unwrap();
+            if let Some(message) = transfers.chunk(&from, id, offset, &data) {
+                ui.system(message)?;
+            }
+            ui.set_transfers(transfers.progress())
+        }
+        ServerFrame::FileCancelled { nick, id, reason } => {
+            let mut transfers = transfers.lock().unwrap();
+            if let Some(message) = transfers.cancelled(&nick, id, &reason) {
+                ui.error(message)?;
+            }
+            ui.set_transfers(transfers.progress())
+        }
+    }
+}
+
+fn wait_for_retry(
+    rx_main_event: &mpsc::Receiver<ClientEvent>,
+    ui: &SharedTui,
+    mentions: &SharedMentions,
+    clock: &SharedClock,
+    delay: Duration,
+    attempts: &str,
+    addr: &str,
+) -> io::Result<RetryOutcome> {
+    let deadline = Instant::now() + delay;
+    let mut shown_secs = None;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(RetryOutcome::Retry);
+        }
+
+        let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        if shown_secs != Some(secs) {
+            shown_secs = Some(secs);
+            ui.lock().unwrap().set_state(ConnState::Retrying {
+                secs,
+                attempts: attempts.to_string(),
+            })?;
+        }
+
+        let tick = remaining.min(Duration::from_millis(200));
+        match rx_main_event.recv_timeout(tick) {
+            Ok(ClientEvent::Custom(Command::Reconnect)) => {
+                return Ok(RetryOutcome::Retry);
+            }
+            Ok(ClientEvent::Custom(Command::Quit)) => {
+                ui.lock().unwrap().system("Disconnecting...")?;
+                return Ok(RetryOutcome::Quit);
+            }
+            Ok(ClientEvent::Custom(Command::Help)) => {
+                help(ui, &[])?;
+            }
+            Ok(ClientEvent::Custom(Command::Mentions)) => {
+                show_mentions(ui, mentions, clock)?;
+            }
+            Ok(ClientEvent::Custom(Command::Addr)) => {
+                ui.lock().unwrap().println(format!("Server : {addr}"))?;
+            }
+            Ok(
+                ClientEvent::UserInput(_)
+                | ClientEvent::JoinRoom(_)
+                | ClientEvent::ServerCommand(..)
+                | ClientEvent::SendFile(_)
+                | ClientEvent::AnswerFile(..),
+            ) => {
+                ui.lock()
+                    .unwrap()
+                    .error("Not connected, message not sent.")?;
+            }
+            Ok(ClientEvent::Welcomed(_) | ClientEvent::ServerDisconnected(_))
+            | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(RetryOutcome::Quit),
+        }
+    }
+}
+
+/// Command line options, overriding the profile's settings.
+#[derive(Parser)]
+#[command(version, about = "YARCA chat client")]
+struct Args {
+    /// Server address
+    #[arg(long, value_name = "ADDR")]
+    server: Option<String>,
+    /// Username
+    #[arg(long)]
+    nick: Option<String>,
+    /// Profile of the config file to use
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    /// Send stdin lines as messages and print received ones to stdout
+    #[arg(long)]
+    no_tui: bool,
+    /// Format of the messages printed by --no-tui
+    #[arg(long, value_enum, default_value = "text", requires = "no_tui")]
+    output: headless::Output,
+}
+
+/// Profile to connect with. `--server` and `--nick` override the profile's values,
+/// and are enough on their own when no profile is asked for. Otherwise the
+/// first-run wizard creates the config if there is none, unless running headless.
+fn start_profile(args: &Args) -> Result<Profile, String> {
+    let mut profile = match (&args.profile, &args.server, &args.nick) {
+        (None, Some(addr), Some(nick)) => Profile {
+            addr: addr.clone(),
+            nick: nick.clone(),
+            key: KeySource::default(),
+            rooms: Vec::new(),
+        },
+        (name, _, _) => {
+            let path = Config::path().ok_or("Cannot find the config directory.")?;
+            match Config::load(&path)? {
+                Some(config) => config.select(name.as_deref())?,
+                None if name.is_some() || args.no_tui => {
+                    return Err(format!(
+                        "No config found at {}, use --server and --nick.",
+                        path.display()
+                    ));
+                }
+                None => {
+                    config::wizard(&path).map_err(|e| format!("Cannot create the config: {e}"))?
+                }
+            }
+        }
+    };
+    if let Some(addr) = &args.server {
+        profile.addr = addr.clone();
+    }
+    if let Some(nick) = &args.nick {
+        profile.nick = nick.clone();
+    }
+    Ok(profile)
+}
+
+/// Sends `frames`, or reports the connection as lost. Returns whether they
+/// were sent, the caller then dropping the connection if not: the reading
+/// thread notices it too, but its event is stale once reconnected.
+fn send_or_disconnect(
+    stream: &mut TcpStream,
+    frames: &[ClientFrame],
+    key: &[u8; 32],
+    ui: &SharedTui,
+) -> io::Result<bool> {
+    match frames
+        .iter()
+        .try_for_each(|frame| write_frame(stream, frame, key))
+    {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            let mut ui = ui.lock().unwrap();
+            ui.set_state(ConnState::Disconnected)?;
+            ui.error(format!("Error sending message: {e}"))?;
+            Ok(false)
+        }
+    }
+}
+
+/// Sends the `Hello` frame, then joins the profile's rooms.
+fn handshake(stream: &mut TcpStream, profile: &Profile, key: &[u8; 32]) -> io::Result<()> {
+    let hello = ClientFrame::Hello {
+        nick: profile.nick.clone(),
+        version: PROTOCOL_VERSION,
+        token: None,
+    };
+    write_frame(stream, &hello, key)?;
+    profile.rooms.iter().try_for_each(|room| {
+        let join = ClientFrame::Join { room: room.clone() };
+        write_frame(stream, &join, key)
+    })
+}
+
+fn main() -> io::Result<()> {
+    dotenvy::dotenv().ok();
+
+    let args = Args::parse();
+    let (profile, secret_key) = match start_profile(&args).and_then(|p| Ok((p.key.load()?, p))) {
+        Ok((secret_key, profile)) => (profile, secret_key),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(headless::EXIT_CONFIG);
+        }
+    };
+    if args.no_tui {
+        std::process::exit(headless::run(&profile, &secret_key, args.output));
+    }
+    let addr = profile.addr.clone();
+    let username = profile.nick.clone();
+
+    let cmds_map = init_hashmap();
+
+    let _guard = TerminalGuard::enter()?;
+    let (theme, theme_warning) = Theme::load();
+    let (clock, clock_warning) = Clock::from_env();
+    let ui = Tui::new(&addr, &username, theme)?.shared();
+    ui.lock().unwrap().draw()?;
+    for warning in [theme_warning, clock_warning].into_iter().flatten() {
+        ui.lock().unwrap().error(warning)?;
+    }
+
+    let (tx_main_event, rx_main_event) = mpsc::channel::<ClientEvent>();
+
+    let roster: SharedRoster = Arc::new(Mutex::new(Roster::default()));
+    let mentions: SharedMentions = Arc::new(Mutex::new(Mentions::from_env(&username)));
+    let clock: SharedClock = Arc::new(Mutex::new(clock));
+    let transfers: SharedTransfers = Arc::new(Mutex::new(Transfers::from_env()));
+
+    let tx_stdin = tx_main_event.clone();
+    let input_ui = ui.clone();
+    let input_roster = roster.clone();
+    thread::spawn(move || {
+        let mut input = InputState {
+            editor: LineEditor::new(),
+            history: History::from_env(),
+            search: None,
+            completer: Completer::new(),
+            roster: input_roster,
+        };
+
+        loop {
+            match event::read() {
+                Ok(Event::Key(key_event)) if key_event.kind == KeyEventKind::Press => {
+                    if let Some(event) = input_manager(&mut input, key_event, &cmds_map, &input_ui)
+                    {
+                        let _ = tx_stdin.send(event.clone());
+                        if event == ClientEvent::Custom(Command::Quit) {
+                            break;
+                        }
+                    }
+                }
+                Ok(Event::Paste(text)) => {
+                    paste_manager(&mut input, &text, &input_ui);
+                }
+                Ok(Event::Mouse(mouse_event)) => match mouse_event.kind {
+                    MouseEventKind::ScrollUp => {
+                        input_ui
+                            .lock()
+                            .unwrap()
+                            .scroll_up(MOUSE_SCROLL_ROWS)
+                            .unwrap();
+                    }
+                    MouseEventKind::ScrollDown => {
+                        input_ui
+                            .lock()
+                            .unwrap()
+                            .scroll_down(MOUSE_SCROLL_ROWS)
+                            .unwrap();
+                    }
+                    _ => {}
+                },
+                Ok(Event::Resize(width, height)) => {
+                    input_ui.lock().unwrap().resize(width, height).unwrap();
+                }
+                _ => {}
+            }
+        }
+    });
+
+    let mut backoff = backoff_from_env();
+    let mut connection: u64 = 0;
+    // Whether to connect right away, rather than after a delay: at first, and
+    // once a connection was welcomed. Servers turning the client down close
+    // the connection before, and are retried with the backoff.
+    let mut connect_now = true;
+
+    'connection_loop: loop {
+        let mut stream = loop {
+            if !connect_now {
+                let Some(delay) = backoff.next_delay() else {
+                    let mut ui = ui.lock().unwrap();
+                    ui.set_state(ConnState::Disconnected)?;
+                    ui.error(format!(
+                        "Giving up after {} attempts.",
+                        backoff.attempts_label()
+                    ))?;
+                    break 'connection_loop;
+                };
+                let attempts = backoff.attempts_label();
+                if let RetryOutcome::Quit = wait_for_retry(
+                    &rx_main_event,
+                    &ui,
+                    &mentions,
+                    &clock,
+                    delay,
+                    &attempts,
+                    &addr,
+                )? {
+                    break 'connection_loop;
+                }
+            }
+            connect_now = false;
+            {
+                let mut ui = ui.lock().unwrap();
+                ui.set_state(ConnState::Connecting)?;
+                ui.system(format!("Attempting to connect to {}...", &addr))?;
+            }
+            match TcpStream::connect(&addr) {
+                Ok(mut s) => match handshake(&mut s, &profile, &secret_key) {
+                    Ok(()) => {
+                        let mut ui = ui.lock().unwrap();
+                        ui.set_state(ConnState::Connected)?;
+                        ui.system(format!("Connected to {}", &addr))?;
+                        connection += 1;
+                        break s;
+                    }
+                    Err(e) => {
+                        let mut ui = ui.lock().unwrap();
+                        ui.error(format!("Error sending username: {e}"))?;
+                        ui.error("Connection failed during initial handshake.")?;
+                    }
+                },
+                Err(e) => {
+                    ui.lock()
+                        .unwrap()
+                        .error(format!("Failed to connect to {}: {e}", &addr))?;
+                }
+            }
+        };
+
+        let read_stream_clone = stream.try_clone()?;
+        let tx_read_event = tx_main_event.clone();
+        let read_thread_username = username.clone();
+        let read_ui = ui.clone();
+        let read_roster = roster.clone();
+        let read_mentions = mentions.clone();
+        let read_clock = clock.clone();
+        let read_transfers = transfers.clone();
+
+        let read_handle = thread::spawn(move || {
+            let mut reader = FrameReader::new(read_stream_clone);
+            loop {
+                match reader.read_frame::<ServerFrame>(&secret_key) {
+                    Ok(Some(frame)) => {
+                        if let ServerFrame::Welcome { .. } = frame {
+                            let _ = tx_read_event.send(ClientEvent::Welcomed(connection));
+                        }
+                        server_frame(
+                            frame,
+                            &read_thread_username,
+                            &read_ui,
+                            &read_roster,
+                            &read_mentions,
+                            &read_clock,
+                            &read_transfers,
+                        )
+                        .unwrap();
+                    }
+                    Err(FrameError::Decrypt) => {
+                        read_ui
+                            .lock()
+                            .unwrap()
+                            .error("Failed to decrypt message.")
+                            .unwrap();
+                    }
+                    Err(e @ (FrameError::Malformed | FrameError::TooLong)) => {
+                        read_ui
+                            .lock()
+                            .unwrap()
+                            .error(format!("Received malformed message: {e}"))
+                            .unwrap();
+                    }
+                    Ok(None) => {
+                        let mut ui = read_ui.lock().unwrap();
+                        ui.set_state(ConnState::Disconnected).unwrap();
+                        ui.system("Server disconnected.").unwrap();
+                        let _ = tx_read_event.send(ClientEvent::ServerDisconnected(connection));
+                        break;
+                    }
+                    Err(FrameError::Io(ref e))
+                        if e.kind() == io::ErrorKind::ConnectionReset
+                            || e.kind() == io::ErrorKind::BrokenPipe
+                            || e.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        let mut ui = read_ui.lock().unwrap();
+                        ui.set_state(ConnState::Disconnected).unwrap();
+                        ui.error(format!(
+                            "Connection error for {read_thread_username}: {e}. Attempting to reconnect..."
+                        ))
+                        .unwrap();
+                        let _ = tx_read_event.send(ClientEvent::ServerDisconnected(connection));
+                        break;
+                    }
+                    Err(FrameError::Io(e)) => {
+                        let mut ui = read_ui.lock().unwrap();
+                        ui.set_state(ConnState::Disconnected).unwrap();
+                        ui.error(format!(
+                            "Unexpected error reading from server for {read_thread_username}: {e}"
+                        ))
+                        .unwrap();
+                        let _ = tx_read_event.send(ClientEvent::ServerDisconnected(connection));
+                        break;
+                    }
+                }
+            }
+        });
+
+        loop {
+            match rx_main_event.try_recv() {
+                Ok(event) => match event {
+                    ClientEvent::UserInput(input) => {
+                        mentions.lock().unwrap().mark_read();
+                        ui.lock().unwrap().set_unread_mentions(0)?;
+                        let message = ClientFrame::Message {
+                            body: input,
+                            room: None,
+                        };
+                        if !send_or_disconnect(&mut stream, &[message], &secret_key, &ui)? {
+                            break;
+                        }
+                    }
+                    ClientEvent::JoinRoom(room) => {
+                        let join = ClientFrame::Join { room };
+                        if !send_or_disconnect(&mut stream, &[join], &secret_key, &ui)? {
+                            break;
+                        }
+                    }
+                    ClientEvent::ServerCommand(name, args) => {
+                        let command = ClientFrame::Command { name, args };
+                        if !send_or_disconnect(&mut stream, &[command], &secret_key, &ui)? {
+                            break;
+                        }
+                    }
+                    ClientEvent::SendFile(args) => {
+                        let Some((to, path)) = args.split_once(' ') else {
+                            ui.lock()
+                                .unwrap()
+                                .error("Usage: /send <nick|#room> <path>")?;
+                            continue;
+                        };
+                        let offer = transfers.lock().unwrap().offer(to, path.trim());
+                        let offer = match offer {
+                            Ok(offer) => offer,
+                            Err(e) => {
+                                ui.lock().unwrap().error(e)?;
+                                continue;
+                            }
+                        };
+                        if !send_or_disconnect(&mut stream, &[offer], &secret_key, &ui)? {
+                            break;
+                        }
+                        ui.lock()
+                            .unwrap()
+                            .system(format!("Offered {path} to {to}, waiting for an answer."))?;
+                    }
+                    ClientEvent::AnswerFile(accept, which) => {
+                        let answer = transfers.lock().unwrap().answer(&which, accept);
+                        let (frame, message) = match answer {
+                            Ok(answer) => answer,
+                            Err(e) => {
+                                ui.lock().unwrap().error(e)?;
+                                continue;
+                            }
+                        };
+                        if !send_or_disconnect(&mut stream, &[frame], &secret_key, &ui)? {
+                            break;
+                        }
+                        let progress = transfers.lock().unwrap().progress();
+                        let mut ui = ui.lock().unwrap();
+                        ui.system(message)?;
+                        ui.set_transfers(progress)?;
+                    }
+                    ClientEvent::Welcomed(welcomed) if welcomed == connection => {
+                        backoff.reset();
+                        connect_now = true;
+                    }
+                    ClientEvent::ServerDisconnected(lost) if lost == connection => {
+                        break;
+                    }
+                    ClientEvent::Welcomed(_) | ClientEvent::ServerDisconnected(_) => {}
+                    ClientEvent::Custom(cmd) => match cmd {
+                        Command::Help => {
+                            help(&ui, &roster.lock().unwrap().commands)?;
+                        }
+                        Command::Mentions => {
+                            show_mentions(&ui, &mentions, &clock)?;
+                        }
+                        Command::Addr => {
+                            ui.lock().unwrap().println(format!("Server : {addr}"))?;
+                        }
+                        Command::Reconnect => {
+                            ui.lock().unwrap().system("Already connected.")?;
+                        }
+                        Command::Quit => {
+                            ui.lock().unwrap().system("Disconnecting...")?;
+                            let _ = stream.shutdown(std::net::Shutdown::Both);
+                            break 'connection_loop;
+                        }
+                    },
+                },
+                // Uploads go one chunk at a time, so that messages typed
+                // meanwhile are sent right away.
+                Err(mpsc::TryRecvError::Empty) if transfers.lock().unwrap().is_sending() => {
+                    let (chunks, messages) = transfers.lock().unwrap().next_chunks();
+                    if !send_or_disconnect(&mut stream, &chunks, &secret_key, &ui)? {
+                        break;
+                    }
+                    let progress = transfers.lock().unwrap().progress();
+                    let mut ui = ui.lock().unwrap();
+                    for message in messages {
+                        ui.system(message)?;
+                    }
+                    ui.set_transfers(progress)?;
+                }
+                Err(mpsc::TryRecvError::Empty) => {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    ui.lock()
+                        .unwrap()
+                        .error("Event channel disconnected. Exiting client.")?;
+                    break 'connection_loop;
+                }
+            }
+        }
+
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        let _ = read_handle.join();
+        // The server forgets transfers along with the connection.
+        transfers.lock().unwrap().disconnected();
+        ui.lock().unwrap().set_transfers(None)?;
+    }
+
+    Ok(())
+}
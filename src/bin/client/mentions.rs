@@ -0,0 +1,310 @@
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    theme::{Role, Span},
+    tui::printable,
+};
+
+const RECENT_MENTIONS: usize = 50;
+/// Messages remembered as checked, more than a room's replayed history.
+const SEEN_MESSAGES: usize = 1000;
+
+/// How the terminal is told about a mention.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Notify {
+    Off,
+    Bell,
+    /// `OSC 9`, understood by iTerm2, Windows Terminal, kitty and others.
+    Osc9,
+    /// `OSC 777`, understood by urxvt, foot and VTE based terminals.
+    Osc777,
+}
+
+pub struct Mention {
+    pub ts: DateTime<Utc>,
+    pub from: String,
+    pub room: String,
+    pub body: String,
+}
+
+/// Messages that mention the user's nickname or one of their highlight words.
+pub struct Mentions {
+    words: Vec<String>,
+    notify: Notify,
+    recent: VecDeque<Mention>,
+    unread: usize,
+    /// ID and time of the messages checked, so that messages the server replays
+    /// when joining a room again are not counted twice. The time tells apart
+    /// the IDs of a server that restarted without its history, counting from 1.
+    seen: HashSet<(u64, DateTime<Utc>)>,
+    /// Same as `seen`, oldest first, to forget the oldest ones.
+    seen_order: VecDeque<(u64, DateTime<Utc>)>,
+}
+
+pub type SharedMentions = Arc<Mutex<Mentions>>;
+
+impl Mentions {
+    /// Watches for `nick` plus the comma separated `HIGHLIGHT_WORDS`, notifying as
+    /// set by `NOTIFY` (`bell`, `osc9`, `osc777` or `off`, defaults to `bell`).
+    pub fn from_env(nick: &str) -> Self {
+        let mut words = vec![nick.to_string()];
+        if let Ok(extra) = std::env::var("HIGHLIGHT_WORDS") {
+            words.extend(
+                extra
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|w| !w.is_empty())
+                    .map(String::from),
+            );
+        }
+        let notify = match std::env::var("NOTIFY").as_deref().map(str::trim) {
+            Ok("off" | "none" | "false") => Notify::Off,
+            Ok("osc9") => Notify::Osc9,
+            Ok("osc777") => Notify::Osc777,
+            _ => Notify::Bell,
+        };
+        Mentions::new(words, notify)
+    }
+
+    fn new(words: Vec<String>, notify: Notify) -> Self {
+        Mentions {
+            words,
+            notify,
+            recent: VecDeque::new(),
+            unread: 0,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+        }
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    /// Records the message if it is a mention. Returns the escape sequence that
+    /// notifies the terminal, empty when notifications are off.
+    pub fn check(
+        &mut self,
+        id: u64,
+        ts: DateTime<Utc>,
+        from: &str,
+        room: &str,
+        body: &str,
+    ) -> Option<String> {
+        if !self.seen.insert((id, ts)) {
+            return None;
+        }
+        self.seen_order.push_back((id, ts));
+        if self.seen_order.len() > SEEN_MESSAGES
+            && let Some(oldest) = self.seen_order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        let mentioned = highlight(body, &self.words)
+            .iter()
+            .any(|span| span.role == Role::Mention);
+        if !mentioned {
+            return None;
+        }
+        if self.recent.len() == RECENT_MENTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Mention {
+            ts,
+            from: from.to_string(),
+            room: room.to_string(),
+            body: body.to_string(),
+        });
+        self.unread += 1;
+        Some(self.notification(from, room, body))
+    }
+
+    pub fn unread(&self) -> usize {
+        self.unread
+    }
+
+    pub fn mark_read(&mut self) {
+        self.unread = 0;
+    }
+
+    pub fn recent(&self) -> impl Iterator<Item = &Mention> {
+        self.recent.iter()
+    }
+
+    fn notification(&self, from: &str, room: &str, body: &str) -> String {
+        let title = sanitize(&format!("{from} in #{room}"));
+        let body = sanitize(body);
+        match self.notify {
+            Notify::Off => String::new(),
+            Notify::Bell => "\x07".to_string(),
+            Notify::Osc9 => format!("\x1b]9;{title}: {body}\x07"),
+            Notify::Osc777 => format!("\x1b]777;notify;{title};{body}\x07"),
+        }
+    }
+}
+
+/// Keeps notification text from ending or altering the escape sequence.
+fn sanitize(text: &str) -> String {
+    printable(text)
+        .chars()
+        .map(|c| if c == ';' { ' ' } else { c })
+        .take(200)
+        .collect()
+}
+
+/// Splits `body` into spans, marking every whole-word occurrence of one of `words`
+/// (case-insensitive, with or without a leading `@`) as a mention.
+pub fn highlight(body: &str, words: &[String]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut plain_start = 0;
+    let mut pos = 0;
+
+    while pos < body.len() {
+        let found = words
+            .iter()
+            .filter(|word| !word.is_empty())
+            .filter_map(|word| match_at(body, pos, word))
+            .max();
+        match found {
+            Some(end) => {
+                if plain_start < pos {
+                    spans.push(Span::plain(&body[plain_start..pos]));
+                }
+                spans.push(Span::new(&body[pos..end], Role::Mention));
+                pos = end;
+                plain_start = end;
+            }
+            None => pos += body[pos..].chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    if plain_start < body.len() {
+        spans.push(Span::plain(&body[plain_start..]));
+    }
+    spans
+}
+
+/// End of `word` (optionally preceded by `@`) if it starts at `pos` as a whole word.
+/// Characters are lowercased one at a time, for offsets to stay those of `body`
+/// even where lowercasing changes their length, like `İ`.
+fn match_at(body: &str, pos: usize, word: &str) -> Option<usize> {
+    if body[..pos].chars().next_back().is_some_and(is_word_char) {
+        return None;
+    }
+    let start = if body[pos..].starts_with('@') {
+        pos + 1
+    } else {
+        pos
+    };
+    let mut wanted = word.chars().flat_map(char::to_lowercase).peekable();
+    let mut end = start;
+    for c in body[start..].chars() {
+        if wanted.peek().is_none() {
+            break;
+        }
+        if !c.to_lowercase().all(|lower| wanted.next() == Some(lower)) {
+            return None;
+        }
+        end += c.len_utf8();
+    }
+    if wanted.peek().is_some() || body[end..].chars().next().is_some_and(is_word_char) {
+        return None;
+    }
+    Some(end)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn mentions() -> Mentions {
+        Mentions::new(vec!["alice".to_string()], Notify::Bell)
+    }
+
+    #[test]
+    fn replayed_messages_count_once() {
+        let mut mentions = mentions();
+        let ts = Utc::now();
+        assert!(
+            mentions
+                .check(7, ts, "bob", "general", "hi alice")
+                .is_some()
+        );
+        assert!(
+            mentions
+                .check(7, ts, "bob", "general", "hi alice")
+                .is_none()
+        );
+        assert_eq!(mentions.unread(), 1);
+    }
+
+    #[test]
+    fn messages_sent_at_the_same_time_all_count() {
+        let mut mentions = mentions();
+        let ts = Utc::now();
+        assert!(mentions.check(1, ts, "bob", "general", "alice?").is_some());
+        assert!(
+            mentions
+                .check(2, ts, "carol", "general", "@alice!")
+                .is_some()
+        );
+        // An older message of another room, replayed when joining it.
+        let earlier = ts - TimeDelta::minutes(5);
+        assert!(
+            mentions
+                .check(0, earlier, "dave", "rust", "alice")
+                .is_some()
+        );
+        assert_eq!(mentions.unread(), 3);
+    }
+
+    fn mentioned(body: &str, words: &[&str]) -> Vec<String> {
+        let words: Vec<String> = words.iter().map(|word| word.to_string()).collect();
+        highlight(body, &words)
+            .into_iter()
+            .filter(|span| span.role == Role::Mention)
+            .map(|span| span.text)
+            .collect()
+    }
+
+    #[test]
+    fn whole_words_match_in_any_case() {
+        assert_eq!(
+            mentioned("Hi ALICE, @alice!", &["alice"]),
+            ["ALICE", "@alice"]
+        );
+        assert!(mentioned("malice alices", &["alice"]).is_empty());
+        assert_eq!(mentioned("deploy-prod now", &["deploy"]).len(), 0);
+    }
+
+    #[test]
+    fn lowercasing_may_change_lengths() {
+        // `İ` lowercases to two characters, three bytes instead of two.
+        assert_eq!(mentioned("İstanbul says hi alice", &["alice"]), ["alice"]);
+        assert_eq!(mentioned("Hello İlker!", &["i̇lker"]), ["İlker"]);
+        assert_eq!(mentioned("Hello İlker!", &["İLKER"]), ["İlker"]);
+        assert!(mentioned("Hello İlker!", &["ilker"]).is_empty());
+    }
+
+    #[test]
+    fn a_restarted_server_reuses_ids() {
+        let mut mentions = mentions();
+        let ts = Utc::now();
+        assert!(mentions.check(1, ts, "bob", "general", "alice").is_some());
+        let later = ts + TimeDelta::seconds(1);
+        assert!(
+            mentions
+                .check(1, later, "bob", "general", "alice")
+                .is_some()
+        );
+    }
+}
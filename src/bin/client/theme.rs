@@ -0,0 +1,202 @@
+use crossterm::style::{Attribute, Color, ContentStyle};
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+/// What a piece of text is, so that the theme can pick how it looks.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Plain,
+    Nick,
+    System,
+    Error,
+    Mention,
+    Timestamp,
+}
+
+#[derive(Clone)]
+pub struct Span {
+    pub text: String,
+    pub role: Role,
+}
+
+impl Span {
+    pub fn new(text: impl Into<String>, role: Role) -> Self {
+        Span {
+            text: text.into(),
+            role,
+        }
+    }
+
+    pub fn plain(text: impl Into<String>) -> Self {
+        Span::new(text, Role::Plain)
+    }
+}
+
+/// Colors read from `theme.toml`, every key being optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeFile {
+    color: Option<bool>,
+    system: Option<String>,
+    error: Option<String>,
+    mention: Option<String>,
+    highlight: Option<String>,
+    timestamp: Option<String>,
+    nicks: Option<Vec<String>>,
+}
+
+pub struct Theme {
+    color: bool,
+    system: Color,
+    error: Color,
+    mention: Color,
+    highlight: Color,
+    timestamp: Color,
+    nicks: Vec<Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            color: true,
+            system: Color::DarkYellow,
+            error: Color::Red,
+            mention: Color::Magenta,
+            highlight: Color::AnsiValue(236),
+            timestamp: Color::DarkGrey,
+            nicks: vec![
+                Color::Cyan,
+                Color::Green,
+                Color::Blue,
+                Color::Magenta,
+                Color::Yellow,
+                Color::Red,
+                Color::DarkCyan,
+                Color::DarkGreen,
+                Color::DarkMagenta,
+                Color::DarkBlue,
+            ],
+        }
+    }
+}
+
+impl Theme {
+    /// Loads `THEME_FILE`, or `<config dir>/yarca/theme.toml`, on top of the default
+    /// theme. Colors are turned off when `NO_COLOR` is set. Problems with the file are
+    /// returned as a warning and the defaults are used instead.
+    pub fn load() -> (Self, Option<String>) {
+        let mut theme = Theme::default();
+        let mut warning = None;
+
+        let path = std::env::var("THEME_FILE")
+            .ok()
+            .map(PathBuf::from)
+            .or_else(|| dirs::config_dir().map(|dir| dir.join("yarca").join("theme.toml")));
+        if let Some(path) = path
+            && let Ok(content) = fs::read_to_string(&path)
+        {
+            match toml::from_str::<ThemeFile>(&content) {
+                Ok(file) => {
+                    if let Err(e) = theme.apply(file) {
+                        warning = Some(format!("Theme {}: {e}", path.display()));
+                    }
+                }
+                Err(e) => warning = Some(format!("Theme {}: {}", path.display(), e.message())),
+            }
+        }
+
+        if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            theme.color = false;
+        }
+        (theme, warning)
+    }
+
+    fn apply(&mut self, file: ThemeFile) -> Result<(), String> {
+        if let Some(color) = file.color {
+            self.color = color;
+        }
+        for (value, slot) in [
+            (file.system, &mut self.system),
+            (file.error, &mut self.error),
+            (file.mention, &mut self.mention),
+            (file.highlight, &mut self.highlight),
+            (file.timestamp, &mut self.timestamp),
+        ] {
+            if let Some(value) = value {
+                *slot = parse_color(&value)?;
+            }
+        }
+        if let Some(nicks) = file.nicks {
+            let nicks = nicks
+                .iter()
+                .map(|n| parse_color(n))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !nicks.is_empty() {
+                self.nicks = nicks;
+            }
+        }
+        Ok(())
+    }
+
+    /// Style of a span. Without colors, roles fall back to text attributes.
+    pub fn style(&self, span: &Span) -> ContentStyle {
+        let mut style = ContentStyle::new();
+        if !self.color {
+            match span.role {
+                Role::Plain => {}
+                Role::Nick => style.attributes.set(Attribute::Bold),
+                Role::System => style.attributes.set(Attribute::Dim),
+                Role::Error => style.attributes.set(Attribute::Bold),
+                Role::Mention => style.attributes.set(Attribute::Reverse),
+                Role::Timestamp => style.attributes.set(Attribute::Dim),
+            }
+            return style;
+        }
+        style.foreground_color = match span.role {
+            Role::Plain => None,
+            Role::Nick => Some(self.nick_color(&span.text)),
+            Role::System => Some(self.system),
+            Role::Error => Some(self.error),
+            Role::Mention => Some(self.mention),
+            Role::Timestamp => Some(self.timestamp),
+        };
+        if matches!(span.role, Role::Nick | Role::Mention) {
+            style.attributes.set(Attribute::Bold);
+        }
+        style
+    }
+
+    /// Background of lines that mention the user, if colors are on.
+    pub fn highlight(&self) -> Option<Color> {
+        self.color.then_some(self.highlight)
+    }
+
+    /// Stable color for a nickname: the same nick gets the same color in every session.
+    fn nick_color(&self, nick: &str) -> Color {
+        let nick = nick.trim_start_matches('@').to_lowercase();
+        // FNV-1a, so that the result doesn't depend on the std hasher's seed.
+        let hash = nick.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+        self.nicks[(hash % self.nicks.len() as u64) as usize]
+    }
+}
+
+/// Accepts crossterm color names (`dark_grey`), `#rrggbb` and ANSI color numbers.
+fn parse_color(value: &str) -> Result<Color, String> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix('#')
+        && hex.len() == 6
+        && let Ok(rgb) = u32::from_str_radix(hex, 16)
+    {
+        return Ok(Color::Rgb {
+            r: (rgb >> 16) as u8,
+            g: (rgb >> 8) as u8,
+            b: rgb as u8,
+        });
+    }
+    if let Ok(ansi) = value.parse::<u8>() {
+        return Ok(Color::AnsiValue(ansi));
+    }
+    Color::try_from(value).map_err(|_| format!("unknown color \"{value}\""))
+}
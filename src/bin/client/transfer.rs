@@ -0,0 +1,502 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+use yarca::protocol::{ClientFrame, FILE_CHUNK_LEN, valid_sha256};
+
+use crate::config::expand_home;
+
+pub type SharedTransfers = Arc<Mutex<Transfers>>;
+
+/// File offered by this client.
+struct Outgoing {
+    path: PathBuf,
+    name: String,
+    to: String,
+}
+
+/// Accepted file being sent to one recipient.
+struct Upload {
+    id: u64,
+    to: String,
+    name: String,
+    size: u64,
+    file: File,
+    offset: u64,
+}
+
+/// File offered by someone else, waiting for `/accept` or `/decline`.
+struct Offer {
+    from: String,
+    id: u64,
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Accepted file being received, written to a `.part` file until it is complete.
+struct Download {
+    name: String,
+    size: u64,
+    sha256: String,
+    file: File,
+    part: PathBuf,
+    received: u64,
+}
+
+/// Files sent and received through the server. Partial downloads are kept on
+/// disk, so accepting the same file again resumes where it stopped.
+pub struct Transfers {
+    dir: PathBuf,
+    next_id: u64,
+    outgoing: HashMap<u64, Outgoing>,
+    uploads: Vec<Upload>,
+    /// Pending offers by the number shown to the user.
+    offers: BTreeMap<u32, Offer>,
+    next_offer: u32,
+    downloads: HashMap<(String, u64), Download>,
+}
+
+impl Transfers {
+    /// Files are saved to `DOWNLOAD_DIR`, or the platform's download directory.
+    pub fn from_env() -> Self {
+        let dir = std::env::var("DOWNLOAD_DIR")
+            .ok()
+            .map(|dir| expand_home(Path::new(&dir)))
+            .or_else(dirs::download_dir)
+            .unwrap_or_else(|| PathBuf::from("."));
+        Transfers::new(dir)
+    }
+
+    /// Saves files to `dir`.
+    pub fn new(dir: PathBuf) -> Self {
+        Transfers {
+            dir,
+            next_id: 1,
+            outgoing: HashMap::new(),
+            uploads: Vec::new(),
+            offers: BTreeMap::new(),
+            next_offer: 1,
+            downloads: HashMap::new(),
+        }
+    }
+
+    /// Hashes the file at `path` and returns the offer to send for it.
+    pub fn offer(&mut self, to: &str, path: &str) -> Result<ClientFrame, String> {
+        let path = expand_home(Path::new(path));
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("{} is not a file.", path.display()))?;
+        let (size, sha256) =
+            hash_file(&path).map_err(|e| format!("Cannot read {}: {e}", path.display()))?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.outgoing.insert(
+            id,
+            Outgoing {
+                path,
+                name: name.clone(),
+                to: to.to_string(),
+            },
+        );
+        Ok(ClientFrame::FileOffer {
+            id,
+            to: to.to_string(),
+            name,
+            size,
+            sha256,
+        })
+    }
+
+    /// Starts sending the file offered as `id` once `nick` accepted it.
+    pub fn answered(&mut self, nick: &str, id: u64, accept: bool, offset: u64) -> String {
+        let Some(outgoing) = self.outgoing.get(&id) else {
+            return format!("{nick} answered an unknown offer.");
+        };
+        if !accept {
+            return format!("{nick} declined {}.", outgoing.name);
+        }
+        let opened = File::open(&outgoing.path).and_then(|mut file| {
+            let size = file.metadata()?.len();
+            file.seek(SeekFrom::Start(offset))?;
+            Ok((file, size))
+        });
+        match opened {
+            Ok((file, size)) => {
+                self.uploads.push(Upload {
+                    id,
+                    to: nick.to_string(),
+                    name: outgoing.name.clone(),
+                    size,
+                    file,
+                    offset,
+                });
+                match offset {
+                    0 => format!("{nick} accepted {}.", outgoing.name),
+                    _ => format!(
+                        "{nick} resumes {} at {}.",
+                        outgoing.name,
+                        size_label(offset)
+                    ),
+                }
+            }
+            Err(e) => format!("Cannot read {}: {e}", outgoing.path.display()),
+        }
+    }
+
+    /// Stops sending the offer `id` to `nick`, which is everyone it was offered to
+    /// when the server turned it down. Returns what to tell the user, `None`
+    /// when it was already stopped: the chunks sent meanwhile are refused too.
+    pub fn cancelled(&mut self, nick: &str, id: u64, reason: &str) -> Option<String> {
+        let uploads = self.uploads.len();
+        self.uploads
+            .retain(|upload| !(upload.id == id && upload.to == nick));
+        let stopped = self.uploads.len() < uploads;
+        let name = match self.outgoing.get(&id) {
+            Some(outgoing) if outgoing.to == nick => self.outgoing.remove(&id).map(|o| o.name),
+            Some(outgoing) if stopped => Some(outgoing.name.clone()),
+            _ if stopped => Some("a file".to_string()),
+            _ => return None,
+        };
+        name.map(|name| format!("Sending {name} to {nick} failed: {reason}"))
+    }
+
+    /// Reads the next chunk of every upload. Returns the frames to send, and
+    /// what to tell the user about the uploads that ended.
+    pub fn next_chunks(&mut self) -> (Vec<ClientFrame>, Vec<String>) {
+        let mut frames = Vec::new();
+        let mut messages = Vec::new();
+        let mut buf = vec![0; FILE_CHUNK_LEN];
+        self.uploads.retain_mut(|upload| {
+            let len = match upload.file.read(&mut buf) {
+                Ok(0) if upload.offset < upload.size => {
+                    messages.push(format!("{} got shorter while sending it.", upload.name));
+                    return false;
+                }
+                Ok(len) => len,
+                Err(e) => {
+                    messages.push(format!("Cannot read {}: {e}", upload.name));
+                    return false;
+                }
+            };
+            if len > 0 {
+                frames.push(ClientFrame::FileChunk {
+                    id: upload.id,
+                    to: upload.to.clone(),
+                    offset: upload.offset,
+                    data: hex::encode(&buf[..len]),
+                });
+                upload.offset += len as u64;
+            }
+            if upload.offset >= upload.size {
+                messages.push(format!("Sent {} to {}.", upload.name, upload.to));
+                return false;
+            }
+            true
+        });
+        (frames, messages)
+    }
+
+    pub fn is_sending(&self) -> bool {
+        !self.uploads.is_empty()
+    }
+
+    /// Records an offer and returns the prompt to show, or why it was ignored.
+    pub fn offered(
+        &mut self,
+        from: &str,
+        id: u64,
+        name: &str,
+        size: u64,
+        sha256: &str,
+    ) -> Result<String, String> {
+        // The checksum names the partial download, so it must not hold a path either.
+        if !valid_sha256(sha256) {
+            return Err(format!(
+                "Ignored a file from {from} with an invalid checksum."
+            ));
+        }
+        // Only the file name is kept, so that the sender cannot pick where it is saved.
+        let name = Path::new(name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with('.'))
+            .unwrap_or_else(|| "file".to_string());
+        let number = self.next_offer;
+        self.next_offer += 1;
+        let prompt = format!(
+            "{from} offers {name} ({}), /accept {number} or /decline {number}",
+            size_label(size)
+        );
+        self.offers.insert(
+            number,
+            Offer {
+                from: from.to_string(),
+                id,
+                name,
+                size,
+                sha256: sha256.to_lowercase(),
+            },
+        );
+        Ok(prompt)
+    }
+
+    /// Answers the offer numbered `which`, the latest one when it is empty.
+    /// Accepting resumes from a previous partial download of the same file.
+    pub fn answer(&mut self, which: &str, accept: bool) -> Result<(ClientFrame, String), String> {
+        let number = match which.trim() {
+            "" => self.offers.keys().next_back().copied(),
+            which => which.trim_start_matches('#').parse().ok(),
+        };
+        let Some(offer) = number.and_then(|number| self.offers.remove(&number)) else {
+            return Err("No such offer.".into());
+        };
+        let answer = |offset| ClientFrame::FileAnswer {
+            from: offer.from.clone(),
+            id: offer.id,
+            accept,
+            offset,
+        };
+        if !accept {
+            return Ok((answer(0), format!("Declined {}.", offer.name)));
+        }
+
+        let part = self
+            .dir
+            .join(format!("{}.{}.part", offer.name, &offer.sha256[..12]));
+        let opened = fs::create_dir_all(&self.dir).and_then(|()| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&part)?;
+            let mut len = file.metadata()?.len();
+            if len > offer.size {
+                file.set_len(0)?;
+                len = 0;
+            }
+            file.seek(SeekFrom::Start(len))?;
+            Ok((file, len))
+        });
+        let (file, received) =
+            opened.map_err(|e| format!("Cannot write {}: {e}", part.display()))?;
+
+        let frame = answer(received);
+        let message = match received {
+            0 => format!("Receiving {}...", offer.name),
+            _ => format!("Resuming {} at {}...", offer.name, size_label(received)),
+        };
+        let key = (offer.from, offer.id);
+        let download = Download {
+            name: offer.name,
+            size: offer.size,
+            sha256: offer.sha256,
+            file,
+            part,
+            received,
+        };
+        if download.received == download.size {
+            return Ok((frame, self.finish(download)));
+        }
+        self.downloads.insert(key, download);
+        Ok((frame, message))
+    }
+
+    /// Writes a received chunk. Returns a message once the download is over.
+    pub fn chunk(&mut self, from: &str, id: u64, offset: u64, data: &str) -> Option<String> {
+        let key = (from.to_string(), id);
+        let download = self.downloads.get_mut(&key)?;
+        let written = match hex::decode(data) {
+            Ok(bytes) if offset == download.received => {
+                download.file.write_all(&bytes).map(|()| bytes.len())
+            }
+            Ok(_) => Err(io::Error::other("chunk out of order")),
+            Err(e) => Err(io::Error::other(e)),
+        };
+        match written {
+            Ok(len) => download.received += len as u64,
+            Err(e) => {
+                let name = download.name.clone();
+                self.downloads.remove(&key);
+                return Some(format!("Receiving {name} failed: {e}"));
+            }
+        }
+        if download.received < download.size {
+            return None;
+        }
+        let download = self.downloads.remove(&key)?;
+        Some(self.finish(download))
+    }
+
+    /// Checks the SHA-256 of a complete download and moves it next to the others.
+    fn finish(&self, download: Download) -> String {
+        drop(download.file);
+        match hash_file(&download.part) {
+            Ok((_, sha256)) if sha256 == download.sha256 => {}
+            Ok(_) => {
+                let _ = fs::remove_file(&download.part);
+                return format!(
+                    "{} is corrupted, its SHA-256 does not match.",
+                    download.name
+                );
+            }
+            Err(e) => return format!("Cannot read {}: {e}", download.part.display()),
+        }
+        let path = free_path(&self.dir, &download.name);
+        match fs::rename(&download.part, &path) {
+            Ok(()) => format!("Saved {} to {}.", download.name, path.display()),
+            Err(e) => format!("Cannot save {}: {e}", path.display()),
+        }
+    }
+
+    /// Forgets everything in progress after the connection dropped. Partial
+    /// downloads stay on disk, to be resumed.
+    pub fn disconnected(&mut self) {
+        self.outgoing.clear();
+        self.uploads.clear();
+        self.offers.clear();
+        self.downloads.clear();
+    }
+
+    /// Progress of every transfer, for the status bar.
+    pub fn progress(&self) -> Option<String> {
+        let percent = |done: u64, size: u64| (done * 100).checked_div(size).unwrap_or(100);
+        let parts: Vec<String> = self
+            .uploads
+            .iter()
+            .map(|u| format!("↑ {} {}%", u.name, percent(u.offset, u.size)))
+            .chain(
+                self.downloads
+                    .values()
+                    .map(|d| format!("↓ {} {}%", d.name, percent(d.received, d.size))),
+            )
+            .collect();
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+/// Size and hex encoded SHA-256 of a file.
+fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::other("not a file"));
+    }
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+/// `dir/name`, or `dir/name (2)` and so on when it already exists.
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (2..)
+        .map(|n| dir.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .expect("Some name is always free.")
+}
+
+fn size_label(size: u64) -> String {
+    match size {
+        0..1024 => format!("{size} B"),
+        1024..1_048_576 => format!("{:.1} KiB", size as f64 / 1024.0),
+        _ => format!("{:.1} MiB", size as f64 / 1_048_576.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn transfers(test: &str) -> Transfers {
+        let dir = std::env::temp_dir().join(format!("yarca-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Transfers::new(dir)
+    }
+
+    #[test]
+    fn offers_keep_only_the_file_name() {
+        let mut transfers = transfers("names");
+        for (name, kept) in [
+            ("report.pdf", "report.pdf"),
+            ("../../etc/passwd", "passwd"),
+            ("/home/alice/notes.txt", "notes.txt"),
+            (".bashrc", "file"),
+            ("..", "file"),
+        ] {
+            let prompt = transfers.offered("bob", 1, name, 10, SHA256).unwrap();
+            assert!(
+                prompt.starts_with(&format!("bob offers {kept} ")),
+                "{prompt}"
+            );
+        }
+    }
+
+    #[test]
+    fn offers_need_a_sha256() {
+        let mut transfers = transfers("sha256");
+        for sha256 in [
+            "",
+            "abc",
+            "aaaaaaaaaaa\u{e9}/../../../../home/u/x",
+            &format!("{}/", &SHA256[..63]),
+            &format!("{SHA256}0"),
+            &SHA256.replace('9', "g"),
+        ] {
+            assert!(
+                transfers.offered("bob", 1, "x", 10, sha256).is_err(),
+                "{sha256}"
+            );
+        }
+        assert!(transfers.offers.is_empty());
+    }
+
+    #[test]
+    fn uploads_are_cancelled_once() {
+        let mut transfers = transfers("cancel");
+        let path = transfers.dir.join("notes.txt");
+        fs::create_dir_all(&transfers.dir).unwrap();
+        fs::write(&path, "hello").unwrap();
+        transfers.offer("#general", path.to_str().unwrap()).unwrap();
+        transfers.answered("bob", 1, true, 0);
+        transfers.answered("carol", 1, true, 0);
+
+        let reason = "bob doesn't keep up.";
+        let message = transfers.cancelled("bob", 1, reason);
+        assert_eq!(
+            message.as_deref(),
+            Some("Sending notes.txt to bob failed: bob doesn't keep up.")
+        );
+        assert_eq!(transfers.cancelled("bob", 1, reason), None);
+        assert!(transfers.is_sending());
+        fs::remove_dir_all(&transfers.dir).unwrap();
+    }
+
+    #[test]
+    fn downloads_stay_in_the_directory() {
+        let mut transfers = transfers("part");
+        let dir = transfers.dir.clone();
+        transfers
+            .offered("bob", 1, "../x.txt", 10, &SHA256.to_uppercase())
+            .unwrap();
+        transfers.answer("", true).unwrap();
+        let download = &transfers.downloads[&("bob".to_string(), 1)];
+        assert_eq!(download.part, dir.join("x.txt.9f86d081884c.part"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,559 @@
+use crossterm::{
+    cursor,
+    event::{
+        DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+        KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, queue,
+    style::{Attribute, Print, PrintStyledContent, SetAttribute, StyledContent, Stylize},
+    terminal::{
+        self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode,
+        enable_raw_mode,
+    },
+};
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::theme::{Role, Span, Theme};
+
+const SCROLLBACK_LIMIT: usize = 5000;
+const PROMPT: &str = "> ";
+const INPUT_MAX_ROWS: usize = 5;
+
+pub type SharedTui = Arc<Mutex<Tui>>;
+
+#[derive(Clone)]
+pub enum ConnState {
+    Connecting,
+    Connected,
+    Retrying { secs: u64, attempts: String },
+    Disconnected,
+}
+
+struct Status {
+    state: ConnState,
+    server: String,
+    nick: String,
+    room: Option<String>,
+    mentions: usize,
+    transfers: Option<String>,
+}
+
+/// Full-screen layout: a scrollable message viewport, a status bar and a fixed input line.
+/// Every mutation redraws the screen so that incoming messages never overwrite the input.
+pub struct Tui {
+    lines: Vec<Line>,
+    theme: Theme,
+    scroll: usize,
+    prompt: String,
+    hint: Option<String>,
+    input: String,
+    input_cursor: usize,
+    status: Status,
+    width: u16,
+    height: u16,
+}
+
+/// A line of the scrollback, or one row of it once wrapped.
+#[derive(Clone)]
+struct Line {
+    spans: Vec<Span>,
+    highlight: bool,
+}
+
+pub struct TerminalGuard {
+    keyboard_enhanced: bool,
+}
+
+impl TerminalGuard {
+    pub fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(
+            io::stdout(),
+            EnterAlternateScreen,
+            EnableMouseCapture,
+            EnableBracketedPaste,
+            cursor::MoveTo(0, 0),
+            Clear(ClearType::All)
+        )?;
+        // Lets terminals that support it report Shift-Enter apart from Enter.
+        let keyboard_enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if keyboard_enhanced {
+            execute!(
+                io::stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+            )?;
+        }
+        Ok(TerminalGuard { keyboard_enhanced })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if self.keyboard_enhanced {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(
+            io::stdout(),
+            DisableBracketedPaste,
+            DisableMouseCapture,
+            LeaveAlternateScreen
+        );
+        let _ = disable_raw_mode();
+    }
+}
+
+impl Tui {
+    pub fn new(server: &str, nick: &str, theme: Theme) -> io::Result<Self> {
+        let (width, height) = terminal::size()?;
+        Ok(Tui {
+            lines: Vec::new(),
+            theme,
+            scroll: 0,
+            prompt: PROMPT.to_string(),
+            hint: None,
+            input: String::new(),
+            input_cursor: 0,
+            status: Status {
+                state: ConnState::Connecting,
+                server: server.to_string(),
+                nick: nick.to_string(),
+                room: None,
+                mentions: 0,
+                transfers: None,
+            },
+            width,
+            height,
+        })
+    }
+
+    pub fn shared(self) -> SharedTui {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn println(&mut self, text: impl AsRef<str>) -> io::Result<()> {
+        self.print_spans(vec![Span::plain(text.as_ref())])
+    }
+
+    /// Prints a notice from the client or the server.
+    pub fn system(&mut self, text: impl AsRef<str>) -> io::Result<()> {
+        self.print_spans(vec![Span::new(text.as_ref(), Role::System)])
+    }
+
+    pub fn error(&mut self, text: impl AsRef<str>) -> io::Result<()> {
+        self.print_spans(vec![Span::new(text.as_ref(), Role::Error)])
+    }
+
+    /// Prints styled text, starting a new line at every `\n` inside the spans.
+    pub fn print_spans(&mut self, spans: Vec<Span>) -> io::Result<()> {
+        self.push_spans(spans, false)
+    }
+
+    /// Same as `print_spans`, with a background that makes the lines stand out.
+    pub fn print_highlighted(&mut self, spans: Vec<Span>) -> io::Result<()> {
+        self.push_spans(spans, true)
+    }
+
+    /// Writes a terminal bell or notification escape sequence.
+    pub fn notify(&mut self, sequence: &str) -> io::Result<()> {
+        let mut out = io::stdout();
+        out.write_all(sequence.as_bytes())?;
+        out.flush()
+    }
+
+    pub fn set_unread_mentions(&mut self, unread: usize) -> io::Result<()> {
+        self.status.mentions = unread;
+        self.draw()
+    }
+
+    /// Shows the progress of file transfers in the status bar.
+    pub fn set_transfers(&mut self, progress: Option<String>) -> io::Result<()> {
+        if self.status.transfers == progress {
+            return Ok(());
+        }
+        self.status.transfers = progress;
+        self.draw()
+    }
+
+    fn push_spans(&mut self, spans: Vec<Span>, highlight: bool) -> io::Result<()> {
+        let width = self.width as usize;
+        let mut line: Vec<Span> = Vec::new();
+        let mut lines = Vec::new();
+        for span in spans {
+            for (i, part) in span.text.split('\n').enumerate() {
+                if i > 0 {
+                    lines.push(std::mem::take(&mut line));
+                }
+                let part = printable(part.trim_end_matches('\r'));
+                if !part.is_empty() {
+                    line.push(Span::new(part, span.role));
+                }
+            }
+        }
+        lines.push(line);
+
+        for spans in lines {
+            let line = Line { spans, highlight };
+            if self.scroll > 0 {
+                self.scroll += wrap(&line, width).len();
+            }
+            self.lines.push(line);
+        }
+        if self.lines.len() > SCROLLBACK_LIMIT {
+            let excess = self.lines.len() - SCROLLBACK_LIMIT;
+            self.lines.drain(..excess);
+        }
+        self.draw()
+    }
+
+    /// Replaces the input line, `cursor` being a byte offset into `input`.
+    pub fn set_input(&mut self, input: &str, cursor: usize) -> io::Result<()> {
+        let rows = self.input_rows();
+        self.input.clear();
+        self.input.push_str(input);
+        self.input_cursor = cursor.min(input.len());
+        if self.input_rows() != rows {
+            return self.draw();
+        }
+        self.draw_input()
+    }
+
+    /// Replaces the prompt in front of the input line, `None` restoring the default one.
+    pub fn set_prompt(&mut self, prompt: Option<&str>) -> io::Result<()> {
+        self.prompt = prompt.unwrap_or(PROMPT).to_string();
+        self.draw_input()
+    }
+
+    /// Shows a line of hints (completion candidates) right above the status bar.
+    pub fn set_hint(&mut self, hint: Option<String>) -> io::Result<()> {
+        if self.hint == hint {
+            return Ok(());
+        }
+        self.hint = hint;
+        self.draw()
+    }
+
+    pub fn set_room(&mut self, room: &str) -> io::Result<()> {
+        self.status.room = Some(room.to_string());
+        self.draw()
+    }
+
+    pub fn set_state(&mut self, state: ConnState) -> io::Result<()> {
+        self.status.state = state;
+        self.draw()
+    }
+
+    pub fn resize(&mut self, width: u16, height: u16) -> io::Result<()> {
+        self.width = width;
+        self.height = height;
+        self.scroll = self.scroll.min(self.max_scroll());
+        execute!(io::stdout(), Clear(ClearType::All))?;
+        self.draw()
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) -> io::Result<()> {
+        self.scroll = (self.scroll + rows).min(self.max_scroll());
+        self.draw()
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) -> io::Result<()> {
+        self.scroll = self.scroll.saturating_sub(rows);
+        self.draw()
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.viewport_height().saturating_sub(1).max(1)
+    }
+
+    fn viewport_height(&self) -> usize {
+        let reserved = self.input_rows() + 1 + usize::from(self.hint.is_some());
+        (self.height as usize).saturating_sub(reserved)
+    }
+
+    fn status_row(&self) -> usize {
+        (self.height as usize).saturating_sub(self.input_rows() + 1)
+    }
+
+    fn max_scroll(&self) -> usize {
+        let width = self.width as usize;
+        let total: usize = self.lines.iter().map(|l| wrap(l, width).len()).sum();
+        total.saturating_sub(self.viewport_height())
+    }
+
+    fn visible_rows(&self) -> Vec<Line> {
+        let width = self.width as usize;
+        let wanted = self.viewport_height() + self.scroll;
+        let mut rows: Vec<Line> = Vec::with_capacity(wanted);
+
+        for line in self.lines.iter().rev() {
+            for row in wrap(line, width).into_iter().rev() {
+                rows.push(row);
+            }
+            if rows.len() >= wanted {
+                break;
+            }
+        }
+        rows.truncate(wanted);
+        rows.drain(..self.scroll.min(rows.len()));
+        rows.reverse();
+        rows
+    }
+
+    pub fn draw(&self) -> io::Result<()> {
+        let mut out = io::stdout();
+        let view_height = self.viewport_height();
+        let rows = self.visible_rows();
+        let padding = view_height.saturating_sub(rows.len());
+
+        for y in 0..view_height {
+            queue!(
+                out,
+                cursor::MoveTo(0, y as u16),
+                Clear(ClearType::CurrentLine)
+            )?;
+            if y >= padding {
+                let row = &rows[y - padding];
+                let background = self.theme.highlight().filter(|_| row.highlight);
+                let mut used = 0;
+                for span in &row.spans {
+                    let mut style = self.theme.style(span);
+                    style.background_color = background.or(style.background_color);
+                    used += span.text.width();
+                    queue!(
+                        out,
+                        PrintStyledContent(StyledContent::new(style, span.text.as_str()))
+                    )?;
+                }
+                if let Some(background) = background {
+                    let padding = " ".repeat((self.width as usize).saturating_sub(used));
+                    queue!(out, PrintStyledContent(padding.on(background)))?;
+                }
+            }
+        }
+        self.queue_hint(&mut out)?;
+        self.queue_status(&mut out)?;
+        self.queue_input(&mut out)?;
+        out.flush()
+    }
+
+    fn draw_input(&self) -> io::Result<()> {
+        let mut out = io::stdout();
+        self.queue_input(&mut out)?;
+        out.flush()
+    }
+
+    fn queue_hint(&self, out: &mut impl Write) -> io::Result<()> {
+        let Some(hint) = &self.hint else {
+            return Ok(());
+        };
+        if self.status_row() == 0 {
+            return Ok(());
+        }
+        queue!(
+            out,
+            cursor::MoveTo(0, (self.status_row() - 1) as u16),
+            Clear(ClearType::CurrentLine),
+            SetAttribute(Attribute::Dim),
+            Print(fit(hint, self.width as usize)),
+            SetAttribute(Attribute::Reset)
+        )
+    }
+
+    fn queue_status(&self, out: &mut impl Write) -> io::Result<()> {
+        if (self.height as usize) < self.input_rows() + 1 {
+            return Ok(());
+        }
+        let state = match &self.status.state {
+            ConnState::Connecting => "connecting".to_string(),
+            ConnState::Connected => "connected".to_string(),
+            ConnState::Retrying { secs, attempts } => {
+                format!("retrying in {secs}s (attempt {attempts})")
+            }
+            ConnState::Disconnected => "disconnected".to_string(),
+        };
+        let mut bar = format!(" [{state}] {} as {}", self.status.server, self.status.nick);
+        if let Some(room) = &self.status.room {
+            bar.push_str(&format!(" in #{room}"));
+        }
+        if self.status.mentions > 0 {
+            bar.push_str(&format!(
+                " | {} unread mentions (/mentions)",
+                self.status.mentions
+            ));
+        }
+        if let Some(transfers) = &self.status.transfers {
+            bar.push_str(&format!(" | {transfers}"));
+        }
+        if self.scroll > 0 {
+            bar.push_str(&format!(" -- scrolled up {} lines --", self.scroll));
+        }
+        let bar = fit(&bar, self.width as usize);
+
+        queue!(
+            out,
+            cursor::MoveTo(0, self.status_row() as u16),
+            Clear(ClearType::CurrentLine),
+            SetAttribute(Attribute::Reverse),
+            Print(bar),
+            SetAttribute(Attribute::Reset)
+        )
+    }
+
+    fn queue_input(&self, out: &mut impl Write) -> io::Result<()> {
+        let rows = self.input_rows();
+        if (self.height as usize) < rows {
+            return Ok(());
+        }
+        let top = self.height as usize - rows;
+        let prompt = fit(
+            &self.prompt,
+            self.prompt.width().min(self.width as usize / 2),
+        );
+        let indent = " ".repeat(prompt.width());
+        let available = (self.width as usize).saturating_sub(prompt.width() + 1);
+
+        let mut lines = Vec::new();
+        let mut offset = 0;
+        for line in self.input.split('\n') {
+            lines.push((offset, line));
+            offset += line.len() + 1;
+        }
+        let cursor_line = lines
+            .iter()
+            .rposition(|&(start, _)| start <= self.input_cursor)
+            .unwrap_or(0);
+        // Keep the line holding the cursor inside the rows that are shown.
+        let first = (cursor_line + 1).saturating_sub(rows);
+
+        let mut cursor_at = (prompt.width(), top);
+        for (row, &(start, line)) in lines[first..first + rows].iter().enumerate() {
+            let prefix = if first + row == 0 { &prompt } else { &indent };
+            let cursor = (first + row == cursor_line).then(|| self.input_cursor - start);
+            let (visible, column) = scroll_line(line, cursor, available);
+            queue!(
+                out,
+                cursor::MoveTo(0, (top + row) as u16),
+                Clear(ClearType::CurrentLine),
+                Print(prefix),
+                Print(visible)
+            )?;
+            if let Some(column) = column {
+                cursor_at = (prefix.width() + column, top + row);
+            }
+        }
+        queue!(out, cursor::MoveTo(cursor_at.0 as u16, cursor_at.1 as u16))
+    }
+
+    fn input_rows(&self) -> usize {
+        self.input.split('\n').count().clamp(1, INPUT_MAX_ROWS)
+    }
+}
+
+/// Cuts the part of `line` that fits in `available` columns, scrolling horizontally
+/// so that `cursor` (a byte offset) stays in view. Returns the cursor's column.
+fn scroll_line(line: &str, cursor: Option<usize>, available: usize) -> (String, Option<usize>) {
+    let graphemes: Vec<(usize, &str)> = line.grapheme_indices(true).collect();
+    let cursor_index = cursor.map(|cursor| {
+        graphemes
+            .iter()
+            .position(|&(idx, _)| idx >= cursor)
+            .unwrap_or(graphemes.len())
+    });
+
+    let mut start = cursor_index.unwrap_or(0);
+    let mut before_cursor = 0;
+    while cursor_index.is_some()
+        && start > 0
+        && before_cursor + graphemes[start - 1].1.width() <= available
+    {
+        start -= 1;
+        before_cursor += graphemes[start].1.width();
+    }
+    let mut visible = String::new();
+    let mut used = 0;
+    for &(_, g) in &graphemes[start..] {
+        if used + g.width() > available {
+            break;
+        }
+        used += g.width();
+        visible.push_str(g);
+    }
+    (visible, cursor_index.map(|_| before_cursor))
+}
+
+/// Splits a line into rows that are at most `width` columns wide on screen.
+fn wrap(line: &Line, width: usize) -> Vec<Line> {
+    let mut rows = Vec::new();
+    let mut row: Vec<Span> = Vec::new();
+    let mut used = 0;
+    for span in &line.spans {
+        let mut text = String::new();
+        for g in span.text.graphemes(true) {
+            let w = g.width();
+            if width > 0 && used + w > width && used > 0 {
+                if !text.is_empty() {
+                    row.push(Span::new(std::mem::take(&mut text), span.role));
+                }
+                rows.push(std::mem::take(&mut row));
+                used = 0;
+            }
+            text.push_str(g);
+            used += w;
+        }
+        if !text.is_empty() {
+            row.push(Span::new(text, span.role));
+        }
+    }
+    rows.push(row);
+    rows.into_iter()
+        .map(|spans| Line {
+            spans,
+            highlight: line.highlight,
+        })
+        .collect()
+}
+
+/// Replaces control characters with spaces, for text from the network not to
+/// move the cursor or send escape sequences to the terminal.
+pub fn printable(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Pads or truncates `text` so that it fills exactly `width` columns.
+fn fit(text: &str, width: usize) -> String {
+    let mut bar = String::new();
+    let mut used = 0;
+    for g in printable(text).graphemes(true) {
+        if used + g.width() > width {
+            break;
+        }
+        used += g.width();
+        bar.push_str(g);
+    }
+    bar.extend(std::iter::repeat_n(' ', width - used));
+    bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_sequences_are_not_printed() {
+        let text = "hi\u{1b}]0;pwned\u{7}\u{1b}[2J\u{9b}31m there";
+        assert!(!printable(text).chars().any(char::is_control));
+        assert_eq!(printable("caf\u{e9} \u{1f600}"), "caf\u{e9} \u{1f600}");
+    }
+
+    #[test]
+    fn bars_fit_without_control_characters() {
+        assert_eq!(fit("#a\u{1b}[1m", 8), "#a [1m  ");
+        assert_eq!(fit("abcdef", 3), "abc");
+    }
+}
@@ -0,0 +1,165 @@
+//! Controls a running server through its admin socket.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use chrono::Local;
+use clap::{Parser, Subcommand};
+use yarca::admin::{AdminReply, AdminRequest, ClientInfo};
+
+#[derive(Parser)]
+#[command(version, about = "Controls a running YARCA server")]
+struct Args {
+    /// Admin socket of the server, its `admin_socket` setting
+    #[arg(long, env = "ADMIN_SOCKET", value_name = "PATH")]
+    socket: PathBuf,
+    /// Print the server's reply as JSON
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List who is connected, from where and since when
+    Clients,
+    /// Disconnect someone
+    Kick {
+        nick: String,
+        reason: Option<String>,
+    },
+    /// Ban a nickname or an IP address, disconnecting whoever it matches
+    Ban { target: String },
+    /// Send a message to everyone
+    Announce { text: String },
+    /// Read the config file again
+    Reload,
+    /// Print the server's metrics
+    Stats,
+    /// Read the key file again, disconnecting everyone for them to reconnect with the new key
+    RotateKey,
+}
+
+impl From<Command> for AdminRequest {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Clients => AdminRequest::Clients,
+            Command::Kick { nick, reason } => AdminRequest::Kick { nick, reason },
+            Command::Ban { target } => AdminRequest::Ban { target },
+            Command::Announce { text } => AdminRequest::Announce { text },
+            Command::Reload => AdminRequest::Reload,
+            Command::Stats => AdminRequest::Stats,
+            Command::RotateKey => AdminRequest::RotateKey,
+        }
+    }
+}
+
+fn main() {
+    dotenvy::dotenv().ok();
+
+    let args = Args::parse();
+    let line = match request(&args.socket, &args.command.into()) {
+        Ok(line) => line,
+        Err(e) => {
+            eprintln!(
+                "Cannot talk to the server on {}: {e}. Is it running with admin_socket set?",
+                args.socket.display()
+            );
+            exit(1);
+        }
+    };
+    if args.json {
+        print!("{line}");
+    }
+    match serde_json::from_str(&line) {
+        Ok(AdminReply::Error { error }) => {
+            if !args.json {
+                eprintln!("{error}");
+            }
+            exit(1);
+        }
+        Ok(_) if args.json => {}
+        Ok(AdminReply::Done { text }) => println!("{}", text.trim_end()),
+        Ok(AdminReply::Clients { clients }) => print_clients(&clients),
+        Err(e) => {
+            eprintln!("Unexpected reply from the server: {e}");
+            exit(1);
+        }
+    }
+}
+
+/// Sends `request` and returns the line the server answered.
+#[cfg(unix)]
+fn request(socket: &Path, request: &AdminRequest) -> io::Result<String> {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream = UnixStream::connect(socket)?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    if reply.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the connection closed without a reply",
+        ));
+    }
+    Ok(reply)
+}
+
+#[cfg(not(unix))]
+fn request(_socket: &Path, _request: &AdminRequest) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the admin socket needs Unix domain sockets",
+    ))
+}
+
+fn print_clients(clients: &[ClientInfo]) {
+    if clients.is_empty() {
+        println!("Nobody is connected.");
+        return;
+    }
+    let rows: Vec<[String; 4]> = clients
+        .iter()
+        .map(|client| {
+            let from = match (&client.ip, &client.server) {
+                (_, Some(server)) => format!("on {server}"),
+                (Some(ip), None) => ip.clone(),
+                (None, None) => String::new(),
+            };
+            let connected = client.connected.with_timezone(&Local);
+            [
+                client.nick.clone(),
+                format!("#{}", client.room),
+                from,
+                connected.format("%Y-%m-%d %H:%M:%S").to_string(),
+            ]
+        })
+        .collect();
+    let header = ["NICK", "ROOM", "FROM", "CONNECTED"].map(String::from);
+    let mut widths = [0; 4];
+    for row in rows.iter().chain([&header]) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in [&header].into_iter().chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
@@ -0,0 +1,367 @@
+//! Building blocks for chat bots.
+//!
+//! A [`Bot`] holds the connection settings and the slash commands it handles,
+//! and [`Bot::run`] keeps it connected, reconnecting with a [`Backoff`] when the
+//! connection drops, while a [`Handler`] reacts to what happens in its room.
+//!
+//! ```no_run
+//! use yarca::bot::{Bot, Command, Context, Handler};
+//!
+//! struct Ping;
+//!
+//! impl Handler for Ping {
+//!     fn on_command(&mut self, ctx: &mut Context, command: &Command) -> std::io::Result<()> {
+//!         ctx.reply(command, "pong")
+//!     }
+//! }
+//!
+//! let key = *b"0123456789abcdef0123456789abcdef";
+//! Bot::new("127.0.0.1:8080", "pingbot", key)
+//!     .command("ping", "Answers pong")
+//!     .run(&mut Ping)
+//!     .unwrap();
+//! ```
+
+use std::{
+    io,
+    net::{Shutdown, TcpStream},
+    thread,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    backoff::Backoff,
+    protocol::{
+        ClientFrame, CommandInfo, DEFAULT_ROOM, FrameError, FrameReader, PROTOCOL_VERSION,
+        ServerFrame, write_frame,
+    },
+};
+
+/// Chat message received in the bot's room.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub id: u64,
+    pub ts: DateTime<Utc>,
+    pub from: String,
+    pub room: String,
+    pub body: String,
+}
+
+/// Use of one of the bot's slash commands.
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub from: String,
+    /// Room the command was used in.
+    pub room: String,
+    pub name: String,
+    pub args: String,
+}
+
+impl Command {
+    /// Arguments split on whitespace.
+    pub fn args(&self) -> impl Iterator<Item = &str> {
+        self.args.split_whitespace()
+    }
+}
+
+/// Something that can be replied to: the reply goes to the same room and
+/// starts with the sender's nickname.
+pub trait Origin {
+    fn from(&self) -> &str;
+    fn room(&self) -> &str;
+}
+
+impl Origin for Message {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+impl Origin for Command {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+/// Reactions of a bot. Every method does nothing by default.
+///
+/// An error returned by a method is treated as a lost connection: the bot
+/// reconnects and carries on.
+pub trait Handler {
+    /// Called after every connection. What it sends arrives once the rooms are joined.
+    fn on_connect(&mut self, _ctx: &mut Context) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Messages of others in the bot's room.
+    fn on_message(&mut self, _ctx: &mut Context, _message: &Message) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Someone else arrived in the bot's room.
+    fn on_join(&mut self, _ctx: &mut Context, _nick: &str, _room: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Someone else left the bot's room.
+    fn on_leave(&mut self, _ctx: &mut Context, _nick: &str, _room: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// One of the commands given to [`Bot::command`] was used, in any room.
+    fn on_command(&mut self, _ctx: &mut Context, _command: &Command) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The connection was lost, the bot is about to reconnect.
+    fn on_disconnect(&mut self, _error: &io::Error) {}
+}
+
+/// What a [`Handler`] can do while connected.
+pub struct Context<'a> {
+    stream: &'a mut TcpStream,
+    key: &'a [u8; 32],
+    nick: &'a str,
+    room: &'a str,
+    quit: bool,
+}
+
+impl Context<'_> {
+    pub fn nick(&self) -> &str {
+        self.nick
+    }
+
+    /// Room the bot is in.
+    pub fn room(&self) -> &str {
+        self.room
+    }
+
+    /// Sends a message to the bot's room.
+    pub fn send(&mut self, body: impl Into<String>) -> io::Result<()> {
+        self.write(&ClientFrame::Message {
+            body: body.into(),
+            room: None,
+        })
+    }
+
+    /// Sends a message to any room, even one the bot is not in.
+    pub fn send_to(&mut self, room: &str, body: impl Into<String>) -> io::Result<()> {
+        self.write(&ClientFrame::Message {
+            body: body.into(),
+            room: Some(room.to_string()),
+        })
+    }
+
+    /// Answers a message or a command in the room it came from, as `nick: body`.
+    pub fn reply(&mut self, to: &impl Origin, body: impl AsRef<str>) -> io::Result<()> {
+        let body = format!("{}: {}", to.from(), body.as_ref());
+        self.send_to(to.room(), body)
+    }
+
+    /// Moves the bot to another room.
+    pub fn join(&mut self, room: &str) -> io::Result<()> {
+        self.write(&ClientFrame::Join {
+            room: room.to_string(),
+        })
+    }
+
+    /// Makes [`Bot::run`] return once the current handler is done.
+    pub fn quit(&mut self) {
+        self.quit = true;
+    }
+
+    fn write(&mut self, frame: &ClientFrame) -> io::Result<()> {
+        write_frame(self.stream, frame, self.key)
+    }
+}
+
+pub struct Bot {
+    addr: String,
+    nick: String,
+    key: [u8; 32],
+    rooms: Vec<String>,
+    commands: Vec<CommandInfo>,
+    backoff: Backoff,
+}
+
+impl Bot {
+    pub fn new(addr: impl Into<String>, nick: impl Into<String>, key: [u8; 32]) -> Self {
+        Bot {
+            addr: addr.into(),
+            nick: nick.into(),
+            key,
+            rooms: Vec::new(),
+            commands: Vec::new(),
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Room joined after every connection, the last one given being where the bot stays.
+    pub fn join(mut self, room: impl Into<String>) -> Self {
+        self.rooms.push(room.into());
+        self
+    }
+
+    /// Registers the slash command `/name` with the server, shown in the clients' help.
+    pub fn command(mut self, name: impl Into<String>, help: impl Into<String>) -> Self {
+        self.commands.push(CommandInfo {
+            name: name.into(),
+            usage: String::new(),
+            help: help.into(),
+        });
+        self
+    }
+
+    /// Delays between reconnections, one second doubling up to a minute by default.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Connects and hands events to `handler` until it calls [`Context::quit`].
+    /// Returns an error once the backoff gives up reconnecting.
+    pub fn run(mut self, handler: &mut impl Handler) -> io::Result<()> {
+        loop {
+            let error = match self.session(handler) {
+                Ok(true) => return Ok(()),
+                Ok(false) => io::ErrorKind::UnexpectedEof.into(),
+                Err(e) => e,
+            };
+            handler.on_disconnect(&error);
+            match self.backoff.next_delay() {
+                Some(delay) => thread::sleep(delay),
+                None => return Err(error),
+            }
+        }
+    }
+
+    /// Returns whether the handler asked to quit, `false` if the server closed
+    /// the connection.
+    fn session(&mut self, handler: &mut impl Handler) -> io::Result<bool> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        let key = self.key;
+        let hello = ClientFrame::Hello {
+            nick: self.nick.clone(),
+            version: PROTOCOL_VERSION,
+            token: None,
+        };
+        write_frame(&mut stream, &hello, &key)?;
+        if !self.commands.is_empty() {
+            let register = ClientFrame::Register {
+                commands: self.commands.clone(),
+            };
+            write_frame(&mut stream, &register, &key)?;
+        }
+        for room in &self.rooms {
+            write_frame(&mut stream, &ClientFrame::Join { room: room.clone() }, &key)?;
+        }
+
+        // The server handles frames in order, so whatever `on_connect` sends
+        // arrives once the rooms are joined.
+        let mut room = self
+            .rooms
+            .last()
+            .map_or(DEFAULT_ROOM, String::as_str)
+            .to_string();
+        let mut ctx = Context {
+            stream: &mut stream,
+            key: &key,
+            nick: &self.nick,
+            room: &room,
+            quit: false,
+        };
+        handler.on_connect(&mut ctx)?;
+        if ctx.quit {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Ok(true);
+        }
+
+        let mut reader = FrameReader::new(stream.try_clone()?);
+        loop {
+            let frame = match reader.read_frame::<ServerFrame>(&key) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(false),
+                Err(FrameError::Io(e)) => return Err(e),
+                // Only this frame is lost, the next one may be fine.
+                Err(_) => continue,
+            };
+            if let ServerFrame::Welcome { room: joined, .. } = &frame {
+                // Only accepted now, servers turning the bot down close first.
+                self.backoff.reset();
+                room.clone_from(joined);
+            }
+
+            let mut ctx = Context {
+                stream: &mut stream,
+                key: &key,
+                nick: &self.nick,
+                room: &room,
+                quit: false,
+            };
+            match frame {
+                ServerFrame::Message {
+                    id,
+                    ts,
+                    from,
+                    room,
+                    body,
+                } if from != self.nick => {
+                    let message = Message {
+                        id,
+                        ts,
+                        from,
+                        room,
+                        body,
+                    };
+                    handler.on_message(&mut ctx, &message)?;
+                }
+                ServerFrame::Joined { nick, room } if nick != self.nick => {
+                    handler.on_join(&mut ctx, &nick, &room)?;
+                }
+                ServerFrame::Left { nick, room } if nick != self.nick => {
+                    handler.on_leave(&mut ctx, &nick, &room)?;
+                }
+                ServerFrame::Command {
+                    from,
+                    room,
+                    name,
+                    args,
+                } => {
+                    let command = Command {
+                        from,
+                        room,
+                        name,
+                        args,
+                    };
+                    handler.on_command(&mut ctx, &command)?;
+                }
+                _ => {}
+            }
+            if ctx.quit {
+                let _ = stream.shutdown(Shutdown::Both);
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Reads a 32 bytes key from the environment variable `var`, `.env` excluded.
+pub fn key_from_env(var: &str) -> io::Result<[u8; 32]> {
+    let key = std::env::var(var)
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("{var} is not set")))?;
+    key.as_bytes().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{var} must be exactly 32 bytes long"),
+        )
+    })
+}
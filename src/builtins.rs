@@ -0,0 +1,161 @@
+use yarca::protocol::ServerFrame;
+
+use crate::{
+    Clients, broadcast_room,
+    commands::{Context, Permission, ServerCommand, ServerCommands},
+    log, room_members, send, valid_room,
+};
+
+/// Commands every server has.
+pub fn builtins() -> ServerCommands {
+    let mut commands = ServerCommands::default();
+    commands.add(ServerCommand {
+        name: "who",
+        usage: "[room]",
+        help: "Lists who is in a room, yours by default",
+        permission: Permission::Everyone,
+        args: (0, 1),
+        run: who,
+    });
+    commands.add(ServerCommand {
+        name: "topic",
+        usage: "[text]",
+        help: "Shows or sets the topic of your room",
+        permission: Permission::Everyone,
+        args: (0, 1),
+        run: topic,
+    });
+    commands.add(ServerCommand {
+        name: "oper",
+        usage: "<password>",
+        help: "Makes you an operator, with the password of your nickname",
+        permission: Permission::Everyone,
+        args: (1, 1),
+        run: oper,
+    });
+    commands.add(ServerCommand {
+        name: "kick",
+        usage: "<nick> [reason]",
+        help: "Disconnects someone",
+        permission: Permission::Operator,
+        args: (1, 2),
+        run: kick,
+    });
+    commands
+}
+
+fn who(ctx: &mut Context, args: &[&str]) -> Result<(), String> {
+    let room = match args.first() {
+        Some(room) => room.trim_start_matches('#').to_string(),
+        None => ctx.room(),
+    };
+    if !valid_room(&room) {
+        return Err(format!("Invalid room name \"{room}\"."));
+    }
+    let nicks = room_members(ctx.clients, &room);
+    if nicks.is_empty() {
+        ctx.reply(format!("Nobody is in #{room}."));
+    } else {
+        ctx.reply(format!("In #{room}: {}", nicks.join(", ")));
+    }
+    Ok(())
+}
+
+fn topic(ctx: &mut Context, args: &[&str]) -> Result<(), String> {
+    let room = ctx.room();
+    let Some(text) = args.first() else {
+        match ctx.topics.get(&room) {
+            Some(topic) => ctx.reply(format!("Topic of #{room}: {topic}")),
+            None => ctx.reply(format!("#{room} has no topic.")),
+        }
+        return Ok(());
+    };
+    info!("{} set the topic of #{room}: {text}", ctx.sender);
+    ctx.topics.insert(room.clone(), text.to_string());
+    let announce = ServerFrame::System {
+        text: format!("{} set the topic of #{room}: {text}", ctx.sender),
+    };
+    broadcast_room(ctx.clients, &room, None, &announce, ctx.key);
+    Ok(())
+}
+
+fn oper(ctx: &mut Context, args: &[&str]) -> Result<(), String> {
+    if ctx.operators.owner(args[0]) != Some(ctx.sender) {
+        warn!(
+            target: log::AUDIT,
+            event = "auth_failed",
+            via = "oper",
+            nick = %ctx.sender,
+            "Wrong operator password"
+        );
+        return Err("Wrong operator password.".to_string());
+    }
+    info!(
+        target: log::AUDIT,
+        event = "oper",
+        nick = %ctx.sender,
+        "Became an operator"
+    );
+    if let Some(client) = ctx.clients.get_mut(ctx.sender) {
+        client.operator = true;
+    }
+    ctx.reply("You are now an operator.");
+    Ok(())
+}
+
+fn kick(ctx: &mut Context, args: &[&str]) -> Result<(), String> {
+    let room = kick_client(
+        ctx.clients,
+        args[0],
+        ctx.sender,
+        args.get(1).copied(),
+        ctx.key,
+    )?;
+    if ctx.room() != room {
+        ctx.reply(format!("Kicked {}.", args[0]));
+    }
+    Ok(())
+}
+
+/// Disconnects `nick`, telling them and their room who did it and why.
+/// Returns the room they were in.
+pub fn kick_client(
+    clients: &Clients,
+    nick: &str,
+    by: &str,
+    reason: Option<&str>,
+    key: &[u8; 32],
+) -> Result<String, String> {
+    let Some(target) = clients.get(nick) else {
+        return Err(format!("{nick} is not online."));
+    };
+    if let Some(server) = target.conn.server() {
+        return Err(format!("{nick} is on {server}, ask an operator there."));
+    }
+    info!(
+        target: log::AUDIT,
+        event = "kick",
+        %nick,
+        %by,
+        reason,
+        "Kicked"
+    );
+    let reason = match reason {
+        Some(reason) => format!("{by}: {reason}"),
+        None => by.to_string(),
+    };
+    let notice = ServerFrame::System {
+        text: format!("You were kicked by {reason}"),
+    };
+    send(target, &notice, key);
+    // The client's handler notices the closed connection and reports it as a
+    // disconnection, which tells the room they left.
+    target.conn.close();
+
+    let room = target.room.clone();
+    let announce = ServerFrame::System {
+        text: format!("{nick} was kicked by {reason}"),
+    };
+    broadcast_room(clients, &room, Some(nick), &announce, key);
+    Ok(room)
+}
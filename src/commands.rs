@@ -0,0 +1,223 @@
+use std::collections::{BTreeMap, HashMap};
+
+use yarca::protocol::{CommandInfo, ServerFrame};
+
+use crate::{Clients, config::Tokens, log, send};
+
+/// Who may use a server command.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Permission {
+    Everyone,
+    /// Clients who gave their operator password to `/oper`.
+    Operator,
+}
+
+/// What a server command can reach while it runs.
+pub struct Context<'a> {
+    pub clients: &'a mut Clients,
+    /// Topic of each room that has one.
+    pub topics: &'a mut HashMap<String, String>,
+    /// Operator passwords, by nickname.
+    pub operators: &'a Tokens,
+    pub sender: &'a str,
+    pub key: &'a [u8; 32],
+}
+
+impl Context<'_> {
+    /// Room the command was used in.
+    pub fn room(&self) -> String {
+        self.clients
+            .get(self.sender)
+            .map(|client| client.room.clone())
+            .unwrap_or_default()
+    }
+
+    /// Sends a system message to whoever used the command.
+    pub fn reply(&self, text: impl Into<String>) {
+        if let Some(client) = self.clients.get(self.sender) {
+            send(client, &ServerFrame::System { text: text.into() }, self.key);
+        }
+    }
+}
+
+/// Returns an error message for the sender when the command fails.
+type Run = fn(&mut Context, &[&str]) -> Result<(), String>;
+
+/// Slash command handled by the server itself.
+pub struct ServerCommand {
+    pub name: &'static str,
+    /// Arguments, like `<nick> [reason]`.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub permission: Permission,
+    /// Fewest and most arguments. The last one takes the rest of the line,
+    /// spaces included.
+    pub args: (usize, usize),
+    pub run: Run,
+}
+
+impl ServerCommand {
+    /// Checks the permission and the number of arguments before running the command.
+    pub fn call(&self, ctx: &mut Context, args: &str, operator: bool) -> Result<(), String> {
+        if self.permission == Permission::Operator && !operator {
+            warn!(
+                target: log::AUDIT,
+                event = "denied",
+                nick = %ctx.sender,
+                command = self.name,
+                "Operator command refused"
+            );
+            return Err(format!("/{} is for operators only.", self.name));
+        }
+        let (min, max) = self.args;
+        let args = split_args(args, max);
+        if args.len() < min {
+            return Err(format!("Usage: /{} {}", self.name, self.usage));
+        }
+        (self.run)(ctx, &args)
+    }
+
+    fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name.to_string(),
+            usage: self.usage.to_string(),
+            help: self.help.to_string(),
+        }
+    }
+}
+
+/// Slash commands handled by the server, by name.
+#[derive(Default)]
+pub struct ServerCommands {
+    commands: BTreeMap<&'static str, ServerCommand>,
+}
+
+impl ServerCommands {
+    pub fn add(&mut self, command: ServerCommand) {
+        self.commands.insert(command.name, command);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ServerCommand> {
+        self.commands.get(name)
+    }
+
+    /// Commands an operator, or anyone else, may use.
+    pub fn list(&self, operator: bool) -> Vec<CommandInfo> {
+        self.commands
+            .values()
+            .filter(|command| operator || command.permission == Permission::Everyone)
+            .map(ServerCommand::info)
+            .collect()
+    }
+}
+
+/// Splits `args` on whitespace into at most `max` arguments, the last one
+/// keeping the rest of the line.
+fn split_args(args: &str, max: usize) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut rest = args.trim();
+    while !rest.is_empty() && split.len() < max {
+        if split.len() + 1 == max {
+            split.push(rest);
+            break;
+        }
+        let (arg, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        split.push(arg);
+        rest = tail.trim_start();
+    }
+    split
+}
+
+/// Slash commands registered by the bots that are online, by name.
+#[derive(Default)]
+pub struct BotCommands {
+    commands: BTreeMap<String, (String, CommandInfo)>,
+}
+
+impl BotCommands {
+    /// Replaces the commands of `bot`. Nothing changes if one of them is invalid,
+    /// a server command or already belongs to another bot.
+    pub fn register(
+        &mut self,
+        bot: &str,
+        commands: Vec<CommandInfo>,
+        server: &ServerCommands,
+    ) -> Result<(), String> {
+        for command in &commands {
+            if !valid_name(&command.name) {
+                return Err(format!("Invalid command name \"{}\".", command.name));
+            }
+            if server.get(&command.name).is_some() {
+                return Err(format!("/{} is a server command.", command.name));
+            }
+            if let Some(owner) = self.owner(&command.name)
+                && owner != bot
+            {
+                return Err(format!("/{} is already handled by {owner}.", command.name));
+            }
+        }
+        self.remove_bot(bot);
+        for command in commands {
+            self.commands
+                .insert(command.name.clone(), (bot.to_string(), command));
+        }
+        Ok(())
+    }
+
+    /// Forgets the commands of `bot`, returning whether it had any.
+    pub fn remove_bot(&mut self, bot: &str) -> bool {
+        let before = self.commands.len();
+        self.commands.retain(|_, (owner, _)| owner != bot);
+        self.commands.len() != before
+    }
+
+    /// Bot handling the command `name`.
+    pub fn owner(&self, name: &str) -> Option<&str> {
+        self.commands.get(name).map(|(owner, _)| owner.as_str())
+    }
+
+    pub fn is_bot(&self, nick: &str) -> bool {
+        self.commands.values().any(|(owner, _)| owner == nick)
+    }
+
+    pub fn list(&self) -> Vec<CommandInfo> {
+        self.commands
+            .values()
+            .map(|(_, info)| info.clone())
+            .collect()
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_args_keeps_the_rest_in_the_last_one() {
+        assert_eq!(
+            split_args("bob  spamming the  room ", 2),
+            ["bob", "spamming the  room"]
+        );
+        assert_eq!(split_args("bob", 2), ["bob"]);
+        assert_eq!(split_args("  a b c", 3), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn split_args_of_nothing_is_empty() {
+        assert!(split_args("   ", 2).is_empty());
+        assert!(split_args("anything", 0).is_empty());
+    }
+
+    #[test]
+    fn one_argument_takes_the_whole_line() {
+        assert_eq!(split_args(" a long topic ", 1), ["a long topic"]);
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use yarca::protocol::MAX_MESSAGE_LEN;
+
+use crate::{
+    log::{LogFormat, LogLevel, Output},
+    webhooks::{Webhook, Webhooks},
+};
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+const DEFAULT_HISTORY_AGE: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_SHUTDOWN_GRACE: u64 = 5;
+
+/// Settings given on the command line take precedence over the environment
+/// (`.env` included), which takes precedence over the config file.
+#[derive(Parser)]
+#[command(version, about = "YARCA chat server")]
+pub struct Args {
+    /// Address to listen on [default: 0.0.0.0:8080]
+    #[arg(long, env = "ADDR", value_name = "ADDR")]
+    listen: Option<String>,
+    /// Address of the WebSocket gateway for browsers, off by default
+    #[arg(long, env = "WEBSOCKET_ADDR", value_name = "ADDR")]
+    websocket: Option<String>,
+    /// Address of the IRC gateway, off by default
+    #[arg(long, env = "IRC_ADDR", value_name = "ADDR")]
+    irc: Option<String>,
+    /// Address of the HTTP API, off by default
+    #[arg(long, env = "HTTP_ADDR", value_name = "ADDR")]
+    http: Option<String>,
+    /// Address serving Prometheus metrics on /metrics, off by default
+    #[arg(long, env = "METRICS_ADDR", value_name = "ADDR")]
+    metrics: Option<String>,
+    /// Unix socket taking `yarca-admin` commands, off by default
+    #[arg(long, env = "ADMIN_SOCKET", value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+    /// File holding the 32 bytes key, used instead of `SECRET`
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+    /// TOML config file, reloaded on SIGHUP
+    #[arg(long, env = "SERVER_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Least severe messages to log [default: info]
+    #[arg(long, env = "LOG_LEVEL", value_enum)]
+    log_level: Option<LogLevel>,
+    /// How to write logs [default: text]
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// File to append logs to, instead of stderr
+    #[arg(long, env = "LOG_FILE", value_name = "PATH")]
+    log_file: Option<PathBuf>,
+}
+
+/// Server config file, every key being optional.
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: Option<String>,
+    websocket: Option<String>,
+    irc: Option<String>,
+    metrics: Option<String>,
+    admin_socket: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+    log_format: Option<LogFormat>,
+    log_file: Option<PathBuf>,
+    log_message_bodies: bool,
+    audit_file: Option<PathBuf>,
+    motd: Option<String>,
+    ban_file: Option<PathBuf>,
+    max_clients: Option<usize>,
+    max_message_len: Option<usize>,
+    max_file_size: Option<u64>,
+    /// Nickname of each operator, and the password they give to `/oper`.
+    operators: HashMap<String, String>,
+    /// Name each WebSocket or IRC client logs in as, and its token.
+    gateway_tokens: HashMap<String, String>,
+    history: HistoryFile,
+    http: HttpFile,
+    webhooks: Vec<Webhook>,
+    federation: FederationFile,
+    shutdown: ShutdownFile,
+}
+
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct HistoryFile {
+    messages: Option<usize>,
+    max_age: Option<u64>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct HttpFile {
+    listen: Option<String>,
+    /// Name the messages are posted as, and its token.
+    tokens: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct ShutdownFile {
+    reason: Option<String>,
+    back_in: Option<u64>,
+    grace: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct FederationFile {
+    name: Option<String>,
+    listen: Option<String>,
+    links: Vec<String>,
+    key_file: Option<PathBuf>,
+}
+
+/// Settings read once at startup, changing them needs a restart.
+pub struct Settings {
+    pub listen: String,
+    pub websocket: Option<String>,
+    pub irc: Option<String>,
+    pub http: Option<String>,
+    pub metrics: Option<String>,
+    pub admin_socket: Option<PathBuf>,
+    pub key: [u8; 32],
+    pub federation: Option<LinkSettings>,
+    /// Where the history is saved when shutting down, and read from at startup.
+    pub history_file: Option<PathBuf>,
+    pub log_output: Output,
+    args: Args,
+    file: ConfigFile,
+}
+
+/// Settings that are applied again when the config is reloaded.
+pub struct Live {
+    pub log_level: LogLevel,
+    /// Whether message bodies are logged too, off by default for privacy.
+    pub log_message_bodies: bool,
+    /// Sent to clients when they connect.
+    pub motd: Option<String>,
+    pub bans: Bans,
+    /// Where bans made through the admin socket are kept.
+    pub ban_file: Option<PathBuf>,
+    pub max_clients: Option<usize>,
+    /// Longest message body, in bytes, at most [`MAX_MESSAGE_LEN`].
+    pub max_message_len: usize,
+    /// Largest file that can be sent, in bytes. Zero turns transfers off.
+    pub max_file_size: u64,
+    /// Nicknames that may become operators with `/oper`, to use commands like
+    /// `/kick`, and their passwords.
+    pub operators: Tokens,
+    pub history: Retention,
+    /// Names that can use the HTTP API, and their tokens.
+    pub api_tokens: Tokens,
+    /// Nicknames that can log in through the WebSocket and IRC gateways, and
+    /// their tokens.
+    pub gateway_tokens: Tokens,
+    pub webhooks: Webhooks,
+    pub shutdown: Shutdown,
+}
+
+/// What clients are told when the server shuts down.
+pub struct Shutdown {
+    pub reason: Option<String>,
+    /// When the server should be back.
+    pub back_in: Option<Duration>,
+    /// How long clients get to be disconnected before the server exits anyway.
+    pub grace: Duration,
+}
+
+/// How this server links to others.
+pub struct LinkSettings {
+    /// Unique among the linked servers.
+    pub name: String,
+    /// Address to accept links on.
+    pub listen: Option<String>,
+    /// Addresses of the servers to link to.
+    pub links: Vec<String>,
+    /// Shared by the linked servers, and different from the one of clients.
+    pub key: [u8; 32],
+}
+
+/// How much of each room's recent messages is replayed to people joining it.
+#[derive(Clone, Copy)]
+pub struct Retention {
+    pub messages: usize,
+    pub max_age: Duration,
+}
+
+/// Secrets letting someone in under a given name, by name.
+#[derive(Default, Clone)]
+pub struct Tokens(HashMap<String, String>);
+
+impl Tokens {
+    pub fn new(tokens: &HashMap<String, String>) -> Self {
+        Tokens(
+            tokens
+                .iter()
+                .filter(|(_, token)| !token.is_empty())
+                .map(|(name, token)| (name.clone(), token.clone()))
+                .collect(),
+        )
+    }
+
+    /// Name `token` belongs to. Every token is compared in constant time, for
+    /// the time taken not to tell how close a guess was.
+    pub fn owner(&self, token: &str) -> Option<&str> {
+        let mut owner = None;
+        for (name, candidate) in &self.0 {
+            if bool::from(candidate.as_bytes().ct_eq(token.as_bytes())) {
+                owner = Some(name.as_str());
+            }
+        }
+        owner
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Banned nicknames and IP addresses, one per line in the ban file.
+#[derive(Default)]
+pub struct Bans(HashSet<String>);
+
+impl Bans {
+    fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read ban file {}: {e}", path.display()))?;
+        Ok(Bans(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_lowercase)
+                .collect(),
+        ))
+    }
+
+    /// Bans a nickname or an IP address, appending it to `file` if there is
+    /// one, or until the config is reloaded otherwise.
+    pub fn add(&mut self, target: &str, file: Option<&Path>) -> Result<(), String> {
+        let target = target.trim().to_lowercase();
+        if let Some(path) = file {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{target}"))
+                .map_err(|e| format!("Cannot write ban file {}: {e}", path.display()))?;
+        }
+        self.0.insert(target);
+        Ok(())
+    }
+
+    pub fn is_banned(&self, nick: &str, ip: &str) -> bool {
+        self.0.contains(&nick.to_lowercase()) || self.0.contains(ip)
+    }
+}
+
+impl Settings {
+    pub fn load() -> Result<(Self, Live), String> {
+        Self::from_args(Args::parse())
+    }
+
+    fn from_args(args: Args) -> Result<(Self, Live), String> {
+        let file = match &args.config {
+            Some(path) => read_config(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let key = read_key(&args, &file)?;
+        let federation = link_settings(&file.federation, &key)?;
+        let live = live(&args, &file)?;
+        let settings = Settings {
+            listen: args
+                .listen
+                .clone()
+                .or(file.listen.clone())
+                .unwrap_or_else(|| DEFAULT_LISTEN.into()),
+            websocket: args.websocket.clone().or(file.websocket.clone()),
+            irc: args.irc.clone().or(file.irc.clone()),
+            http: args.http.clone().or(file.http.listen.clone()),
+            metrics: args.metrics.clone().or(file.metrics.clone()),
+            admin_socket: args.admin_socket.clone().or(file.admin_socket.clone()),
+            key,
+            federation,
+            history_file: file.history.file.clone(),
+            log_output: Output {
+                format: args.log_format.or(file.log_format).unwrap_or_default(),
+                file: args.log_file.clone().or(file.log_file.clone()),
+                audit_file: file.audit_file.clone(),
+            },
+            args,
+            file,
+        };
+        Ok((settings, live))
+    }
+
+    /// Reads the config file again. Returns the new live settings, along with
+    /// warnings about changes that only apply after a restart.
+    pub fn reload(&self) -> Result<(Live, Vec<String>), String> {
+        let Some(path) = &self.args.config else {
+            return Err("No config file to reload.".into());
+        };
+        let file = read_config(path)?;
+        let mut warnings = Vec::new();
+        if file.listen != self.file.listen {
+            warnings.push("listen changed, restart the server to apply it.".to_string());
+        }
+        if file.websocket != self.file.websocket {
+            warnings.push("websocket changed, restart the server to apply it.".to_string());
+        }
+        if file.irc != self.file.irc {
+            warnings.push("irc changed, restart the server to apply it.".to_string());
+        }
+        if file.metrics != self.file.metrics {
+            warnings.push("metrics changed, restart the server to apply it.".to_string());
+        }
+        if file.admin_socket != self.file.admin_socket {
+            warnings.push("admin_socket changed, restart the server to apply it.".to_string());
+        }
+        if file.http.listen != self.file.http.listen {
+            warnings.push("http.listen changed, restart the server to apply it.".to_string());
+        }
+        if (&file.log_format, &file.log_file, &file.audit_file)
+            != (
+                &self.file.log_format,
+                &self.file.log_file,
+                &self.file.audit_file,
+            )
+        {
+            warnings.push(
+                "log_format, log_file or audit_file changed, restart the server to apply it."
+                    .to_string(),
+            );
+        }
+        if file.history.file != self.file.history.file {
+            warnings.push("history.file changed, restart the server to apply it.".to_string());
+        }
+        if file.federation != self.file.federation {
+            warnings.push("federation changed, restart the server to apply it.".to_string());
+        }
+        if file.key_file != self.file.key_file {
+            warnings.push("key_file changed, restart the server to apply it.".to_string());
+        }
+        Ok((live(&self.args, &file)?, warnings))
+    }
+
+    /// Reads the clients' key again, for it to be changed without a restart.
+    /// It must come from a key file, as `SECRET` cannot change meanwhile.
+    pub fn rotated_key(&self) -> Result<[u8; 32], String> {
+        if self.args.key_file.is_none() && std::env::var("SECRET").is_ok() {
+            return Err("The key comes from SECRET, use a key file to rotate it.".into());
+        }
+        let key = read_key(&self.args, &self.file)?;
+        if self.federation.as_ref().is_some_and(|link| link.key == key) {
+            return Err("The federation key must differ from the clients' one.".into());
+        }
+        Ok(key)
+    }
+}
+
+fn live(args: &Args, file: &ConfigFile) -> Result<Live, String> {
+    let max_message_len = file.max_message_len.unwrap_or(MAX_MESSAGE_LEN);
+    if max_message_len > MAX_MESSAGE_LEN {
+        return Err(format!(
+            "max_message_len cannot exceed {MAX_MESSAGE_LEN}, the longest body a frame holds."
+        ));
+    }
+    let bans = match &file.ban_file {
+        Some(path) => Bans::load(path)?,
+        None => Bans::default(),
+    };
+    Ok(Live {
+        log_level: args.log_level.or(file.log_level).unwrap_or(LogLevel::Info),
+        log_message_bodies: file.log_message_bodies,
+        motd: file.motd.clone().filter(|motd| !motd.trim().is_empty()),
+        bans,
+        ban_file: file.ban_file.clone(),
+        max_clients: file.max_clients,
+        max_message_len,
+        max_file_size: file.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+        operators: Tokens::new(&file.operators),
+        history: Retention {
+            messages: file.history.messages.unwrap_or(0),
+            max_age: Duration::from_secs(file.history.max_age.unwrap_or(DEFAULT_HISTORY_AGE)),
+        },
+        api_tokens: Tokens::new(&file.http.tokens),
+        gateway_tokens: Tokens::new(&file.gateway_tokens),
+        webhooks: Webhooks::new(file.webhooks.clone()),
+        shutdown: Shutdown {
+            reason: file
+                .shutdown
+                .reason
+                .clone()
+                .filter(|reason| !reason.trim().is_empty()),
+            back_in: file.shutdown.back_in.map(Duration::from_secs),
+            grace: Duration::from_secs(file.shutdown.grace.unwrap_or(DEFAULT_SHUTDOWN_GRACE)),
+        },
+    })
+}
+
+/// `--key-file`, else `SECRET`, else the config's `key_file`.
+fn read_key(args: &Args, file: &ConfigFile) -> Result<[u8; 32], String> {
+    let key = match (&args.key_file, std::env::var("SECRET"), &file.key_file) {
+        (Some(path), _, _) => read_key_file(path)?,
+        (None, Ok(secret), _) => secret,
+        (None, Err(_), Some(path)) => read_key_file(path)?,
+        (None, Err(_), None) => {
+            return Err("SECRET must be set in the .env file, or use --key-file.".into());
+        }
+    };
+    key.as_bytes()
+        .try_into()
+        .map_err(|_| "The key must be exactly 32 bytes long.".to_string())
+}
+
+fn link_settings(file: &FederationFile, key: &[u8; 32]) -> Result<Option<LinkSettings>, String> {
+    let Some(name) = file.name.as_deref().map(str::trim) else {
+        if file.listen.is_some() || !file.links.is_empty() {
+            return Err("federation.name must be set to link servers.".into());
+        }
+        return Ok(None);
+    };
+    if name.is_empty() {
+        return Err("federation.name cannot be empty.".into());
+    }
+    let Some(path) = &file.key_file else {
+        return Err("federation.key_file must be set to link servers.".into());
+    };
+    let link_key: [u8; 32] = read_key_file(path)?
+        .as_bytes()
+        .try_into()
+        .map_err(|_| "The federation key must be exactly 32 bytes long.".to_string())?;
+    if &link_key == key {
+        return Err("The federation key must differ from the clients' one.".into());
+    }
+    Ok(Some(LinkSettings {
+        name: name.to_string(),
+        listen: file.listen.clone(),
+        links: file.links.clone(),
+        key: link_key,
+    }))
+}
+
+fn read_config(path: &Path) -> Result<ConfigFile, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read config {}: {e}", path.display()))?;
+    toml::from_str(&content).map_err(|e| format!("Config {}: {}", path.display(), e.message()))
+}
+
+fn read_key_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|key| key.trim().to_string())
+        .map_err(|e| format!("Cannot read key file {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    /// Settings read from a config file holding `config`, in a directory of
+    /// their own. Returns the path of the config file too.
+    fn settings(test: &str, config: &str) -> (Settings, Live, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("yarca-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("key");
+        fs::write(&key, KEY).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, config).unwrap();
+        let args = Args::parse_from([
+            "YARCA".as_ref(),
+            "--key-file".as_ref(),
+            key.as_os_str(),
+            "--config".as_ref(),
+            path.as_os_str(),
+        ]);
+        let (settings, live) = Settings::from_args(args).unwrap();
+        (settings, live, path)
+    }
+
+    #[test]
+    fn reloads_apply_live_settings() {
+        let (settings, live, path) = settings(
+            "reload-live",
+            "motd = \"Hello\"\n[operators]\nalice = \"secret\"\n",
+        );
+        assert_eq!(live.motd.as_deref(), Some("Hello"));
+        assert!(live.operators.contains("alice"));
+
+        fs::write(
+            &path,
+            "motd = \"Welcome\"\nmax_clients = 10\n[operators]\nbob = \"hunter2\"\n",
+        )
+        .unwrap();
+        let (live, warnings) = settings.reload().unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+        assert_eq!(live.motd.as_deref(), Some("Welcome"));
+        assert_eq!(live.max_clients, Some(10));
+        assert!(!live.operators.contains("alice"));
+        assert_eq!(live.operators.owner("hunter2"), Some("bob"));
+    }
+
+    #[test]
+    fn reloads_warn_about_settings_needing_a_restart() {
+        let (settings, _, path) = settings("reload-restart", "listen = \"127.0.0.1:8080\"\n");
+        fs::write(
+            &path,
+            "listen = \"127.0.0.1:9090\"\nirc = \"127.0.0.1:6667\"\n",
+        )
+        .unwrap();
+        let (_, warnings) = settings.reload().unwrap();
+        assert_eq!(
+            warnings,
+            [
+                "listen changed, restart the server to apply it.",
+                "irc changed, restart the server to apply it.",
+            ]
+        );
+        // Still the one read at startup.
+        assert_eq!(settings.listen, "127.0.0.1:8080");
+    }
+
+    #[test]
+    fn invalid_configs_are_not_reloaded() {
+        let (settings, _, path) = settings("reload-invalid", "");
+        fs::write(&path, "motd = 1\n").unwrap();
+        assert!(settings.reload().is_err());
+        fs::write(&path, "no_such_setting = true\n").unwrap();
+        assert!(settings.reload().is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(settings.reload().is_err());
+    }
+
+    #[test]
+    fn messages_fit_in_a_frame() {
+        let (settings, live, path) = settings("max-message-len", "");
+        assert_eq!(live.max_message_len, MAX_MESSAGE_LEN);
+        fs::write(&path, "max_message_len = 4096\n").unwrap();
+        assert_eq!(settings.reload().unwrap().0.max_message_len, 4096);
+        fs::write(
+            &path,
+            format!("max_message_len = {}\n", MAX_MESSAGE_LEN + 1),
+        )
+        .unwrap();
+        assert!(settings.reload().is_err());
+    }
+
+    #[test]
+    fn empty_tokens_let_nobody_in() {
+        let tokens = Tokens::new(&HashMap::from([
+            ("alice".to_string(), "secret".to_string()),
+            ("bob".to_string(), String::new()),
+        ]));
+        assert_eq!(tokens.owner("secret"), Some("alice"));
+        assert_eq!(tokens.owner(""), None);
+        assert_eq!(tokens.owner("secre"), None);
+        assert!(!tokens.contains("bob"));
+    }
+}
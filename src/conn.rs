@@ -0,0 +1,252 @@
+use std::{
+    io::{self, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, SyncSender, TrySendError},
+    },
+    time::Duration,
+};
+
+use yarca::protocol::{ServerFrame, encode_frame};
+
+use crate::metrics;
+
+/// Frames a client may have waiting before it is disconnected, for one that
+/// stops reading not to make the server's memory grow.
+pub const QUEUE_LEN: usize = 1024;
+
+/// Frames a client may have waiting for a file chunk to be queued too, so
+/// that files never fill the queue that chat needs.
+pub const CHUNK_QUEUE_LEN: usize = QUEUE_LEN / 4;
+
+/// How long writing to a client may block before it is considered gone.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Frames waiting to be written to a client by the thread owning its socket,
+/// so that a client that stops reading never holds up the dispatcher.
+#[derive(Debug)]
+pub struct Queue<T> {
+    /// `None` asks the writer to close the connection once the frames before
+    /// it are sent.
+    frames: SyncSender<Option<T>>,
+    ip: String,
+    /// Frames sent but not yet taken by the writer.
+    queued: AtomicUsize,
+    /// Shut down when the queue is full, which ends both of its halves.
+    stream: TcpStream,
+    overflowed: AtomicBool,
+}
+
+impl<T> Queue<T> {
+    /// Queue of the client on `stream`, of [`QUEUE_LEN`] frames at most. The
+    /// stream's writes time out after [`WRITE_TIMEOUT`].
+    fn new(frames: SyncSender<Option<T>>, stream: &TcpStream) -> io::Result<Self> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(Queue {
+            frames,
+            ip: stream.peer_addr()?.ip().to_string(),
+            queued: AtomicUsize::new(0),
+            stream: stream.try_clone()?,
+            overflowed: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns whether the frame was queued. A full queue disconnects the client.
+    fn push(&self, frame: T) -> bool {
+        match self.frames.try_send(Some(frame)) {
+            Ok(()) => {
+                self.queued.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if !self.overflowed.swap(true, Ordering::Relaxed) {
+                    warn!(
+                        "Client {} doesn't keep up with its frames, disconnecting it.",
+                        self.ip
+                    );
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                }
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    fn close(&self) {
+        if self.frames.try_send(None).is_err() {
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Connection of a client, whichever way it came in.
+#[derive(Debug)]
+pub enum Conn {
+    /// Native clients, frames are encrypted right away and written by
+    /// [`write_lines`].
+    Tcp(Queue<String>),
+    /// Browsers and IRC clients, frames are handed to the thread that turns
+    /// them into the gateway's protocol.
+    Gateway(Queue<ServerFrame>),
+    /// People on a linked server, whose frames the link carries for the
+    /// whole room, so nothing is sent to them one by one.
+    Remote { server: String },
+}
+
+impl Conn {
+    pub fn send(&self, frame: &ServerFrame, key: &[u8; 32]) {
+        self.push(frame, key);
+    }
+
+    /// Sends a file chunk, unless [`CHUNK_QUEUE_LEN`] frames are already
+    /// waiting. Returns whether it was queued, the transfer having to stop
+    /// otherwise.
+    pub fn send_chunk(&self, frame: &ServerFrame, key: &[u8; 32]) -> bool {
+        self.queued() < CHUNK_QUEUE_LEN && self.push(frame, key)
+    }
+
+    fn push(&self, frame: &ServerFrame, key: &[u8; 32]) -> bool {
+        let queued = match self {
+            Conn::Tcp(queue) => queue.push(encode_frame(frame, key)),
+            Conn::Gateway(queue) => queue.push(frame.clone()),
+            Conn::Remote { .. } => false,
+        };
+        if queued {
+            metrics::FRAMES_SENT.inc();
+        }
+        queued
+    }
+
+    /// Connection of a native client on `stream`, whose encrypted frames are
+    /// queued to `lines` for [`write_lines`].
+    pub fn tcp(lines: SyncSender<Option<String>>, stream: &TcpStream) -> io::Result<Self> {
+        Queue::new(lines, stream).map(Conn::Tcp)
+    }
+
+    /// Connection of a gateway client on `stream`, whose frames are queued to
+    /// `frames`.
+    pub fn gateway(
+        frames: SyncSender<Option<ServerFrame>>,
+        stream: &TcpStream,
+    ) -> io::Result<Self> {
+        Queue::new(frames, stream).map(Conn::Gateway)
+    }
+
+    /// Called by writers for every frame they take.
+    pub fn dequeued(&self) {
+        if let Conn::Tcp(Queue { queued, .. }) | Conn::Gateway(Queue { queued, .. }) = self {
+            let _ = queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        }
+    }
+
+    /// Frames waiting to be written, always none for people on linked servers.
+    pub fn queued(&self) -> usize {
+        match self {
+            Conn::Tcp(Queue { queued, .. }) | Conn::Gateway(Queue { queued, .. }) => {
+                queued.load(Ordering::Relaxed)
+            }
+            Conn::Remote { .. } => 0,
+        }
+    }
+
+    /// Disconnects the client once the frames sent before are written, its
+    /// handler then reporting it gone.
+    pub fn close(&self) {
+        match self {
+            Conn::Tcp(queue) => queue.close(),
+            Conn::Gateway(queue) => queue.close(),
+            Conn::Remote { .. } => {}
+        }
+    }
+
+    pub fn ip(&self) -> String {
+        match self {
+            Conn::Tcp(Queue { ip, .. }) | Conn::Gateway(Queue { ip, .. }) => ip.clone(),
+            Conn::Remote { .. } => String::new(),
+        }
+    }
+
+    /// Linked server the client is on, `None` for local clients.
+    pub fn server(&self) -> Option<&str> {
+        match self {
+            Conn::Remote { server } => Some(server),
+            _ => None,
+        }
+    }
+}
+
+/// Writes the lines queued for a native client to its `stream`, until the
+/// connection is closed or a write fails or times out.
+pub fn write_lines(lines: &Receiver<Option<String>>, mut stream: TcpStream, conn: &Conn) {
+    for line in lines {
+        conn.dequeued();
+        let Some(line) = line else {
+            break;
+        };
+        if let Err(e) = stream.write_all(line.as_bytes()) {
+            debug!("Error writing to client {}: {e}", conn.ip());
+            break;
+        }
+        metrics::BYTES_SENT.add(line.len() as u64);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+
+    const KEY: [u8; 32] = [0; 32];
+
+    /// Connection of a client that never reads, along with the queue's receiver.
+    fn stalled() -> (Conn, Receiver<Option<String>>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (lines, outgoing) = mpsc::sync_channel(QUEUE_LEN);
+        (Conn::tcp(lines, &stream).unwrap(), outgoing, client)
+    }
+
+    fn chunk(offset: u64) -> ServerFrame {
+        ServerFrame::FileChunk {
+            from: "alice".to_string(),
+            id: 1,
+            offset,
+            data: "00".to_string(),
+        }
+    }
+
+    #[test]
+    fn chunks_leave_room_for_chat() {
+        let (conn, outgoing, _client) = stalled();
+        for offset in 0..CHUNK_QUEUE_LEN as u64 {
+            assert!(conn.send_chunk(&chunk(offset), &KEY));
+        }
+        assert!(!conn.send_chunk(&chunk(CHUNK_QUEUE_LEN as u64), &KEY));
+        let text = "Still there.".to_string();
+        conn.send(&ServerFrame::System { text }, &KEY);
+        assert_eq!(conn.queued(), CHUNK_QUEUE_LEN + 1);
+
+        // The writer taking frames makes room for the next chunk.
+        for _ in 0..2 {
+            outgoing.recv().unwrap();
+            conn.dequeued();
+        }
+        assert!(conn.send_chunk(&chunk(CHUNK_QUEUE_LEN as u64), &KEY));
+    }
+
+    #[test]
+    fn full_queues_disconnect_the_client() {
+        let (conn, _outgoing, mut client) = stalled();
+        let text = "Hello.".to_string();
+        for _ in 0..=QUEUE_LEN {
+            conn.send(&ServerFrame::System { text: text.clone() }, &KEY);
+        }
+        assert_eq!(conn.queued(), QUEUE_LEN);
+        let mut buf = [0; 16];
+        assert_eq!(io::Read::read(&mut client, &mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+//! Admin socket, a local Unix socket taking the commands of `yarca-admin`: one
+//! JSON [`AdminRequest`] per connection, answered by one [`AdminReply`].
+
+use std::{io, path::Path, sync::mpsc::Sender};
+
+#[cfg(unix)]
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    sync::mpsc,
+    thread::spawn,
+};
+
+use yarca::admin::{AdminReply, AdminRequest};
+#[cfg(unix)]
+use yarca::protocol::MAX_FRAME_LEN;
+
+use crate::ServerMessage;
+
+pub fn error(text: impl Into<String>) -> AdminReply {
+    AdminReply::Error { error: text.into() }
+}
+
+pub fn done(text: impl Into<String>) -> AdminReply {
+    AdminReply::Done { text: text.into() }
+}
+
+/// Takes commands on the socket at `path`, in the background. The socket is
+/// only open to the server's user, and removed by [`remove`].
+#[cfg(unix)]
+pub fn listen(path: &Path, tx: Sender<ServerMessage>) -> io::Result<()> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another server uses the admin socket {}", path.display()),
+            ));
+        }
+        // Left over by a server that didn't stop cleanly.
+        fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    info!("Admin socket listening on {}", path.display());
+    spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    spawn(move || {
+                        if let Err(e) = handle(stream, &tx) {
+                            debug!("Admin connection ended: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Error accepting admin connection: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Binds the socket in a directory only the server's user can enter, then
+/// moves it to `path` once it is only open to that user, so that nobody can
+/// connect in between.
+#[cfg(unix)]
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket path", path.display()),
+        )
+    })?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let private = dir.join(name);
+    let bound = UnixListener::bind(&private).and_then(|listener| {
+        fs::set_permissions(&private, Permissions::from_mode(0o600))?;
+        fs::rename(&private, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private);
+    let _ = fs::remove_dir(&dir);
+    bound
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &Path, _tx: Sender<ServerMessage>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The admin socket needs Unix domain sockets.",
+    ))
+}
+
+#[cfg(unix)]
+fn handle(stream: UnixStream, tx: &Sender<ServerMessage>) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .take(MAX_FRAME_LEN as u64)
+        .read_line(&mut line)?;
+    let reply = match serde_json::from_str::<AdminRequest>(&line) {
+        Ok(request) => {
+            debug!(?request, "Admin request");
+            let (reply, answer) = mpsc::channel();
+            let message = match request {
+                AdminRequest::Reload => ServerMessage::Reload(Some(reply)),
+                request => ServerMessage::Admin(request, reply),
+            };
+            if tx.send(message).is_err() {
+                error("The server is shutting down.")
+            } else {
+                answer
+                    .recv()
+                    .unwrap_or_else(|_| error("The server is shutting down."))
+            }
+        }
+        Err(e) => error(format!("Invalid request: {e}")),
+    };
+    let mut line = serde_json::to_string(&reply).expect("Replies serialize.");
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())
+}
+
+/// Removes the socket when the server stops.
+pub fn remove(path: &Path) {
+    let _ = std::fs::remove_file(path);
+}
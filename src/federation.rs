@@ -0,0 +1,623 @@
+//! Links between servers, so that people on either see one chat. Linked servers
+//! form a tree: a link that would make a loop is dropped, and what comes from
+//! one link is passed on to the others. When a link drops, the people on the
+//! servers behind it leave, on both sides of the netsplit.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use yarca::{
+    backoff::Backoff,
+    protocol::{FrameError, FrameReader, ServerFrame, write_frame},
+};
+
+use crate::{
+    Client, Clients, ServerMessage, accept, broadcast_room, config::LinkSettings, conn::Conn, log,
+};
+
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Silence after which a link is considered gone.
+const LINK_TIMEOUT: Duration = Duration::from_secs(90);
+
+static NEXT_LINK: AtomicU64 = AtomicU64::new(1);
+
+/// Frames between servers, encrypted with the federation key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LinkFrame {
+    /// First frame, both ways.
+    Hello {
+        server: String,
+    },
+    /// Servers reachable through the sender.
+    Servers {
+        servers: Vec<String>,
+    },
+    /// Servers no longer reachable through the sender.
+    Split {
+        servers: Vec<String>,
+    },
+    /// Someone on `server` connected, or moved to `room`.
+    User {
+        server: String,
+        nick: String,
+        room: String,
+    },
+    Quit {
+        nick: String,
+    },
+    Message {
+        from: String,
+        room: String,
+        body: String,
+    },
+    Ping,
+}
+
+/// Link to another server, for the dispatcher to send frames on.
+#[derive(Debug)]
+pub struct Link {
+    /// `None` closes the link.
+    frames: Sender<Option<LinkFrame>>,
+    server: String,
+}
+
+impl Link {
+    fn send(&self, frame: &LinkFrame) {
+        let _ = self.frames.send(Some(frame.clone()));
+    }
+
+    fn close(&self) {
+        let _ = self.frames.send(None);
+    }
+}
+
+struct Peer {
+    link: Arc<Link>,
+    /// Servers behind the link, the linked one included.
+    servers: HashSet<String>,
+}
+
+/// Links as seen by the dispatcher. Without a federation name, there are none.
+#[derive(Default)]
+pub struct Federation {
+    name: String,
+    peers: HashMap<u64, Peer>,
+}
+
+impl Federation {
+    pub fn new(name: &str) -> Self {
+        Federation {
+            name: name.to_string(),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Sends `frame` to every link but `except`.
+    pub fn relay(&self, frame: &LinkFrame, except: Option<u64>) {
+        for (id, peer) in &self.peers {
+            if Some(*id) != except {
+                peer.link.send(frame);
+            }
+        }
+    }
+
+    /// Someone here connected, or moved to `room`.
+    pub fn user(&self, nick: &str, room: &str) {
+        let frame = LinkFrame::User {
+            server: self.name.clone(),
+            nick: nick.to_string(),
+            room: room.to_string(),
+        };
+        self.relay(&frame, None);
+    }
+
+    pub fn quit(&self, nick: &str) {
+        let frame = LinkFrame::Quit {
+            nick: nick.to_string(),
+        };
+        self.relay(&frame, None);
+    }
+
+    pub fn message(&self, from: &str, room: &str, body: &str) {
+        let frame = LinkFrame::Message {
+            from: from.to_string(),
+            room: room.to_string(),
+            body: body.to_string(),
+        };
+        self.relay(&frame, None);
+    }
+
+    /// Whether a message from `nick` may come through link `id`: it must not be
+    /// someone here or behind another link.
+    pub fn may_speak_as(&self, id: u64, nick: &str, clients: &Clients) -> bool {
+        let Some(peer) = self.peers.get(&id) else {
+            return false;
+        };
+        clients.get(nick).is_none_or(|client| {
+            client
+                .conn
+                .server()
+                .is_some_and(|s| peer.servers.contains(s))
+        })
+    }
+
+    /// A server linked: tells it about every server and person known here.
+    pub fn link_up(&mut self, id: u64, link: Arc<Link>, clients: &Clients) {
+        if link.server == self.name || self.reaches(&link.server, None) {
+            warn!("Link to {} would make a loop, dropping it.", link.server);
+            link.close();
+            return;
+        }
+        info!("Linked to {}.", link.server);
+        let mut servers = vec![self.name.clone()];
+        servers.extend(
+            self.peers
+                .values()
+                .flat_map(|peer| peer.servers.iter().cloned()),
+        );
+        link.send(&LinkFrame::Servers { servers });
+        for (nick, client) in clients {
+            link.send(&LinkFrame::User {
+                server: client.conn.server().unwrap_or(&self.name).to_string(),
+                nick: nick.clone(),
+                room: client.room.clone(),
+            });
+        }
+        let servers = vec![link.server.clone()];
+        self.relay(
+            &LinkFrame::Servers {
+                servers: servers.clone(),
+            },
+            None,
+        );
+        self.peers.insert(
+            id,
+            Peer {
+                link,
+                servers: servers.into_iter().collect(),
+            },
+        );
+    }
+
+    /// Handles a frame from link `id`, but messages. Returns whether the list
+    /// of people changed.
+    pub fn handle(
+        &mut self,
+        id: u64,
+        frame: LinkFrame,
+        clients: &mut Clients,
+        key: &[u8; 32],
+    ) -> bool {
+        let Some(peer) = self.peers.get(&id) else {
+            return false;
+        };
+        match frame {
+            LinkFrame::Servers { servers } => {
+                if let Some(known) = servers
+                    .iter()
+                    .find(|server| **server == self.name || self.reaches(server, Some(id)))
+                {
+                    warn!(
+                        "Link to {} would make a loop through {known}, dropping it.",
+                        peer.link.server
+                    );
+                    peer.link.close();
+                    return false;
+                }
+                self.relay(
+                    &LinkFrame::Servers {
+                        servers: servers.clone(),
+                    },
+                    Some(id),
+                );
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.servers.extend(servers);
+                }
+                false
+            }
+            LinkFrame::Split { servers } => {
+                let Some(peer) = self.peers.get_mut(&id) else {
+                    return false;
+                };
+                let gone: Vec<String> = servers
+                    .into_iter()
+                    .filter(|server| peer.servers.remove(server))
+                    .collect();
+                self.split(gone, Some(id), clients, key)
+            }
+            LinkFrame::User { server, nick, room } => {
+                if !peer.servers.contains(&server) || !crate::valid_room(&room) {
+                    return false;
+                }
+                if !crate::valid_nick(&nick) {
+                    warn!("{server} sent the invalid nickname {nick:?}, ignoring them.");
+                    return false;
+                }
+                let frame = LinkFrame::User {
+                    server: server.clone(),
+                    nick: nick.clone(),
+                    room: room.clone(),
+                };
+                match clients.get_mut(&nick) {
+                    Some(client) if client.conn.server() != Some(server.as_str()) => {
+                        warn!(
+                            "{nick} on {server} has the nickname of someone else, ignoring them."
+                        );
+                        return false;
+                    }
+                    Some(client) => {
+                        if client.room == room {
+                            return false;
+                        }
+                        let old_room = std::mem::replace(&mut client.room, room.clone());
+                        let left = ServerFrame::Left {
+                            nick: nick.clone(),
+                            room: old_room.clone(),
+                        };
+                        broadcast_room(clients, &old_room, None, &left, key);
+                    }
+                    None => {
+                        debug!("{nick} joined from {server}.");
+                        let conn = Arc::new(Conn::Remote { server });
+                        clients.insert(
+                            nick.clone(),
+                            Client {
+                                conn,
+                                room: room.clone(),
+                                connected: Utc::now(),
+                                operator: false,
+                            },
+                        );
+                    }
+                }
+                let joined = ServerFrame::Joined {
+                    nick,
+                    room: room.clone(),
+                };
+                broadcast_room(clients, &room, None, &joined, key);
+                self.relay(&frame, Some(id));
+                true
+            }
+            LinkFrame::Quit { nick } => {
+                if !self.may_speak_as(id, &nick, clients) {
+                    return false;
+                }
+                let Some(client) = clients.remove(&nick) else {
+                    return false;
+                };
+                debug!(
+                    "{nick} left from {}.",
+                    client.conn.server().unwrap_or_default()
+                );
+                let left = ServerFrame::Left {
+                    nick: nick.clone(),
+                    room: client.room.clone(),
+                };
+                broadcast_room(clients, &client.room, None, &left, key);
+                self.relay(&LinkFrame::Quit { nick }, Some(id));
+                true
+            }
+            LinkFrame::Hello { .. } | LinkFrame::Message { .. } | LinkFrame::Ping => false,
+        }
+    }
+
+    /// Link `id` dropped, and with it the servers behind it. Returns whether
+    /// the list of people changed.
+    pub fn link_down(&mut self, id: u64, clients: &mut Clients, key: &[u8; 32]) -> bool {
+        let Some(peer) = self.peers.remove(&id) else {
+            return false;
+        };
+        warn!("Link to {} dropped.", peer.link.server);
+        self.split(peer.servers.into_iter().collect(), None, clients, key)
+    }
+
+    /// Removes the people on `servers`, and tells the other links but `except`.
+    fn split(
+        &self,
+        mut servers: Vec<String>,
+        except: Option<u64>,
+        clients: &mut Clients,
+        key: &[u8; 32],
+    ) -> bool {
+        if servers.is_empty() {
+            return false;
+        }
+        servers.sort();
+        self.relay(
+            &LinkFrame::Split {
+                servers: servers.clone(),
+            },
+            except,
+        );
+        let gone: Vec<String> = clients
+            .iter()
+            .filter(|(_, client)| {
+                client
+                    .conn
+                    .server()
+                    .is_some_and(|server| servers.iter().any(|s| s == server))
+            })
+            .map(|(nick, _)| nick.clone())
+            .collect();
+        for nick in gone {
+            if let Some(client) = clients.remove(&nick) {
+                let left = ServerFrame::Left {
+                    nick,
+                    room: client.room.clone(),
+                };
+                broadcast_room(clients, &client.room, None, &left, key);
+            }
+        }
+        let text = format!("Netsplit, lost {}.", servers.join(", "));
+        info!("{text}");
+        for client in clients.values() {
+            client
+                .conn
+                .send(&ServerFrame::System { text: text.clone() }, key);
+        }
+        true
+    }
+
+    /// Whether `server` is behind one of the links but `except`.
+    fn reaches(&self, server: &str, except: Option<u64>) -> bool {
+        self.peers
+            .iter()
+            .any(|(id, peer)| Some(*id) != except && peer.servers.contains(server))
+    }
+}
+
+/// Accepts links and keeps the configured ones up, in the background.
+pub fn start(settings: &LinkSettings, tx: &Sender<ServerMessage>) -> io::Result<()> {
+    if let Some(addr) = &settings.listen {
+        let listener = TcpListener::bind(addr)?;
+        info!("Accepting links on {addr}");
+        let (name, key, tx) = (settings.name.clone(), settings.key, tx.clone());
+        spawn(move || {
+            for stream in accept(&listener) {
+                match stream {
+                    Ok(stream) => {
+                        let (name, tx) = (name.clone(), tx.clone());
+                        spawn(move || run(stream, &name, &key, &tx));
+                    }
+                    Err(e) => error!("Error accepting link: {e}"),
+                }
+            }
+        });
+    }
+    for addr in &settings.links {
+        let (addr, name, key, tx) = (
+            addr.clone(),
+            settings.name.clone(),
+            settings.key,
+            tx.clone(),
+        );
+        spawn(move || {
+            let mut backoff = Backoff::default();
+            loop {
+                match TcpStream::connect(&addr) {
+                    Ok(stream) => {
+                        // Links dropped right away, like loops, keep backing off.
+                        let started = Instant::now();
+                        if run(stream, &name, &key, &tx) && started.elapsed() > PING_INTERVAL {
+                            backoff.reset();
+                        }
+                    }
+                    Err(e) => debug!("Cannot link to {addr}: {e}"),
+                }
+                if let Some(delay) = backoff.next_delay() {
+                    sleep(delay);
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Runs a link until it drops. Returns whether the other server was let in.
+fn run(stream: TcpStream, name: &str, key: &[u8; 32], tx: &Sender<ServerMessage>) -> bool {
+    let addr = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let (Ok(mut writer), Ok(reading)) = (stream.try_clone(), stream.try_clone()) else {
+        warn!("Cannot set up the link with {addr}.");
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(LINK_TIMEOUT));
+    let mut reader = FrameReader::new(reading);
+
+    let hello = LinkFrame::Hello {
+        server: name.to_string(),
+    };
+    if let Err(e) = write_frame(&mut writer, &hello, key) {
+        warn!("Cannot link with {addr}: {e}");
+        return false;
+    }
+    let server = match reader.read_frame(key) {
+        Ok(Some(LinkFrame::Hello { server })) if !server.trim().is_empty() => server,
+        Err(FrameError::Decrypt) => {
+            warn!(
+                target: log::AUDIT,
+                event = "auth_failed",
+                via = "federation",
+                %addr,
+                "Link refused, wrong federation key"
+            );
+            return false;
+        }
+        Ok(_) | Err(_) => {
+            warn!("Link from {addr} refused: invalid handshake.");
+            return false;
+        }
+    };
+
+    let id = NEXT_LINK.fetch_add(1, Ordering::Relaxed);
+    let (frames, outgoing) = mpsc::channel();
+    let link = Arc::new(Link {
+        frames,
+        server: server.clone(),
+    });
+    let _ = tx.send(ServerMessage::LinkUp(id, link));
+
+    let key_copy = *key;
+    spawn(move || {
+        loop {
+            let frame = match outgoing.recv_timeout(PING_INTERVAL) {
+                Ok(Some(frame)) => frame,
+                Err(RecvTimeoutError::Timeout) => LinkFrame::Ping,
+                Ok(None) | Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if write_frame(&mut writer, &frame, &key_copy).is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    loop {
+        match reader.read_frame::<LinkFrame>(key) {
+            Ok(Some(frame)) => {
+                let _ = tx.send(ServerMessage::LinkFrame(id, frame));
+            }
+            Ok(None) => break,
+            Err(FrameError::Io(e)) => {
+                debug!("Link to {server}: {e}");
+                break;
+            }
+            Err(e) => {
+                warn!("{e} from {server}, dropping the link.");
+                break;
+            }
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = tx.send(ServerMessage::LinkDown(id));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+
+    const KEY: [u8; 32] = [0; 32];
+
+    fn link(server: &str) -> (Arc<Link>, Receiver<Option<LinkFrame>>) {
+        let (frames, rx) = mpsc::channel();
+        let link = Link {
+            frames,
+            server: server.to_string(),
+        };
+        (Arc::new(link), rx)
+    }
+
+    fn closed(rx: &Receiver<Option<LinkFrame>>) -> bool {
+        rx.try_iter().any(|frame| frame.is_none())
+    }
+
+    #[test]
+    fn links_to_itself_are_dropped() {
+        let mut federation = Federation::new("a");
+        let (link, rx) = link("a");
+        federation.link_up(1, link, &Clients::new());
+        assert!(closed(&rx));
+        assert!(federation.peers.is_empty());
+    }
+
+    #[test]
+    fn second_links_to_a_server_are_dropped() {
+        let mut federation = Federation::new("a");
+        let (first, first_rx) = link("b");
+        federation.link_up(1, first, &Clients::new());
+        let (second, second_rx) = link("b");
+        federation.link_up(2, second, &Clients::new());
+        assert!(closed(&second_rx));
+        assert!(!closed(&first_rx));
+        assert_eq!(federation.peers.len(), 1);
+    }
+
+    #[test]
+    fn servers_reachable_another_way_drop_the_link() {
+        let mut federation = Federation::new("a");
+        let mut clients = Clients::new();
+        let (b, b_rx) = link("b");
+        federation.link_up(1, b, &clients);
+        let (c, c_rx) = link("c");
+        federation.link_up(2, c, &clients);
+        let servers = LinkFrame::Servers {
+            servers: vec!["b".to_string()],
+        };
+        assert!(!federation.handle(2, servers, &mut clients, &KEY));
+        assert!(closed(&c_rx));
+        assert!(!closed(&b_rx));
+        assert!(!federation.peers[&2].servers.contains("b"));
+    }
+
+    #[test]
+    fn servers_naming_this_one_drop_the_link() {
+        let mut federation = Federation::new("a");
+        let mut clients = Clients::new();
+        let (b, b_rx) = link("b");
+        federation.link_up(1, b, &clients);
+        let servers = LinkFrame::Servers {
+            servers: vec!["c".to_string(), "a".to_string()],
+        };
+        federation.handle(1, servers, &mut clients, &KEY);
+        assert!(closed(&b_rx));
+        assert!(!federation.reaches("c", None));
+    }
+
+    #[test]
+    fn new_servers_are_passed_on() {
+        let mut federation = Federation::new("a");
+        let mut clients = Clients::new();
+        let (b, b_rx) = link("b");
+        federation.link_up(1, b, &clients);
+        let (c, c_rx) = link("c");
+        federation.link_up(2, c, &clients);
+        b_rx.try_iter().for_each(drop);
+        let servers = LinkFrame::Servers {
+            servers: vec!["d".to_string()],
+        };
+        federation.handle(2, servers, &mut clients, &KEY);
+        assert!(federation.reaches("d", None));
+        assert!(!federation.reaches("d", Some(2)));
+        assert!(!closed(&c_rx));
+        let relayed: Vec<_> = b_rx.try_iter().flatten().collect();
+        assert!(matches!(
+            relayed.as_slice(),
+            [LinkFrame::Servers { servers }] if servers == &["d"]
+        ));
+    }
+
+    #[test]
+    fn invalid_nicknames_are_ignored() {
+        let mut federation = Federation::new("a");
+        let mut clients = Clients::new();
+        let (b, _b_rx) = link("b");
+        federation.link_up(1, b, &clients);
+        for nick in ["bob", "ev il", "ev!l@host"] {
+            let user = LinkFrame::User {
+                server: "b".to_string(),
+                nick: nick.to_string(),
+                room: "general".to_string(),
+            };
+            federation.handle(1, user, &mut clients, &KEY);
+        }
+        assert_eq!(clients.keys().collect::<Vec<_>>(), ["bob"]);
+    }
+}
@@ -0,0 +1,269 @@
+//! HTTP API, for scripts that post or read messages without staying connected.
+//! Every request needs an `Authorization: Bearer <token>` header, with one of
+//! the tokens of the config's `[http]` section, its name being the sender:
+//!
+//! - `POST /rooms/<room>/messages` with `{"body": "..."}` posts to a room,
+//! - `POST /users/<nick>/messages` with `{"body": "..."}` posts to their room,
+//!   mentioning them,
+//! - `GET /rooms/<room>/messages` returns the room's recent history,
+//! - `GET /users` lists who is online, and in which room.
+
+use std::{
+    io::{self, Read},
+    sync::{
+        Arc,
+        mpsc::{self, Sender},
+    },
+    thread::spawn,
+};
+
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tiny_http::{Header, Method, Request, Response, Server};
+use yarca::protocol::MAX_PLAINTEXT_LEN;
+
+use crate::{ServerMessage, log, on_stop};
+
+/// What the dispatcher is asked to do.
+#[derive(Debug)]
+pub enum ApiRequest {
+    PostRoom { room: String, body: String },
+    PostUser { nick: String, body: String },
+    History { room: String },
+    Users,
+}
+
+/// Status code and JSON body.
+pub type ApiResponse = (u16, Value);
+
+#[derive(Deserialize)]
+struct Post {
+    body: String,
+}
+
+pub fn error(status: u16, text: impl Into<String>) -> ApiResponse {
+    (status, json!({ "error": text.into() }))
+}
+
+/// Serves the API on `addr`, in the background.
+pub fn listen(addr: &str, tx: Sender<ServerMessage>) -> io::Result<()> {
+    let server = Arc::new(Server::http(addr).map_err(io::Error::other)?);
+    info!("HTTP API listening on {addr}");
+    let waker = server.clone();
+    on_stop(move || waker.unblock());
+    spawn(move || {
+        for request in server.incoming_requests() {
+            let tx = tx.clone();
+            spawn(move || handle(request, &tx));
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut request: Request, tx: &Sender<ServerMessage>) {
+    let (status, body) = route(&mut request, tx);
+    debug!(method = %request.method(), url = request.url(), status, "HTTP request");
+    if status == 401 {
+        let ip = request.remote_addr().map(|addr| addr.ip().to_string());
+        warn!(target: log::AUDIT, event = "auth_failed", via = "http", ip, "Bad API token");
+    }
+    let content_type =
+        Header::from_bytes("Content-Type", "application/json").expect("The header is valid.");
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    let _ = request.respond(response);
+}
+
+fn route(request: &mut Request, tx: &Sender<ServerMessage>) -> ApiResponse {
+    let token = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let Some(token) = token else {
+        return error(401, "Missing bearer token.");
+    };
+
+    let path = request.url().split('?').next().unwrap_or_default();
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| segment.trim_start_matches('#').to_string())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let api_request = match (request.method(), segments.as_slice()) {
+        (Method::Post, ["rooms", room, "messages"]) => match read_post(request) {
+            Ok(post) => ApiRequest::PostRoom {
+                room: room.to_string(),
+                body: post.body,
+            },
+            Err(response) => return response,
+        },
+        (Method::Post, ["users", nick, "messages"]) => match read_post(request) {
+            Ok(post) => ApiRequest::PostUser {
+                nick: nick.to_string(),
+                body: post.body,
+            },
+            Err(response) => return response,
+        },
+        (Method::Get, ["rooms", room, "messages"]) => ApiRequest::History {
+            room: room.to_string(),
+        },
+        (Method::Get, ["users"]) => ApiRequest::Users,
+        (_, ["rooms", _, "messages"] | ["users", _, "messages"] | ["users"]) => {
+            return error(405, "Method not allowed.");
+        }
+        _ => return error(404, "Not found."),
+    };
+
+    let (reply, answer) = mpsc::channel();
+    if tx
+        .send(ServerMessage::Api(token, api_request, reply))
+        .is_err()
+    {
+        return error(503, "The server is shutting down.");
+    }
+    answer
+        .recv()
+        .unwrap_or_else(|_| error(503, "The server is shutting down."))
+}
+
+fn read_post(request: &mut Request) -> Result<Post, ApiResponse> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_PLAINTEXT_LEN as u64 + 1)
+        .read_to_string(&mut body)
+        .map_err(|e| error(400, format!("Cannot read the body: {e}")))?;
+    if body.len() > MAX_PLAINTEXT_LEN {
+        return Err(error(413, "The body is too long."));
+    }
+    serde_json::from_str(&body)
+        .map_err(|e| error(400, format!("Expected {{\"body\": \"...\"}}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::TcpStream,
+        sync::mpsc::{Receiver, channel},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::tests::free_addr;
+
+    /// Address of an API whose dispatcher answers every request it gets with
+    /// a 201, and the requests it got along with their token.
+    fn api() -> (String, Receiver<(String, ApiRequest)>) {
+        let addr = free_addr();
+        let (tx, rx) = channel();
+        listen(&addr, tx).unwrap();
+        let (forwarded, requests) = channel();
+        spawn(move || {
+            for message in rx {
+                if let ServerMessage::Api(token, request, reply) = message {
+                    let _ = reply.send((201, json!({ "id": 1 })));
+                    let _ = forwarded.send((token, request));
+                }
+            }
+        });
+        (addr, requests)
+    }
+
+    /// Status code of the response to `method` on `path`, with `headers`.
+    fn status(addr: &str, method: &str, path: &str, headers: &str, body: &str) -> u16 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        response
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_else(|| panic!("No status in {response:?}"))
+    }
+
+    const TOKEN: &str = "Authorization: Bearer secret\r\n";
+
+    #[test]
+    fn requests_need_a_token() {
+        let (addr, requests) = api();
+        let post = r#"{"body": "Build passed"}"#;
+        assert_eq!(
+            status(&addr, "POST", "/rooms/general/messages", "", post),
+            401
+        );
+        let basic = "Authorization: Basic c2VjcmV0\r\n";
+        assert_eq!(status(&addr, "GET", "/users", basic, ""), 401);
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn posts_reach_the_dispatcher() {
+        let (addr, requests) = api();
+        let post = r#"{"body": "Build passed"}"#;
+        assert_eq!(
+            status(&addr, "POST", "/rooms/general/messages", TOKEN, post),
+            201
+        );
+        assert!(matches!(
+            requests.recv_timeout(Duration::from_secs(5)),
+            Ok((token, ApiRequest::PostRoom { room, body }))
+                if token == "secret" && room == "general" && body == "Build passed"
+        ));
+
+        assert_eq!(
+            status(&addr, "POST", "/users/alice/messages", TOKEN, post),
+            201
+        );
+        assert!(matches!(
+            requests.recv_timeout(Duration::from_secs(5)),
+            Ok((_, ApiRequest::PostUser { nick, .. })) if nick == "alice"
+        ));
+        assert_eq!(
+            status(&addr, "GET", "/rooms/#general/messages", TOKEN, ""),
+            201
+        );
+        assert!(matches!(
+            requests.recv_timeout(Duration::from_secs(5)),
+            Ok((_, ApiRequest::History { room })) if room == "general"
+        ));
+    }
+
+    #[test]
+    fn unknown_requests_are_refused() {
+        let (addr, requests) = api();
+        assert_eq!(status(&addr, "GET", "/rooms", TOKEN, ""), 404);
+        assert_eq!(status(&addr, "DELETE", "/users", TOKEN, ""), 405);
+        assert_eq!(status(&addr, "POST", "/users", TOKEN, ""), 405);
+        let post = "Build passed";
+        assert_eq!(
+            status(&addr, "POST", "/rooms/general/messages", TOKEN, post),
+            400
+        );
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn long_bodies_are_refused() {
+        let (addr, requests) = api();
+        let body = "x".repeat(MAX_PLAINTEXT_LEN);
+        let post = format!(r#"{{"body": "{body}"}}"#);
+        assert_eq!(
+            status(&addr, "POST", "/rooms/general/messages", TOKEN, &post),
+            413
+        );
+        assert!(requests.try_recv().is_err());
+    }
+}
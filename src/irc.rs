@@ -0,0 +1,505 @@
+//! IRC gateway, for the IRC clients and bots people already use. Rooms are
+//! channels and the server password (`PASS`) is the gateway token of the
+//! nickname, never the key. YARCA users are in one room at a time, so joining
+//! a channel parts the previous one.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::spawn,
+};
+
+use yarca::protocol::{ClientFrame, DEFAULT_ROOM, MAX_FRAME_LEN, ServerFrame};
+
+use crate::{
+    NICK_RULES, ServerMessage, SharedTokens, accept,
+    conn::{Conn, QUEUE_LEN},
+    forward, log,
+    metrics::{self, Transport},
+    valid_nick,
+};
+
+const SERVER_NAME: &str = "yarca";
+
+/// Commands without a YARCA counterpart, silently ignored rather than
+/// forwarded as slash commands.
+const IGNORED: &[&str] = &["CAP", "MODE", "USERHOST", "ISON", "AWAY", "PONG", "WHOIS"];
+
+/// Parsed IRC line, the prefix dropped.
+struct Line {
+    command: String,
+    params: Vec<String>,
+}
+
+/// Which command a [`ServerFrame::Names`] answers.
+enum Request {
+    Names,
+    Who,
+}
+
+/// Shared by the reading and writing halves of a connection.
+#[derive(Default)]
+struct State {
+    room: Option<String>,
+    /// Requests waiting for a [`ServerFrame::Names`], in the order they were sent.
+    pending: VecDeque<Request>,
+}
+
+type Shared<T> = Arc<Mutex<T>>;
+
+/// Accepts IRC clients on `addr`, in the background.
+pub fn listen(addr: &str, tokens: SharedTokens, tx: Sender<ServerMessage>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("IRC gateway listening on {addr}");
+    spawn(move || {
+        for stream in accept(&listener) {
+            match stream {
+                Ok(stream) => {
+                    let (tx, tokens) = (tx.clone(), tokens.clone());
+                    spawn(move || {
+                        if let Err(e) = handle(stream, &tokens, &tx) {
+                            debug!("IRC connection ended: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Error accepting IRC connection: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(stream: TcpStream, tokens: &SharedTokens, tx: &Sender<ServerMessage>) -> io::Result<()> {
+    let ip = stream
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    debug!("New IRC connection {ip}");
+    let out = Arc::new(Mutex::new(stream.try_clone()?));
+    let mut reader = BufReader::new(stream);
+
+    let Some(nick) = register(&mut reader, &out, tokens)? else {
+        metrics::HANDSHAKE_FAILURES.inc(Transport::Irc);
+        return Ok(());
+    };
+    let version = env!("CARGO_PKG_VERSION");
+    send_all(
+        &out,
+        [
+            format!(":{SERVER_NAME} 001 {nick} :Welcome to YARCA, {nick}"),
+            format!(
+                ":{SERVER_NAME} 002 {nick} :Your host is {SERVER_NAME}, running version {version}"
+            ),
+            format!(":{SERVER_NAME} 004 {nick} {SERVER_NAME} {version} o o"),
+            format!(":{SERVER_NAME} 422 {nick} :MOTD is sent as a notice"),
+        ],
+    )?;
+
+    let (frames, outgoing) = mpsc::sync_channel(QUEUE_LEN);
+    let conn = Arc::new(Conn::gateway(frames, &out.lock().unwrap())?);
+    let state = Shared::<State>::default();
+    {
+        let (conn, out, state) = (conn.clone(), out.clone(), state.clone());
+        let (tx, nick) = (tx.clone(), nick.clone());
+        spawn(move || write_frames(&outgoing, &conn, &out, &state, &tx, &nick));
+    }
+    let _ = tx.send(ServerMessage::NewClient(nick.clone(), conn.clone()));
+
+    let result = read_commands(&mut reader, &out, &state, tx, &nick);
+    // Ends the writing half, which holds the connection and so its queue.
+    conn.close();
+    let _ = tx.send(ServerMessage::ClientDisconnected(nick, conn));
+    result
+}
+
+/// Waits for `PASS`, `NICK` and `USER`. Returns the nickname, or `None` if the
+/// client left or the password isn't the nickname's token.
+fn register(
+    reader: &mut impl BufRead,
+    out: &Shared<TcpStream>,
+    tokens: &SharedTokens,
+) -> io::Result<Option<String>> {
+    let mut password = None;
+    let mut nick = None;
+    let mut user = false;
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Ok(None);
+        };
+        let Some(Line { command, params }) = parse(&line) else {
+            continue;
+        };
+        let first = params.first().map(String::as_str);
+        match command.as_str() {
+            "CAP" if first == Some("LS") => send(out, format!(":{SERVER_NAME} CAP * LS :"))?,
+            "CAP" => {}
+            "PASS" => password = first.map(str::to_string),
+            "NICK" => match first {
+                Some(first) if valid_nick(first) => nick = Some(first.to_string()),
+                Some(first) => send(out, format!(":{SERVER_NAME} 432 * {first} :{NICK_RULES}"))?,
+                None => send(out, format!(":{SERVER_NAME} 431 * :No nickname given"))?,
+            },
+            "USER" => user = true,
+            "PING" => send(
+                out,
+                format!(":{SERVER_NAME} PONG {SERVER_NAME} :{}", first.unwrap_or("")),
+            )?,
+            "QUIT" => return Ok(None),
+            _ => send(
+                out,
+                format!(":{SERVER_NAME} 451 * :You have not registered"),
+            )?,
+        }
+        if let (Some(nick), true) = (&nick, user) {
+            let owner = password.as_deref().and_then(|password| {
+                let tokens = tokens.read().unwrap();
+                tokens.owner(password).map(String::from)
+            });
+            if owner.as_ref() != Some(nick) {
+                let ip = out
+                    .lock()
+                    .unwrap()
+                    .peer_addr()
+                    .map(|addr| addr.ip().to_string());
+                warn!(
+                    target: log::AUDIT,
+                    event = "auth_failed",
+                    via = "irc",
+                    %nick,
+                    ip = ip.ok(),
+                    "Wrong IRC password"
+                );
+                send_all(
+                    out,
+                    [
+                        format!(":{SERVER_NAME} 464 {nick} :Password incorrect"),
+                        "ERROR :Closing link, the password is the gateway token of your nickname"
+                            .to_string(),
+                    ],
+                )?;
+                return Ok(None);
+            }
+            return Ok(Some(nick.clone()));
+        }
+    }
+}
+
+fn read_commands(
+    reader: &mut impl BufRead,
+    out: &Shared<TcpStream>,
+    state: &Shared<State>,
+    tx: &Sender<ServerMessage>,
+    nick: &str,
+) -> io::Result<()> {
+    while let Some(line) = read_line(reader)? {
+        let Some(Line { command, params }) = parse(&line) else {
+            continue;
+        };
+        let first = params.first().map(String::as_str);
+        let room = state.lock().unwrap().room.clone().unwrap_or_default();
+        match command.as_str() {
+            "PING" => send(
+                out,
+                format!(":{SERVER_NAME} PONG {SERVER_NAME} :{}", first.unwrap_or("")),
+            )?,
+            "QUIT" => break,
+            // Only the last channel is kept, being in one room at a time.
+            "JOIN" => {
+                if let Some(channel) = first.and_then(|channels| channels.split(',').next_back())
+                    && channel != "0"
+                {
+                    let room = channel.trim_start_matches('#').to_string();
+                    forward(tx, nick, ClientFrame::Join { room });
+                }
+            }
+            "PART" => match first.map(|channel| channel.trim_start_matches('#')) {
+                Some(channel) if channel != room => send(
+                    out,
+                    format!(":{SERVER_NAME} 442 {nick} #{channel} :You're not on that channel"),
+                )?,
+                _ if room == DEFAULT_ROOM => send(
+                    out,
+                    format!(
+                        ":{SERVER_NAME} NOTICE {nick} :Everyone is in a room, join another one to leave #{room}"
+                    ),
+                )?,
+                _ => forward(
+                    tx,
+                    nick,
+                    ClientFrame::Join {
+                        room: DEFAULT_ROOM.to_string(),
+                    },
+                ),
+            },
+            "PRIVMSG" | "NOTICE" => {
+                let (Some(target), Some(text)) = (first, params.get(1)) else {
+                    continue;
+                };
+                if target.trim_start_matches('#') == room && target.starts_with('#') {
+                    let body = match text.strip_prefix("\u{1}ACTION ") {
+                        Some(action) => format!("* {nick} {}", action.trim_end_matches('\u{1}')),
+                        None => text.clone(),
+                    };
+                    forward(tx, nick, ClientFrame::Message { body, room: None });
+                } else if command == "NOTICE" {
+                    // Notices never get an error back.
+                } else if target.starts_with('#') {
+                    send(
+                        out,
+                        format!(
+                            ":{SERVER_NAME} 404 {nick} {target} :Join the channel to talk in it"
+                        ),
+                    )?;
+                } else {
+                    send(
+                        out,
+                        format!(
+                            ":{SERVER_NAME} 401 {nick} {target} :Private messages are not supported"
+                        ),
+                    )?;
+                }
+            }
+            "NAMES" | "WHO" => {
+                let request = match command.as_str() {
+                    "NAMES" => Request::Names,
+                    _ => Request::Who,
+                };
+                let room = first.map(|channel| channel.trim_start_matches('#').to_string());
+                request_names(state, tx, nick, request, room);
+            }
+            "TOPIC" => {
+                let args = params.get(1).cloned().unwrap_or_default();
+                let name = "topic".to_string();
+                forward(tx, nick, ClientFrame::Command { name, args });
+            }
+            "KICK" => {
+                let args = params.get(1..).unwrap_or_default().join(" ");
+                let name = "kick".to_string();
+                forward(tx, nick, ClientFrame::Command { name, args });
+            }
+            // `OPER <name> <password>`, the name being their nickname.
+            "OPER" => {
+                let args = params.get(1).cloned().unwrap_or_default();
+                let name = "oper".to_string();
+                forward(tx, nick, ClientFrame::Command { name, args });
+            }
+            "NICK" => send(
+                out,
+                format!(":{SERVER_NAME} NOTICE {nick} :Reconnect to change your nickname"),
+            )?,
+            command if IGNORED.contains(&command) => {}
+            // Anything else is a server or bot command, like `/roll 2d6`.
+            command => {
+                let name = command.to_lowercase();
+                let args = params.join(" ");
+                forward(tx, nick, ClientFrame::Command { name, args });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turns frames from the server into IRC lines, until the connection is closed.
+fn write_frames(
+    frames: &Receiver<Option<ServerFrame>>,
+    conn: &Conn,
+    out: &Shared<TcpStream>,
+    state: &Shared<State>,
+    tx: &Sender<ServerMessage>,
+    nick: &str,
+) {
+    let mask = |nick: &str| format!("{nick}!{nick}@{SERVER_NAME}");
+    for frame in frames {
+        conn.dequeued();
+        let Some(frame) = frame else {
+            let _ = out.lock().unwrap().shutdown(Shutdown::Both);
+            break;
+        };
+        let lines =
+            match frame {
+                ServerFrame::Welcome { room, .. } => {
+                    let old = state.lock().unwrap().room.replace(room.clone());
+                    if old.as_ref() == Some(&room) {
+                        continue;
+                    }
+                    request_names(state, tx, nick, Request::Names, Some(room.clone()));
+                    let part = old.map(|old| format!(":{} PART #{old}", mask(nick)));
+                    part.into_iter()
+                        .chain([format!(":{} JOIN #{room}", mask(nick))])
+                        .collect()
+                }
+                ServerFrame::Message {
+                    from, room, body, ..
+                } if from != nick => body
+                    .lines()
+                    .map(|line| format!(":{} PRIVMSG #{room} :{line}", mask(&from)))
+                    .collect(),
+                ServerFrame::System { text } => text
+                    .lines()
+                    .map(|line| format!(":{SERVER_NAME} NOTICE {nick} :{line}"))
+                    .collect(),
+                ServerFrame::Joined { nick: who, room } => {
+                    vec![format!(":{} JOIN #{room}", mask(&who))]
+                }
+                ServerFrame::Left { nick: who, room } => {
+                    vec![format!(":{} PART #{room}", mask(&who))]
+                }
+                ServerFrame::Names { room, nicks } => {
+                    match state.lock().unwrap().pending.pop_front() {
+                    Some(Request::Names) => vec![
+                        format!(":{SERVER_NAME} 353 {nick} = #{room} :{}", nicks.join(" ")),
+                        format!(":{SERVER_NAME} 366 {nick} #{room} :End of /NAMES list."),
+                    ],
+                    Some(Request::Who) => nicks
+                        .iter()
+                        .map(|who| {
+                            format!(":{SERVER_NAME} 352 {nick} #{room} {who} {SERVER_NAME} {SERVER_NAME} {who} H :0 {who}")
+                        })
+                        .chain([format!(":{SERVER_NAME} 315 {nick} #{room} :End of /WHO list.")])
+                        .collect(),
+                    None => continue,
+                }
+                }
+                ServerFrame::FileOffer { from, name, .. } => vec![format!(
+                    ":{SERVER_NAME} NOTICE {nick} :{from} offers {name}, only the terminal client can accept files."
+                )],
+                _ => continue,
+            };
+        if send_all(out, lines).is_err() {
+            break;
+        }
+    }
+}
+
+/// Asks the server who is in `room`, holding the state while doing so, so
+/// that answers come back in the order of `pending`.
+fn request_names(
+    state: &Shared<State>,
+    tx: &Sender<ServerMessage>,
+    nick: &str,
+    request: Request,
+    room: Option<String>,
+) {
+    let mut state = state.lock().unwrap();
+    state.pending.push_back(request);
+    forward(tx, nick, ClientFrame::Names { room });
+}
+
+fn parse(line: &str) -> Option<Line> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    if let Some(prefixed) = rest.strip_prefix(':') {
+        rest = prefixed.split_once(' ')?.1;
+    }
+    let (head, trailing) = match rest.split_once(" :") {
+        Some((head, trailing)) => (head, Some(trailing)),
+        None => (rest, None),
+    };
+    let mut words = head.split_whitespace();
+    let command = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(str::to_string).collect();
+    params.extend(trailing.map(str::to_string));
+    Some(Line { command, params })
+}
+
+/// Reads a line, `None` at the end of the stream. Lines longer than a frame
+/// are refused.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut buf = Vec::new();
+    reader
+        .by_ref()
+        .take(MAX_FRAME_LEN as u64)
+        .read_until(b'\n', &mut buf)?;
+    metrics::BYTES_RECEIVED.add(buf.len() as u64);
+    match buf.last() {
+        None => Ok(None),
+        Some(b'\n') => Ok(Some(String::from_utf8_lossy(&buf).into_owned())),
+        Some(_) if buf.len() == MAX_FRAME_LEN => {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"))
+        }
+        // The last line, without its line break.
+        Some(_) => Ok(Some(String::from_utf8_lossy(&buf).into_owned())),
+    }
+}
+
+fn send(out: &Shared<TcpStream>, line: String) -> io::Result<()> {
+    send_all(out, [line])
+}
+
+fn send_all(out: &Shared<TcpStream>, lines: impl IntoIterator<Item = String>) -> io::Result<()> {
+    let mut out = out.lock().unwrap();
+    for line in lines {
+        write!(out, "{line}\r\n")?;
+        metrics::BYTES_SENT.add(line.len() as u64 + 2);
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+    use super::*;
+    use crate::{config::Tokens, tests::free_addr};
+
+    #[test]
+    fn quitting_closes_the_connection() {
+        let addr = free_addr();
+        let tokens = Tokens::new(&HashMap::from([("alice".into(), "secret".into())]));
+        let (tx, rx) = mpsc::channel();
+        listen(&addr, Arc::new(RwLock::new(tokens)), tx).unwrap();
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"PASS secret\r\nNICK alice\r\nUSER alice 0 * :Alice\r\n")
+            .unwrap();
+        let Ok(ServerMessage::NewClient(nick, _)) = rx.recv_timeout(Duration::from_secs(5)) else {
+            panic!("alice didn't log in");
+        };
+        assert_eq!(nick, "alice");
+
+        stream.write_all(b"QUIT :bye\r\n").unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        assert!(received.contains(" 001 alice "), "{received}");
+        let disconnected = rx.recv_timeout(Duration::from_secs(5));
+        assert!(matches!(
+            disconnected,
+            Ok(ServerMessage::ClientDisconnected(..))
+        ));
+    }
+
+    #[test]
+    fn invalid_nicknames_are_refused() {
+        let addr = free_addr();
+        let tokens = Tokens::new(&HashMap::from([("alice".into(), "secret".into())]));
+        let (tx, rx) = mpsc::channel();
+        listen(&addr, Arc::new(RwLock::new(tokens)), tx).unwrap();
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(b"PASS secret\r\nNICK :al ice\r\nUSER alice 0 * :Alice\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with(":yarca 432 * al ice :"), "{line}");
+        assert!(rx.try_recv().is_err());
+
+        stream.write_all(b"NICK alice\r\n").unwrap();
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Ok(ServerMessage::NewClient(nick, _)) if nick == "alice"
+        ));
+    }
+}
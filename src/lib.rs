@@ -0,0 +1,5 @@
+pub mod admin;
+pub mod backoff;
+pub mod bot;
+pub mod crypto;
+pub mod protocol;
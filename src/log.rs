@@ -0,0 +1,125 @@
+//! Server logs, through `tracing`: leveled, as text or JSON lines, to stderr or
+//! a file. Events with the `audit` target are security events, also appended to
+//! the audit log when there is one, whatever the level.
+
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing_subscriber::{
+    Layer, Registry,
+    filter::{LevelFilter, filter_fn},
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+};
+
+/// Target of the events that go to the audit log.
+pub const AUDIT: &str = "audit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, fields included.
+    Json,
+}
+
+/// Where logs go, set once at startup.
+pub struct Output {
+    pub format: LogFormat,
+    /// Stderr when `None`.
+    pub file: Option<PathBuf>,
+    pub audit_file: Option<PathBuf>,
+}
+
+type SetLevel = Box<dyn Fn(LevelFilter) + Send + Sync>;
+
+static SET_LEVEL: OnceLock<SetLevel> = OnceLock::new();
+static BODIES: AtomicBool = AtomicBool::new(false);
+
+pub fn init(output: &Output, level: LogLevel) -> Result<(), String> {
+    let writer = match &output.file {
+        Some(path) => BoxMakeWriter::new(Mutex::new(open(path)?)),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let layer = fmt::layer().with_ansi(false).with_writer(writer);
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match output.format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    let (level, handle) = reload::Layer::new(LevelFilter::from(level));
+
+    let audit = match &output.audit_file {
+        Some(path) => Some(
+            fmt::layer()
+                .json()
+                .with_writer(Mutex::new(open(path)?))
+                .with_filter(filter_fn(|metadata| metadata.target() == AUDIT)),
+        ),
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layer.with_filter(level))
+        .with(audit)
+        .try_init()
+        .map_err(|e| format!("Cannot set up logging: {e}"))?;
+    let _ = SET_LEVEL.set(Box::new(move |level| {
+        let _ = handle.reload(level);
+    }));
+    Ok(())
+}
+
+/// Logs are only ever appended to.
+fn open(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Cannot open log file {}: {e}", path.display()))
+}
+
+pub fn set_level(level: LogLevel) {
+    if let Some(set_level) = SET_LEVEL.get() {
+        set_level(level.into());
+    }
+}
+
+/// Whether message bodies may be logged, which they are not by default.
+pub fn bodies() -> bool {
+    BODIES.load(Ordering::Relaxed)
+}
+
+pub fn set_bodies(bodies: bool) {
+    BODIES.store(bodies, Ordering::Relaxed);
+}
@@ -1,215 +1,1360 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    collections::{BTreeSet, HashMap},
+    fmt,
+    io::{self, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, Sender},
+    },
     thread::spawn,
+    time::{Duration, Instant},
 };
 
-use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
-use hex::{decode, encode};
-use rand::{Rng, rng};
+use backlog::Backlog;
+use chrono::{DateTime, Utc};
+use commands::{BotCommands, ServerCommands};
+use config::{Live, Settings, Tokens};
+use conn::Conn;
+use federation::{Federation, Link, LinkFrame};
+use http::{ApiRequest, ApiResponse};
+use metrics::Transport;
+use transfers::Transfers;
+use webhooks::Webhooks;
+use yarca::{
+    admin::{AdminReply, AdminRequest, ClientInfo},
+    crypto::encrypt,
+    protocol::{
+        self, ClientFrame, CommandInfo, DEFAULT_ROOM, FrameError, FrameReader, MAX_MESSAGE_LEN,
+        PROTOCOL_VERSION, ServerFrame, valid_sha256,
+    },
+};
+
+#[macro_use]
+extern crate tracing;
+
+mod backlog;
+mod builtins;
+mod commands;
+mod config;
+mod conn;
+mod control;
+mod federation;
+mod http;
+mod irc;
+mod log;
+mod metrics;
+mod transfers;
+mod webhooks;
+mod websocket;
 
 #[derive(Debug)]
 enum ServerMessage {
-    NewClient(String, Arc<Mutex<TcpStream>>),
-    ClientDisconnected(String),
-    ChatMessage(String, String),
+    NewClient(String, Arc<Conn>),
+    /// Sent with the client's connection, so that a connection that was turned
+    /// down doesn't remove another client with the same nickname.
+    ClientDisconnected(String, Arc<Conn>),
+    /// Sender, body and the room named by a bot, if any.
+    ChatMessage(String, String, Option<String>),
+    JoinRoom(String, String),
+    /// Sender and the room to list, theirs if `None`.
+    Names(String, Option<String>),
+    RegisterCommands(String, Vec<CommandInfo>),
+    /// Sender, command name and arguments.
+    Command(String, String, String),
+    /// Sender and one of the file transfer frames.
+    File(String, ClientFrame),
+    /// Token, request and where to send the response, from the HTTP API.
+    Api(String, ApiRequest, Sender<ApiResponse>),
+    /// A server linked, with the ID of the link.
+    LinkUp(u64, Arc<Link>),
+    LinkFrame(u64, LinkFrame),
+    LinkDown(u64),
+    /// Request from the admin socket, and where to send the reply.
+    Admin(AdminRequest, Sender<AdminReply>),
+    /// Reads the config file again, replying to the admin socket if it asked.
+    Reload(Option<Sender<AdminReply>>),
+    /// Disconnects everyone and exits.
+    Shutdown,
+}
+
+struct Client {
+    conn: Arc<Conn>,
+    room: String,
+    connected: DateTime<Utc>,
+    /// Whether they gave their operator password to `/oper`.
+    operator: bool,
+}
+
+type Clients = HashMap<String, Client>;
+
+/// Key of the clients, read by the listeners for every new connection so that
+/// the admin socket can rotate it.
+type SharedKey = Arc<RwLock<[u8; 32]>>;
+
+/// Gateway tokens, read by the gateways for every login and changed when the
+/// config is reloaded.
+type SharedTokens = Arc<RwLock<Tokens>>;
+
+fn send(client: &Client, frame: &ServerFrame, key: &[u8; 32]) {
+    client.conn.send(frame, key);
+}
+
+fn broadcast_room(
+    clients: &Clients,
+    room: &str,
+    except: Option<&str>,
+    frame: &ServerFrame,
+    key: &[u8; 32],
+) {
+    for (name, client) in clients.iter() {
+        if client.room == room && except != Some(name.as_str()) {
+            send(client, frame, key);
+        }
+    }
+}
+
+/// Nicknames of the people in `room`, sorted.
+fn room_members(clients: &Clients, room: &str) -> Vec<String> {
+    let mut nicks: Vec<String> = clients
+        .iter()
+        .filter(|(_, client)| client.room == room)
+        .map(|(nick, _)| nick.clone())
+        .collect();
+    nicks.sort();
+    nicks
+}
+
+/// Sends everyone the list of online users, of rooms in use and of the commands
+/// they may use, so that clients can complete them.
+fn broadcast_presence(
+    clients: &Clients,
+    server_commands: &ServerCommands,
+    bot_commands: &BotCommands,
+    key: &[u8; 32],
+) {
+    let mut users: Vec<String> = clients.keys().cloned().collect();
+    users.sort();
+    let rooms: BTreeSet<String> = clients
+        .values()
+        .map(|client| client.room.clone())
+        .chain([DEFAULT_ROOM.to_string()])
+        .collect();
+
+    let users = ServerFrame::Users { users };
+    let rooms = ServerFrame::Rooms {
+        rooms: rooms.into_iter().collect(),
+    };
+    let available = [false, true].map(|operator| {
+        let mut list = server_commands.list(operator);
+        list.extend(bot_commands.list());
+        ServerFrame::Commands { commands: list }
+    });
+    for client in clients.values() {
+        send(client, &users, key);
+        send(client, &rooms, key);
+        send(client, &available[usize::from(client.operator)], key);
+    }
+}
+
+/// Stamps a message, sends it to `room` and its webhooks, and keeps it for the backlog.
+#[allow(clippy::too_many_arguments)]
+fn post_message(
+    clients: &Clients,
+    backlog: &mut Backlog,
+    webhooks: &Webhooks,
+    next_id: &mut u64,
+    from: String,
+    room: String,
+    body: String,
+    key: &[u8; 32],
+) -> u64 {
+    let id = *next_id;
+    *next_id += 1;
+    if log::bodies() {
+        debug!(id, %from, %room, body = body.trim(), "Broadcasting message");
+    } else {
+        debug!(id, %from, %room, "Broadcasting message");
+    }
+
+    let message = ServerFrame::Message {
+        id,
+        ts: Utc::now(),
+        from,
+        room: room.clone(),
+        body,
+    };
+    let started = Instant::now();
+    broadcast_room(clients, &room, None, &message, key);
+    metrics::BROADCAST_LATENCY.observe(started.elapsed());
+    metrics::MESSAGES.inc();
+    webhooks.notify(&message, clients);
+    backlog.push(&room, message);
+    id
+}
+
+fn send_topic(client: &Client, topics: &HashMap<String, String>, key: &[u8; 32]) {
+    if let Some(topic) = topics.get(&client.room) {
+        let text = format!("Topic of #{}: {topic}", client.room);
+        send(client, &ServerFrame::System { text }, key);
+    }
+}
+
+/// Set once shutting down, for the listeners to stop taking connections.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Wakes a listener waiting for a connection, for it to notice [`STOPPING`].
+type Waker = Box<dyn Fn() + Send>;
+
+static WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+
+/// Registers how to wake a listener when the server shuts down.
+fn on_stop(waker: impl Fn() + Send + 'static) {
+    WAKERS.lock().unwrap().push(Box::new(waker));
+}
+
+/// Connections to `listener`, until the server shuts down.
+fn accept(listener: &TcpListener) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+    if let Ok(mut addr) = listener.local_addr() {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        // Connecting is enough for `accept` to return.
+        on_stop(move || {
+            let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+        });
+    }
+    listener
+        .incoming()
+        .take_while(|_| !STOPPING.load(Ordering::SeqCst))
+}
+
+/// Stops every listener, closing their ports once they are woken up.
+fn stop_listening() {
+    STOPPING.store(true, Ordering::SeqCst);
+    for wake in WAKERS.lock().unwrap().iter() {
+        wake();
+    }
+}
+
+/// Sends SIGHUP to the dispatcher as a reload request, and SIGINT and SIGTERM
+/// as a shutdown request.
+#[cfg(unix)]
+fn watch_signals(tx: Sender<ServerMessage>) -> std::io::Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+    let mut signals = signal_hook::iterator::Signals::new([SIGHUP, SIGINT, SIGTERM])?;
+    spawn(move || {
+        for signal in signals.forever() {
+            let message = match signal {
+                SIGHUP => ServerMessage::Reload(None),
+                _ => ServerMessage::Shutdown,
+            };
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn watch_signals(_tx: Sender<ServerMessage>) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Stops taking connections, tells everyone the server is going away and
+/// disconnects them, saves the history, then exits once every client is gone
+/// or the grace period is over. Asking to shut down again exits right away.
+fn shut_down(
+    rx: &Receiver<ServerMessage>,
+    clients: &Mutex<Clients>,
+    backlog: &Backlog,
+    settings: &Settings,
+    live: &Live,
+    key: &[u8; 32],
+) -> ! {
+    // Sending the notice and saving the history count in the grace period too.
+    let deadline = Instant::now() + live.shutdown.grace;
+    stop_listening();
+    let text = shutdown_notice(&live.shutdown);
+    info!("{text}");
+    let notice = ServerFrame::System { text };
+    // Waited for without holding the lock, for the metrics to be served meanwhile.
+    let mut conns: HashMap<String, Arc<Conn>> = {
+        let mut clients = clients.lock().unwrap();
+        // People on linked servers leave with the links when the process exits.
+        clients.retain(|_, client| client.conn.server().is_none());
+        for client in clients.values() {
+            send(client, &notice, key);
+            client.conn.close();
+        }
+        clients
+            .iter()
+            .map(|(nick, client)| (nick.clone(), client.conn.clone()))
+            .collect()
+    };
+    if let Some(path) = &settings.history_file {
+        match backlog.save(path) {
+            Ok(()) => info!("History saved to {}", path.display()),
+            Err(e) => error!("{e}"),
+        }
+    }
+    if let Some(path) = &settings.admin_socket {
+        control::remove(path);
+    }
+
+    while !conns.is_empty() {
+        let wait = deadline.saturating_duration_since(Instant::now());
+        match rx.recv_timeout(wait) {
+            Ok(ServerMessage::ClientDisconnected(username, conn)) => {
+                if conns
+                    .get(&username)
+                    .is_some_and(|known| Arc::ptr_eq(known, &conn))
+                {
+                    conns.remove(&username);
+                    clients.lock().unwrap().remove(&username);
+                }
+            }
+            // Accepted before the listeners stopped.
+            Ok(ServerMessage::NewClient(_, conn)) => {
+                conn.send(&notice, key);
+                conn.close();
+            }
+            Ok(ServerMessage::Shutdown) => break,
+            Ok(_) => {}
+            Err(_) => {
+                warn!("{} clients still connected, exiting anyway.", conns.len());
+                break;
+            }
+        }
+    }
+    info!("Server stopped.");
+    std::process::exit(0)
 }
 
-fn encrypt(plaintext: &str, key: &[u8; 32]) -> (String, String) {
-    let mut rng = rng();
-    let nonce_bytes: [u8; 12] = rng.random();
-    let cipher = Aes256Gcm::new_from_slice(key).expect("Cipher failed.");
-    let nonce = Nonce::from_slice(&nonce_bytes);
+fn shutdown_notice(shutdown: &config::Shutdown) -> String {
+    let mut text = "Server shutting down".to_string();
+    if let Some(reason) = &shutdown.reason {
+        text.push_str(&format!(" ({reason})"));
+    }
+    if let Some(back_in) = shutdown.back_in {
+        text.push_str(&format!(", expected back in {}", duration_words(back_in)));
+    }
+    text.push('.');
+    text
+}
+
+/// `back_in` in the largest unit that fits, "5 minutes" rather than "300 seconds".
+fn duration_words(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (count, unit) = match secs {
+        0..60 => (secs, "second"),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural}")
+}
+
+/// Hands a frame from `username` over to the dispatcher.
+fn forward(tx: &Sender<ServerMessage>, username: &str, frame: ClientFrame) {
+    metrics::FRAMES_RECEIVED.inc();
+    let message = match frame {
+        ClientFrame::Message { body, room } => ServerMessage::ChatMessage(
+            username.to_string(),
+            body.trim().to_string(),
+            room.map(|room| room.trim_start_matches('#').to_string()),
+        ),
+        ClientFrame::Register { commands } => {
+            ServerMessage::RegisterCommands(username.to_string(), commands)
+        }
+        ClientFrame::Command { name, args } => ServerMessage::Command(
+            username.to_string(),
+            name.trim_start_matches('/').to_string(),
+            args.trim().to_string(),
+        ),
+        frame @ (ClientFrame::FileOffer { .. }
+        | ClientFrame::FileAnswer { .. }
+        | ClientFrame::FileChunk { .. }) => ServerMessage::File(username.to_string(), frame),
+        ClientFrame::Join { room } => ServerMessage::JoinRoom(
+            username.to_string(),
+            room.trim().trim_start_matches('#').to_string(),
+        ),
+        ClientFrame::Names { room } => ServerMessage::Names(
+            username.to_string(),
+            room.map(|room| room.trim().trim_start_matches('#').to_string()),
+        ),
+        ClientFrame::Hello { .. } => {
+            warn!("Unexpected handshake from {username}. Dropping frame.");
+            return;
+        }
+    };
+    let _ = tx.send(message);
+}
 
-    let cipher_text = cipher
-        .encrypt(nonce, plaintext.as_bytes())
-        .expect("Encryption failed.");
-    (encode(nonce_bytes), encode(&cipher_text))
+/// Why the first frame of a native client turns it down.
+enum Refusal {
+    Invalid,
+    /// A `hello` with a nickname [`valid_nick`] turns down.
+    Nick,
+    /// A `hello` in another [`PROTOCOL_VERSION`].
+    Version(u32),
+    /// A bare nickname, sent by clients from before JSON frames.
+    Legacy,
 }
 
-fn decrypt(nonce_hex: &str, ciphertext_hex: &str, key: &[u8; 32]) -> Option<String> {
-    let cipher = Aes256Gcm::new_from_slice(key).expect("Cipher failed.");
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::Invalid => f.write_str("Invalid handshake."),
+            Refusal::Nick => f.write_str(NICK_RULES),
+            Refusal::Version(version) => write!(
+                f,
+                "This server speaks protocol version {PROTOCOL_VERSION}, not {version}. Update your client."
+            ),
+            Refusal::Legacy => f.write_str(
+                "This server speaks a newer protocol than your client. Update your client.",
+            ),
+        }
+    }
+}
 
-    let nonce_bytes = decode(nonce_hex).ok()?;
-    let ciphertext_bytes = decode(ciphertext_hex).ok()?;
-    let nonce = Nonce::from_slice(&nonce_bytes);
+impl Refusal {
+    /// What to tell the client, in a frame it can read. Clients from before
+    /// JSON frames get the encrypted text alone, without a line break.
+    fn encode(&self, key: &[u8; 32]) -> Option<String> {
+        match self {
+            Refusal::Invalid => None,
+            Refusal::Nick | Refusal::Version(_) => {
+                let text = self.to_string();
+                Some(protocol::encode_frame(&ServerFrame::System { text }, key))
+            }
+            Refusal::Legacy => {
+                let (nonce, ciphertext) = encrypt(&self.to_string(), key);
+                Some(format!("{nonce}:{ciphertext}"))
+            }
+        }
+    }
+}
 
-    match cipher.decrypt(nonce, ciphertext_bytes.as_slice()) {
-        Ok(plaintext_bytes) => String::from_utf8(plaintext_bytes).ok(),
-        Err(_) => None,
+/// Nickname given by the decrypted first frame of a native client.
+fn hello(text: &str) -> Result<String, Refusal> {
+    match serde_json::from_str(text) {
+        Ok(ClientFrame::Hello { version, .. }) if version != PROTOCOL_VERSION => {
+            Err(Refusal::Version(version))
+        }
+        Ok(ClientFrame::Hello { nick, .. }) if valid_nick(nick.trim()) => {
+            Ok(nick.trim().to_string())
+        }
+        Ok(ClientFrame::Hello { nick, .. }) if !nick.trim().is_empty() => Err(Refusal::Nick),
+        Ok(_) => Err(Refusal::Invalid),
+        Err(_) if !text.trim().is_empty() && !text.trim_start().starts_with('{') => {
+            Err(Refusal::Legacy)
+        }
+        Err(_) => Err(Refusal::Invalid),
     }
 }
 
+const NICK_RULES: &str =
+    "Nicknames have up to 32 characters, without spaces, control characters, ':', '!' or '@'.";
+
+/// Whether `nick` can be someone's nickname, one that IRC clients and mentions
+/// can tell apart from what surrounds it.
+fn valid_nick(nick: &str) -> bool {
+    !nick.is_empty()
+        && nick.chars().count() <= 32
+        && !nick
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, ':' | '!' | '@'))
+}
+
+fn valid_room(room: &str) -> bool {
+    !room.is_empty()
+        && room.len() <= 32
+        && room
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
 fn main() -> Result<(), std::io::Error> {
     dotenvy::dotenv().ok();
 
-    let secret_key_string = std::env::var("SECRET").expect("SECRET must be set in the .env file");
-    let secret_key: [u8; 32] = match secret_key_string.as_bytes().try_into() {
-        Ok(key_bytes) => key_bytes,
-        Err(_) => panic!("SECRET must be exactly 32 bytes long."),
+    let (settings, mut live) = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
     };
+    if let Err(e) = log::init(&settings.log_output, live.log_level) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+    log::set_bodies(live.log_message_bodies);
+    let shared_key: SharedKey = Arc::new(RwLock::new(settings.key));
+    let shared_tokens: SharedTokens = Arc::new(RwLock::new(live.gateway_tokens.clone()));
+    let mut backlog = Backlog::new(live.history);
+    if let Some(path) = &settings.history_file
+        && let Err(e) = backlog.load(path)
+    {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 
-    let addr = std::env::var("ADDR").unwrap_or_else(|_| "0.0.0.0:8080".into());
+    let addr = settings.listen.clone();
     let listener = TcpListener::bind(&addr)?;
-    println!("Server listening on {}", &addr);
+    info!("Server listening on {}", &addr);
 
     let (tx_server, rx_server) = std::sync::mpsc::channel::<ServerMessage>();
-    let clients: Arc<Mutex<HashMap<String, Arc<Mutex<TcpStream>>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+    watch_signals(tx_server.clone())?;
+    if (settings.websocket.is_some() || settings.irc.is_some()) && live.gateway_tokens.is_empty() {
+        warn!("Gateways are on, but there are no gateway_tokens to log in with.");
+    }
+    if let Some(addr) = &settings.websocket {
+        websocket::listen(addr, shared_tokens.clone(), tx_server.clone())?;
+    }
+    if let Some(addr) = &settings.irc {
+        irc::listen(addr, shared_tokens.clone(), tx_server.clone())?;
+    }
+    if let Some(addr) = &settings.http {
+        http::listen(addr, tx_server.clone())?;
+    }
+    if let Some(path) = &settings.admin_socket {
+        control::listen(path, tx_server.clone())?;
+    }
+    let mut federation = Federation::default();
+    if let Some(link_settings) = &settings.federation {
+        federation::start(link_settings, &tx_server)?;
+        federation = Federation::new(&link_settings.name);
+    }
+    let clients: Arc<Mutex<Clients>> = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(addr) = &settings.metrics {
+        metrics::listen(addr, clients.clone())?;
+    }
 
     let clients_clone = clients.clone();
-    let secret_key_arc = Arc::new(secret_key);
-    let secret_key_arc_clone = secret_key_arc.clone();
+    let shared_key_clone = shared_key.clone();
 
-    spawn(move || {
-        for msg in rx_server {
+    let dispatcher = spawn(move || {
+        // The dispatcher's copy, changed along with the shared one.
+        let mut secret_key = settings.key;
+        let mut next_id: u64 = backlog.last_id() + 1;
+        let server_commands = builtins::builtins();
+        let mut bot_commands = BotCommands::default();
+        let mut topics: HashMap<String, String> = HashMap::new();
+        let mut transfers = Transfers::default();
+        for msg in rx_server.iter() {
+            let key = &secret_key;
+            let mut clients = clients_clone.lock().unwrap();
             match msg {
-                ServerMessage::NewClient(username, stream) => {
-                    println!("Client {username} connected.");
-                    clients_clone
-                        .lock()
-                        .unwrap()
-                        .insert(username.clone(), stream);
-
-                    let join_msg = format!("{username} has joined chat.");
-                    for (name, client_stream_mutex) in clients_clone.lock().unwrap().iter() {
-                        if name == &username {
-                            continue;
+                ServerMessage::NewClient(username, conn) => {
+                    let ip = conn.ip();
+                    let client = Client {
+                        conn,
+                        room: DEFAULT_ROOM.to_string(),
+                        connected: Utc::now(),
+                        operator: false,
+                    };
+                    let refusal = if live.bans.is_banned(&username, &ip) {
+                        Some("You are banned from this server.")
+                    } else if live.max_clients.is_some_and(|max| clients.len() >= max) {
+                        Some("The server is full, try again later.")
+                    } else if clients
+                        .get(&username)
+                        .is_some_and(|other| other.conn.server().is_some())
+                    {
+                        Some("Someone on a linked server has this nickname.")
+                    } else if clients.contains_key(&username) {
+                        Some("This nickname is taken.")
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = refusal {
+                        if live.bans.is_banned(&username, &ip) {
+                            warn!(
+                                target: log::AUDIT,
+                                event = "banned",
+                                nick = %username,
+                                %ip,
+                                "Refused a banned client"
+                            );
+                        } else {
+                            info!(nick = %username, %ip, reason, "Refused client");
                         }
-                        let (nonce, encrypted_msg) = encrypt(&join_msg, &secret_key_arc_clone);
-                        let message_to_send = format!("{nonce}:{encrypted_msg}");
-
-                        let mut client_stream = client_stream_mutex.lock().unwrap();
-
-                        let _ = client_stream.write_all(message_to_send.as_bytes());
+                        send(
+                            &client,
+                            &ServerFrame::System {
+                                text: reason.to_string(),
+                            },
+                            key,
+                        );
+                        client.conn.close();
+                        continue;
                     }
-                }
-                ServerMessage::ClientDisconnected(username) => {
-                    println!("Client {username} disconnected.");
-                    clients_clone.lock().unwrap().remove(&username);
-
-                    let disconnected_msg = format!("{username} has left chat.");
 
-                    for (_, client_stream_mutex) in clients_clone.lock().unwrap().iter() {
-                        let (nonce, encrypted_msg) =
-                            encrypt(&disconnected_msg, &secret_key_arc_clone);
-                        let message_to_send = format!("{nonce}:{encrypted_msg}");
+                    info!(nick = %username, %ip, "Client connected");
+                    send(
+                        &client,
+                        &ServerFrame::Welcome {
+                            nick: username.clone(),
+                            room: client.room.clone(),
+                        },
+                        key,
+                    );
+                    if let Some(motd) = &live.motd {
+                        send(&client, &ServerFrame::System { text: motd.clone() }, key);
+                    }
+                    send_topic(&client, &topics, key);
+                    for message in backlog.replay(&client.room) {
+                        send(&client, message, key);
+                    }
+                    clients.insert(username.clone(), client);
 
-                        let mut client_stream = client_stream_mutex.lock().unwrap();
+                    let join_msg = ServerFrame::Joined {
+                        nick: username.clone(),
+                        room: DEFAULT_ROOM.to_string(),
+                    };
+                    broadcast_room(&clients, DEFAULT_ROOM, Some(&username), &join_msg, key);
+                    live.webhooks.notify(&join_msg, &clients);
+                    federation.user(&username, DEFAULT_ROOM);
+                    broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                }
+                ServerMessage::ClientDisconnected(username, conn) => {
+                    if !clients
+                        .get(&username)
+                        .is_some_and(|client| Arc::ptr_eq(&client.conn, &conn))
+                    {
+                        continue;
+                    }
+                    info!(nick = %username, "Client disconnected");
+                    let Some(client) = clients.remove(&username) else {
+                        continue;
+                    };
+                    federation.quit(&username);
 
-                        let _ = client_stream.write_all(message_to_send.as_bytes());
+                    bot_commands.remove_bot(&username);
+                    for (from, id) in transfers.remove_client(&username) {
+                        if let Some(sender) = clients.get(&from) {
+                            let cancelled = ServerFrame::FileCancelled {
+                                nick: username.clone(),
+                                id,
+                                reason: format!("{username} went away."),
+                            };
+                            send(sender, &cancelled, key);
+                        }
                     }
+                    let disconnected_msg = ServerFrame::Left {
+                        nick: username,
+                        room: client.room.clone(),
+                    };
+                    broadcast_room(&clients, &client.room, None, &disconnected_msg, key);
+                    live.webhooks.notify(&disconnected_msg, &clients);
+                    broadcast_presence(&clients, &server_commands, &bot_commands, key);
                 }
-                ServerMessage::ChatMessage(sender, content) => {
-                    let full_message = format!("[{sender}]: {content}");
-                    println!("Broadcasting: {}", full_message.trim());
-
-                    for (_, client_stream_mutex) in clients_clone.lock().unwrap().iter() {
-                        let (nonce, encrypted_msg) = encrypt(&full_message, &secret_key_arc_clone);
-                        let message_to_send = format!("{nonce}:{encrypted_msg}");
+                ServerMessage::ChatMessage(sender, content, target) => {
+                    let Some(client) = clients.get(&sender) else {
+                        continue;
+                    };
+                    let max = live.max_message_len;
+                    if content.len() > max {
+                        send(
+                            client,
+                            &ServerFrame::System {
+                                text: format!("Message too long, the limit is {max} bytes."),
+                            },
+                            key,
+                        );
+                        continue;
+                    }
+                    let room = match target {
+                        Some(room) if room != client.room && !bot_commands.is_bot(&sender) => {
+                            send(
+                                client,
+                                &ServerFrame::System {
+                                    text: "Only bots can post to another room.".to_string(),
+                                },
+                                key,
+                            );
+                            continue;
+                        }
+                        Some(room) if !valid_room(&room) => continue,
+                        Some(room) => room,
+                        None => client.room.clone(),
+                    };
+                    federation.message(&sender, &room, &content);
+                    post_message(
+                        &clients,
+                        &mut backlog,
+                        &live.webhooks,
+                        &mut next_id,
+                        sender,
+                        room,
+                        content,
+                        key,
+                    );
+                }
+                ServerMessage::JoinRoom(username, room) => {
+                    let Some(client) = clients.get_mut(&username) else {
+                        continue;
+                    };
+                    if !valid_room(&room) {
+                        send(
+                            client,
+                            &ServerFrame::System {
+                                text: format!("Invalid room name \"{room}\"."),
+                            },
+                            key,
+                        );
+                        continue;
+                    }
+                    if client.room == room {
+                        continue;
+                    }
+                    let old_room = std::mem::replace(&mut client.room, room.clone());
+                    federation.user(&username, &room);
+                    send(
+                        client,
+                        &ServerFrame::Welcome {
+                            nick: username.clone(),
+                            room: room.clone(),
+                        },
+                        key,
+                    );
+                    send_topic(client, &topics, key);
+                    for message in backlog.replay(&room) {
+                        send(client, message, key);
+                    }
 
-                        let mut client_stream = client_stream_mutex.lock().unwrap();
+                    let left = ServerFrame::Left {
+                        nick: username.clone(),
+                        room: old_room.clone(),
+                    };
+                    broadcast_room(&clients, &old_room, None, &left, key);
+                    live.webhooks.notify(&left, &clients);
+                    let joined = ServerFrame::Joined {
+                        nick: username.clone(),
+                        room: room.clone(),
+                    };
+                    broadcast_room(&clients, &room, Some(&username), &joined, key);
+                    live.webhooks.notify(&joined, &clients);
+                    broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                }
+                ServerMessage::Names(sender, room) => {
+                    let Some(client) = clients.get(&sender) else {
+                        continue;
+                    };
+                    let room = room.unwrap_or_else(|| client.room.clone());
+                    let nicks = room_members(&clients, &room);
+                    send(client, &ServerFrame::Names { room, nicks }, key);
+                }
+                ServerMessage::RegisterCommands(bot, commands) => {
+                    let Some(client) = clients.get(&bot) else {
+                        continue;
+                    };
+                    let names: Vec<String> = commands.iter().map(|c| c.name.clone()).collect();
+                    match bot_commands.register(&bot, commands, &server_commands) {
+                        Ok(()) => {
+                            info!("{bot} registered /{}", names.join(", /"));
+                            broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                        }
+                        Err(e) => send(client, &ServerFrame::System { text: e }, key),
+                    }
+                }
+                ServerMessage::Command(sender, name, args) => {
+                    let Some(client) = clients.get(&sender) else {
+                        continue;
+                    };
+                    if let Some(command) = server_commands.get(&name) {
+                        debug!("Running /{name} for {sender}");
+                        let operator = client.operator;
+                        let mut ctx = commands::Context {
+                            clients: &mut clients,
+                            topics: &mut topics,
+                            operators: &live.operators,
+                            sender: &sender,
+                            key,
+                        };
+                        if let Err(e) = command.call(&mut ctx, &args, operator) {
+                            ctx.reply(e);
+                        }
+                        // `/oper` changes which commands they may use.
+                        if clients
+                            .get(&sender)
+                            .is_some_and(|client| client.operator != operator)
+                        {
+                            broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                        }
+                        continue;
+                    }
+                    let Some(bot) = bot_commands.owner(&name).and_then(|bot| clients.get(bot))
+                    else {
+                        send(
+                            client,
+                            &ServerFrame::System {
+                                text: format!("Unknown command /{name}."),
+                            },
+                            key,
+                        );
+                        continue;
+                    };
+                    debug!("Forwarding /{name} from {sender}");
+                    let command = ServerFrame::Command {
+                        from: sender,
+                        room: client.room.clone(),
+                        name,
+                        args,
+                    };
+                    send(bot, &command, key);
+                }
+                ServerMessage::File(sender, frame) => {
+                    let Some(client) = clients.get(&sender) else {
+                        continue;
+                    };
+                    match frame {
+                        ClientFrame::FileOffer {
+                            id,
+                            to,
+                            name,
+                            size,
+                            sha256,
+                        } => {
+                            let recipients = match to.strip_prefix('#') {
+                                Some(room) => clients
+                                    .iter()
+                                    .filter(|(nick, c)| c.room == room && **nick != sender)
+                                    .map(|(nick, _)| nick.clone())
+                                    .collect(),
+                                None if to != sender && clients.contains_key(&to) => {
+                                    vec![to.clone()]
+                                }
+                                None => Vec::new(),
+                            };
+                            let refusal = if !valid_sha256(&sha256) {
+                                Some("Invalid file checksum.".to_string())
+                            } else if live.max_file_size == 0 {
+                                Some("File transfers are disabled on this server.".to_string())
+                            } else if size > live.max_file_size {
+                                Some(format!(
+                                    "Files are limited to {} bytes.",
+                                    live.max_file_size
+                                ))
+                            } else if recipients.is_empty() {
+                                Some(format!("Nobody to send it to in {to}."))
+                            } else {
+                                None
+                            };
+                            if let Some(reason) = refusal {
+                                let cancelled = ServerFrame::FileCancelled {
+                                    nick: to,
+                                    id,
+                                    reason,
+                                };
+                                send(client, &cancelled, key);
+                                continue;
+                            }
 
-                        let _ = client_stream.write_all(message_to_send.as_bytes());
+                            info!("{sender} offers {name} ({size} bytes) to {to}");
+                            transfers.offer(&sender, id, size, &recipients);
+                            let offer = ServerFrame::FileOffer {
+                                from: sender.clone(),
+                                id,
+                                name,
+                                size,
+                                sha256,
+                            };
+                            for nick in &recipients {
+                                send(&clients[nick], &offer, key);
+                            }
+                        }
+                        ClientFrame::FileAnswer {
+                            from,
+                            id,
+                            accept,
+                            offset,
+                        } => {
+                            if let Err(e) = transfers.answer(&from, id, &sender, accept, offset) {
+                                send(client, &ServerFrame::System { text: e }, key);
+                                continue;
+                            }
+                            if let Some(offerer) = clients.get(&from) {
+                                let answer = ServerFrame::FileAnswer {
+                                    nick: sender,
+                                    id,
+                                    accept,
+                                    offset,
+                                };
+                                send(offerer, &answer, key);
+                            }
+                        }
+                        ClientFrame::FileChunk {
+                            id,
+                            to,
+                            offset,
+                            data,
+                        } => {
+                            let len = data.len() as u64 / 2;
+                            let checked = match clients.get(&to) {
+                                Some(recipient) => transfers
+                                    .chunk(&sender, id, &to, offset, len)
+                                    .map(|()| recipient),
+                                None => Err(format!("{to} went away.")),
+                            };
+                            let reason = match checked {
+                                Ok(recipient) => {
+                                    let chunk = ServerFrame::FileChunk {
+                                        from: sender.clone(),
+                                        id,
+                                        offset,
+                                        data,
+                                    };
+                                    if recipient.conn.send_chunk(&chunk, key) {
+                                        continue;
+                                    }
+                                    // Dropped, so the rest of the file can't follow.
+                                    transfers.cancel(&sender, id, &to);
+                                    let text = format!(
+                                        "{sender} stopped sending you a file, as you didn't keep up."
+                                    );
+                                    send(recipient, &ServerFrame::System { text }, key);
+                                    format!("{to} doesn't keep up, send it again to resume.")
+                                }
+                                Err(reason) => reason,
+                            };
+                            let cancelled = ServerFrame::FileCancelled {
+                                nick: to,
+                                id,
+                                reason,
+                            };
+                            send(client, &cancelled, key);
+                        }
+                        _ => {}
+                    }
+                }
+                ServerMessage::Api(token, request, reply) => {
+                    let Some(name) = live.api_tokens.owner(&token).map(String::from) else {
+                        let _ = reply.send(http::error(401, "Invalid token."));
+                        continue;
+                    };
+                    let max_len = live.max_message_len;
+                    let mut post = |room: String, body: String| {
+                        if body.len() > max_len {
+                            let text = format!("Message too long, the limit is {max_len} bytes.");
+                            return http::error(413, text);
+                        }
+                        federation.message(&name, &room, &body);
+                        let id = post_message(
+                            &clients,
+                            &mut backlog,
+                            &live.webhooks,
+                            &mut next_id,
+                            name.clone(),
+                            room,
+                            body,
+                            key,
+                        );
+                        (201, serde_json::json!({ "id": id }))
+                    };
+                    let response = match request {
+                        ApiRequest::PostRoom { room, .. } if !valid_room(&room) => {
+                            http::error(400, format!("Invalid room name \"{room}\"."))
+                        }
+                        ApiRequest::PostRoom { body, .. } | ApiRequest::PostUser { body, .. }
+                            if body.trim().is_empty() =>
+                        {
+                            http::error(400, "Empty message.")
+                        }
+                        ApiRequest::PostRoom { room, body } => {
+                            info!("{name} posted to #{room} over HTTP");
+                            post(room, body)
+                        }
+                        // Posted to their room, mentioning them for them to notice.
+                        ApiRequest::PostUser { nick, body } => match clients.get(&nick) {
+                            Some(client) => {
+                                info!("{name} wrote to {nick} over HTTP");
+                                post(client.room.clone(), format!("@{nick} {body}"))
+                            }
+                            None => http::error(404, format!("{nick} is not online.")),
+                        },
+                        ApiRequest::History { room } => {
+                            let messages: Vec<&ServerFrame> = backlog.replay(&room).collect();
+                            (200, serde_json::json!({ "messages": messages }))
+                        }
+                        ApiRequest::Users => {
+                            let mut users: Vec<(&String, &Client)> = clients.iter().collect();
+                            users.sort_by_key(|(nick, _)| *nick);
+                            let users: Vec<serde_json::Value> = users
+                                .into_iter()
+                                .map(|(nick, client)| {
+                                    serde_json::json!({ "nick": nick, "room": client.room })
+                                })
+                                .collect();
+                            (200, serde_json::json!({ "users": users }))
+                        }
+                    };
+                    let _ = reply.send(response);
+                }
+                ServerMessage::LinkUp(id, link) => federation.link_up(id, link, &clients),
+                ServerMessage::LinkFrame(id, LinkFrame::Message { from, room, body }) => {
+                    if !federation.may_speak_as(id, &from, &clients)
+                        || !valid_room(&room)
+                        || body.len() > MAX_MESSAGE_LEN
+                    {
+                        continue;
+                    }
+                    federation.relay(
+                        &LinkFrame::Message {
+                            from: from.clone(),
+                            room: room.clone(),
+                            body: body.clone(),
+                        },
+                        Some(id),
+                    );
+                    post_message(
+                        &clients,
+                        &mut backlog,
+                        &live.webhooks,
+                        &mut next_id,
+                        from,
+                        room,
+                        body,
+                        key,
+                    );
+                }
+                ServerMessage::LinkFrame(id, frame) => {
+                    if federation.handle(id, frame, &mut clients, key) {
+                        broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                    }
+                }
+                ServerMessage::LinkDown(id) => {
+                    if federation.link_down(id, &mut clients, key) {
+                        broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                    }
+                }
+                ServerMessage::Shutdown => {
+                    drop(clients);
+                    shut_down(&rx_server, &clients_clone, &backlog, &settings, &live, key)
+                }
+                ServerMessage::Admin(request, reply) => {
+                    let local = || {
+                        clients
+                            .values()
+                            .filter(|client| client.conn.server().is_none())
+                    };
+                    let response = match request {
+                        AdminRequest::Clients => {
+                            let mut list: Vec<ClientInfo> = clients
+                                .iter()
+                                .map(|(nick, client)| ClientInfo {
+                                    nick: nick.clone(),
+                                    room: client.room.clone(),
+                                    ip: client.conn.server().is_none().then(|| client.conn.ip()),
+                                    server: client.conn.server().map(String::from),
+                                    connected: client.connected,
+                                })
+                                .collect();
+                            list.sort_by(|a, b| a.nick.cmp(&b.nick));
+                            AdminReply::Clients { clients: list }
+                        }
+                        AdminRequest::Kick { nick, reason } => {
+                            let by = "the administrator";
+                            match builtins::kick_client(&clients, &nick, by, reason.as_deref(), key)
+                            {
+                                Ok(_) => control::done(format!("Kicked {nick}.")),
+                                Err(e) => control::error(e),
+                            }
+                        }
+                        AdminRequest::Ban { target } if target.trim().is_empty() => {
+                            control::error("Nothing to ban.")
+                        }
+                        AdminRequest::Ban { target } => {
+                            match live.bans.add(&target, live.ban_file.as_deref()) {
+                                Ok(()) => {
+                                    info!(target: log::AUDIT, event = "ban", %target, "Banned");
+                                    let notice = ServerFrame::System {
+                                        text: "You are banned from this server.".to_string(),
+                                    };
+                                    let mut banned: Vec<String> = Vec::new();
+                                    for (nick, client) in &*clients {
+                                        if client.conn.server().is_none()
+                                            && live.bans.is_banned(nick, &client.conn.ip())
+                                        {
+                                            send(client, &notice, key);
+                                            client.conn.close();
+                                            banned.push(nick.clone());
+                                        }
+                                    }
+                                    let mut text = format!("Banned {}", target.trim());
+                                    if live.ban_file.is_none() {
+                                        text.push_str(" until the config is reloaded");
+                                    }
+                                    if !banned.is_empty() {
+                                        banned.sort();
+                                        text.push_str(&format!(
+                                            ", disconnected {}",
+                                            banned.join(", ")
+                                        ));
+                                    }
+                                    text.push('.');
+                                    control::done(text)
+                                }
+                                Err(e) => control::error(e),
+                            }
+                        }
+                        AdminRequest::Announce { text } if text.trim().is_empty() => {
+                            control::error("Nothing to announce.")
+                        }
+                        AdminRequest::Announce { text } => {
+                            info!("Announcement: {}", text.trim());
+                            let notice = ServerFrame::System {
+                                text: format!("Announcement: {}", text.trim()),
+                            };
+                            for client in local() {
+                                send(client, &notice, key);
+                            }
+                            control::done(format!("Announced to {} clients.", local().count()))
+                        }
+                        AdminRequest::Stats => control::done(metrics::render(&clients)),
+                        AdminRequest::RotateKey => match settings.rotated_key() {
+                            Ok(new_key) if new_key == secret_key => {
+                                control::error("The key file still holds the current key.")
+                            }
+                            Ok(new_key) => {
+                                *shared_key_clone.write().unwrap() = new_key;
+                                let notice = ServerFrame::System {
+                                    text: "The server key changed, reconnect with the new one."
+                                        .to_string(),
+                                };
+                                // Gateway clients never had the key, so they stay.
+                                let native: Vec<&Client> = local()
+                                    .filter(|client| matches!(*client.conn, Conn::Tcp(_)))
+                                    .collect();
+                                for client in &native {
+                                    send(client, &notice, key);
+                                    client.conn.close();
+                                }
+                                let disconnected = native.len();
+                                secret_key = new_key;
+                                warn!(
+                                    target: log::AUDIT,
+                                    event = "key_rotated",
+                                    disconnected,
+                                    "Rotated the clients' key"
+                                );
+                                control::done(format!(
+                                    "Key rotated, {disconnected} clients disconnected."
+                                ))
+                            }
+                            Err(e) => control::error(e),
+                        },
+                        // The admin socket sends it as `ServerMessage::Reload`, to share the
+                        // handling of SIGHUP.
+                        AdminRequest::Reload => unreachable!("Reloads are not admin messages."),
+                    };
+                    let _ = reply.send(response);
+                }
+                ServerMessage::Reload(reply) => {
+                    let result = match settings.reload() {
+                        Ok((new_live, warnings)) => {
+                            log::set_level(new_live.log_level);
+                            log::set_bodies(new_live.log_message_bodies);
+                            backlog.set_retention(new_live.history);
+                            *shared_tokens.write().unwrap() = new_live.gateway_tokens.clone();
+                            live = new_live;
+                            // Operators may have changed, and with them who sees which commands.
+                            for (nick, client) in clients.iter_mut() {
+                                client.operator &= live.operators.contains(nick);
+                            }
+                            broadcast_presence(&clients, &server_commands, &bot_commands, key);
+                            let mut text = "Configuration reloaded.".to_string();
+                            for warning in warnings {
+                                warn!("{warning}");
+                                text.push_str(&format!("\n{warning}"));
+                            }
+                            info!("Configuration reloaded.");
+                            control::done(text)
+                        }
+                        Err(e) => {
+                            let text =
+                                format!("Reload failed, keeping the current configuration: {e}");
+                            warn!("{text}");
+                            control::error(text)
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        let _ = reply.send(result);
                     }
                 }
             }
         }
     });
 
-    for stream in listener.incoming() {
+    for stream in accept(&listener) {
         match stream {
             Ok(stream) => {
                 let tx_clone = tx_server.clone();
-                let secret_key_clone_for_handler = secret_key_arc.clone();
+                let secret_key = *shared_key.read().unwrap();
 
                 let client_ip = stream.peer_addr().unwrap().to_string();
-                println!("New connection {client_ip}");
+                debug!("New connection {client_ip}");
 
                 spawn(move || {
-                    let mut stream_clone = stream.try_clone().expect("Failed to clone stream");
-                    let arc_stream = Arc::new(Mutex::new(stream));
-
-                    let mut buffer = [0; 1024];
-                    let username;
+                    let key = &secret_key;
+                    let stream_clone = stream.try_clone().expect("Failed to clone stream");
+                    let mut reader = FrameReader::new(metrics::Counted(stream_clone));
 
-                    match stream_clone.read(&mut buffer) {
-                        Ok(bytes_read) if bytes_read > 0 => {
-                            let msg = String::from_utf8_lossy(&buffer[..bytes_read]);
-                            let (nonce_hex, ciphertext_hex) =
-                                msg.trim().split_once(':').unwrap_or(("", ""));
-
-                            match decrypt(nonce_hex, ciphertext_hex, &secret_key_clone_for_handler)
-                            {
-                                Some(name) => {
-                                    username = name.trim().to_string();
-                                    let _ = tx_clone.send(ServerMessage::NewClient(
-                                        username.clone(),
-                                        arc_stream.clone(),
-                                    ));
-                                }
-                                None => {
-                                    eprintln!("Failed to decrypt username. Disconnecting client.");
-                                    return;
+                    let username = match reader.read_text(key) {
+                        Ok(Some(text)) => match hello(&text) {
+                            Ok(nick) => nick,
+                            Err(refusal) => {
+                                metrics::HANDSHAKE_FAILURES.inc(Transport::Tcp);
+                                warn!("Refused handshake from {client_ip}: {refusal}");
+                                if let Some(reply) = refusal.encode(key) {
+                                    let _ = (&stream).write_all(reply.as_bytes());
                                 }
+                                return;
                             }
+                        },
+                        Err(FrameError::Malformed | FrameError::TooLong) => {
+                            metrics::HANDSHAKE_FAILURES.inc(Transport::Tcp);
+                            warn!("Invalid handshake from {client_ip}. Disconnecting client.");
+                            return;
                         }
-                        Err(e) => {
-                            eprintln!("Error reading initial username from {client_ip} {e}");
+                        Err(FrameError::Decrypt) => {
+                            metrics::DECRYPT_FAILURES.inc();
+                            metrics::HANDSHAKE_FAILURES.inc(Transport::Tcp);
+                            warn!(
+                                target: log::AUDIT,
+                                event = "decrypt_failed",
+                                ip = %client_ip,
+                                "Failed to decrypt the handshake, disconnecting"
+                            );
+                            return;
+                        }
+                        Err(FrameError::Io(e)) => {
+                            metrics::HANDSHAKE_FAILURES.inc(Transport::Tcp);
+                            warn!("Error reading initial username from {client_ip} {e}");
                             return;
                         }
-                        _ => {
-                            eprintln!("Client {client_ip} disconnected before sending username.");
+                        Ok(None) => {
+                            metrics::HANDSHAKE_FAILURES.inc(Transport::Tcp);
+                            warn!("Client {client_ip} disconnected before sending username.");
+                            return;
+                        }
+                    };
+                    let (lines, outgoing) = std::sync::mpsc::sync_channel(conn::QUEUE_LEN);
+                    let conn = match Conn::tcp(lines, &stream) {
+                        Ok(conn) => Arc::new(conn),
+                        Err(e) => {
+                            warn!("Cannot set up the connection of {client_ip}: {e}");
                             return;
                         }
+                    };
+                    {
+                        let conn = conn.clone();
+                        spawn(move || conn::write_lines(&outgoing, stream, &conn));
                     }
+                    let _ = tx_clone.send(ServerMessage::NewClient(username.clone(), conn.clone()));
 
                     loop {
-                        match stream_clone.read(&mut buffer) {
-                            Ok(bytes_read) if bytes_read > 0 => {
-                                let message_content =
-                                    String::from_utf8_lossy(&buffer[..bytes_read]);
-
-                                let (nonce_hex, ciphertext_hex) =
-                                    message_content.trim().split_once(':').unwrap_or(("", ""));
-
-                                if let Some(decrypted_message) = decrypt(
-                                    nonce_hex,
-                                    ciphertext_hex,
-                                    &secret_key_clone_for_handler,
-                                ) {
-                                    let _ = tx_clone.send(ServerMessage::ChatMessage(
-                                        username.clone(),
-                                        decrypted_message.trim().to_string(),
-                                    ));
-                                } else {
-                                    eprintln!(
-                                        "Failed to decrypt message from {username}. Dropping message."
-                                    )
-                                }
-                            }
-                            Ok(_) => {
-                                println!("Client {username} disconnected.");
-                                let _ = tx_clone
-                                    .send(ServerMessage::ClientDisconnected(username.clone()));
+                        match reader.read_frame::<ClientFrame>(key) {
+                            Ok(Some(frame)) => forward(&tx_clone, &username, frame),
+                            Err(FrameError::Io(e)) => {
+                                warn!("Error reading from client {username}: {e}");
+                                let _ = tx_clone.send(ServerMessage::ClientDisconnected(
+                                    username.clone(),
+                                    conn.clone(),
+                                ));
                                 break;
                             }
+                            Err(FrameError::Decrypt) => {
+                                metrics::DECRYPT_FAILURES.inc();
+                                warn!(
+                                    target: log::AUDIT,
+                                    event = "decrypt_failed",
+                                    nick = %username,
+                                    ip = %client_ip,
+                                    "Failed to decrypt a frame, dropping it"
+                                );
+                            }
                             Err(e) => {
-                                eprintln!("Error reading from client {username}: {e}");
-                                let _ = tx_clone
-                                    .send(ServerMessage::ClientDisconnected(username.clone()));
+                                warn!("{e} from {username}. Dropping message.")
+                            }
+                            Ok(None) => {
+                                debug!("Client {username} disconnected.");
+                                let _ = tx_clone.send(ServerMessage::ClientDisconnected(
+                                    username.clone(),
+                                    conn.clone(),
+                                ));
                                 break;
                             }
                         }
                     }
+                    // Ends the writer, which holds the connection and so its queue.
+                    conn.close();
                 });
             }
             Err(e) => {
-                eprintln!("Error accepting connection: {e}");
+                error!("Error accepting connection: {e}");
             }
         }
     }
+    // Shutting down: the port closes while the dispatcher waits for the
+    // clients, and exits.
+    drop(listener);
+    let _ = dispatcher.join();
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Address of a free local port, for tests starting listeners.
+    pub fn free_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn hello_gives_the_trimmed_nickname() {
+        let text = format!(r#"{{"type":"hello","version":{PROTOCOL_VERSION},"nick":" alice "}}"#);
+        assert_eq!(hello(&text).ok().as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn hello_needs_the_protocol_version() {
+        let missing = r#"{"type":"hello","nick":"alice"}"#;
+        assert!(matches!(hello(missing), Err(Refusal::Version(0))));
+        let newer = r#"{"type":"hello","version":99,"nick":"alice"}"#;
+        assert!(matches!(hello(newer), Err(Refusal::Version(99))));
+    }
+
+    #[test]
+    fn bare_nicknames_are_old_clients() {
+        assert!(matches!(hello("alice"), Err(Refusal::Legacy)));
+        assert!(matches!(hello(r#"{"type":"join"}"#), Err(Refusal::Invalid)));
+        let empty = format!(r#"{{"type":"hello","version":{PROTOCOL_VERSION},"nick":" "}}"#);
+        assert!(matches!(hello(&empty), Err(Refusal::Invalid)));
+    }
+
+    #[test]
+    fn nicknames_stand_apart() {
+        for nick in ["alice", "Bob_2", "élodie", "李", &"a".repeat(32)] {
+            assert!(valid_nick(nick), "{nick}");
+        }
+        for nick in [
+            "", "al ice", "alice:", "a!b", "@alice", "al\u{7}", "al\tice",
+        ] {
+            assert!(!valid_nick(nick), "{nick:?}");
+        }
+        assert!(!valid_nick(&"a".repeat(33)));
+        let text = format!(r#"{{"type":"hello","version":{PROTOCOL_VERSION},"nick":"a b"}}"#);
+        assert!(matches!(hello(&text), Err(Refusal::Nick)));
+    }
+}
@@ -0,0 +1,260 @@
+//! Prometheus metrics, served in the text format on `GET /metrics`.
+
+use std::{
+    fmt::Write,
+    io::{self, Read},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+use tiny_http::{Header, Response, Server};
+
+use crate::Clients;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+pub struct Histogram {
+    /// Observations up to each bound, not cumulated yet.
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// How a client connected.
+#[derive(Clone, Copy)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+    Irc,
+}
+
+const TRANSPORTS: [&str; 3] = ["tcp", "websocket", "irc"];
+
+pub struct PerTransport([Counter; 3]);
+
+impl PerTransport {
+    const fn new() -> Self {
+        PerTransport([Counter::new(), Counter::new(), Counter::new()])
+    }
+
+    pub fn inc(&self, transport: Transport) {
+        self.0[transport as usize].inc();
+    }
+}
+
+pub static FRAMES_RECEIVED: Counter = Counter::new();
+pub static FRAMES_SENT: Counter = Counter::new();
+pub static BYTES_RECEIVED: Counter = Counter::new();
+pub static BYTES_SENT: Counter = Counter::new();
+pub static MESSAGES: Counter = Counter::new();
+pub static DECRYPT_FAILURES: Counter = Counter::new();
+pub static HANDSHAKE_FAILURES: PerTransport = PerTransport::new();
+pub static BROADCAST_LATENCY: Histogram = Histogram::new();
+
+/// Counts the bytes read from a client.
+pub struct Counted<R>(pub R);
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        BYTES_RECEIVED.add(read as u64);
+        Ok(read)
+    }
+}
+
+/// Serves the metrics on `addr`, in the background.
+pub fn listen(addr: &str, clients: Arc<Mutex<Clients>>) -> io::Result<()> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    info!("Metrics served on http://{addr}/metrics");
+    spawn(move || {
+        let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+            .expect("The header is valid.");
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                let text = render(&clients.lock().unwrap());
+                Response::from_string(text).with_header(content_type.clone())
+            } else {
+                Response::from_string("Not found.").with_status_code(404)
+            };
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+/// Every metric, in the Prometheus text format.
+pub fn render(clients: &Clients) -> String {
+    let mut out = String::new();
+    let (local, remote): (Vec<_>, Vec<_>) = clients
+        .iter()
+        .partition(|(_, client)| client.conn.server().is_none());
+    gauge(
+        &mut out,
+        "yarca_clients",
+        "Clients connected here.",
+        local.len(),
+    );
+    gauge(
+        &mut out,
+        "yarca_remote_clients",
+        "Clients connected to linked servers.",
+        remote.len(),
+    );
+
+    let counters = [
+        (
+            "yarca_frames_received_total",
+            "Frames received from clients.",
+            &FRAMES_RECEIVED,
+        ),
+        (
+            "yarca_frames_sent_total",
+            "Frames sent to clients.",
+            &FRAMES_SENT,
+        ),
+        (
+            "yarca_bytes_received_total",
+            "Bytes received from clients.",
+            &BYTES_RECEIVED,
+        ),
+        (
+            "yarca_bytes_sent_total",
+            "Bytes sent to clients.",
+            &BYTES_SENT,
+        ),
+        ("yarca_messages_total", "Chat messages posted.", &MESSAGES),
+        (
+            "yarca_decrypt_failures_total",
+            "Frames that could not be decrypted.",
+            &DECRYPT_FAILURES,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", counter.get());
+    }
+
+    let name = "yarca_handshake_failures_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Connections dropped before saying hello.\n# TYPE {name} counter"
+    );
+    for (transport, counter) in TRANSPORTS.iter().zip(&HANDSHAKE_FAILURES.0) {
+        let _ = writeln!(out, "{name}{{transport=\"{transport}\"}} {}", counter.get());
+    }
+
+    let name = "yarca_client_queue_depth";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Frames waiting to be written to a client.\n# TYPE {name} gauge"
+    );
+    for (nick, client) in &local {
+        let nick = nick.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = writeln!(out, "{name}{{nick=\"{nick}\"}} {}", client.conn.queued());
+    }
+
+    let name = "yarca_broadcast_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Time to send a message to everyone in its room.\n# TYPE {name} histogram"
+    );
+    let mut cumulated = 0;
+    for (bound, bucket) in BUCKETS.iter().zip(&BROADCAST_LATENCY.buckets) {
+        cumulated += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulated}");
+    }
+    let count = BROADCAST_LATENCY.count.load(Ordering::Relaxed);
+    let sum = BROADCAST_LATENCY.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+    out
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use chrono::Utc;
+    use yarca::protocol::ServerFrame;
+
+    use super::*;
+    use crate::{Client, conn::Conn};
+
+    #[test]
+    fn native_clients_report_their_queue() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (lines, _outgoing) = mpsc::sync_channel(8);
+        let conn = Conn::tcp(lines, &stream).unwrap();
+        for _ in 0..3 {
+            let text = "Hello.".to_string();
+            conn.send(&ServerFrame::System { text }, &[0; 32]);
+        }
+
+        let client = Client {
+            conn: Arc::new(conn),
+            room: "#general".to_string(),
+            connected: Utc::now(),
+            operator: false,
+        };
+        let clients = Clients::from([("alice".to_string(), client)]);
+        let text = render(&clients);
+        assert!(text.contains("yarca_client_queue_depth{nick=\"alice\"} 3\n"));
+    }
+}
@@ -0,0 +1,440 @@
+//! Wire format shared by the server and the clients.
+//!
+//! Every frame is a single line `nonce:ciphertext\n`, both hex encoded, where the
+//! plaintext is the JSON form of a [`ClientFrame`] or a [`ServerFrame`].
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::crypto::{decrypt, encrypt};
+
+/// Longest line accepted by [`FrameReader`], hex encoding included.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Longest JSON a frame carries: [`MAX_FRAME_LEN`] less the nonce, the `:`, the
+/// newline and the tag, halved by the hex encoding. WebSocket and HTTP bodies,
+/// which are not encrypted, are held to it too.
+pub const MAX_PLAINTEXT_LEN: usize = (MAX_FRAME_LEN - 2 * 12 - 2) / 2 - 16;
+
+/// Longest message body, in bytes, that always fits in a [`ServerFrame::Message`]:
+/// JSON may escape a byte into six, and the other fields take at most
+/// `MESSAGE_OVERHEAD`.
+pub const MAX_MESSAGE_LEN: usize = (MAX_PLAINTEXT_LEN - MESSAGE_OVERHEAD) / 6;
+
+const MESSAGE_OVERHEAD: usize = 1024;
+
+pub const DEFAULT_ROOM: &str = "general";
+
+/// Version of the frames below, sent in [`ClientFrame::Hello`]. Servers turn
+/// down clients speaking another one.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Bytes of a file sent in each chunk, small enough to fit in a frame once
+/// hex encoded in the JSON and again once encrypted.
+pub const FILE_CHUNK_LEN: usize = 8 * 1024;
+
+/// Whether `sha256` is a hex encoded SHA-256, as file offers must carry: it
+/// names the partial download on the receiver's disk.
+pub fn valid_sha256(sha256: &str) -> bool {
+    sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Slash command handled by the server or by a bot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandInfo {
+    /// Name without the leading `/`.
+    pub name: String,
+    /// Arguments, like `<nick> [reason]`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub usage: String,
+    pub help: String,
+}
+
+/// Frames sent by clients to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// First frame of every connection.
+    Hello {
+        nick: String,
+        /// [`PROTOCOL_VERSION`] of the client, 0 for clients older than versions.
+        #[serde(default)]
+        version: u32,
+        /// Gateway token of the nickname, for WebSocket clients that cannot
+        /// send an `Authorization` header. Native clients have the key instead.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Chat message for the sender's current room. Bots may name another room,
+    /// to answer a command where it was used.
+    Message {
+        body: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+    },
+    Join {
+        room: String,
+    },
+    /// Asks who is in a room, the sender's one when `None`.
+    Names {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+    },
+    /// Slash commands the sender handles, replacing the ones it registered before.
+    Register {
+        commands: Vec<CommandInfo>,
+    },
+    /// Use of a slash command of the server or of a bot.
+    Command {
+        name: String,
+        args: String,
+    },
+    /// Proposes a file to a user, or to everyone in a room when `to` starts
+    /// with `#`. The ID is chosen by the sender, unique among its offers.
+    FileOffer {
+        id: u64,
+        to: String,
+        name: String,
+        size: u64,
+        /// Hex encoded SHA-256 of the whole file.
+        sha256: String,
+    },
+    /// Answer to a [`ServerFrame::FileOffer`]. Chunks start at `offset`, which
+    /// is more than zero when resuming an interrupted transfer.
+    FileAnswer {
+        from: String,
+        id: u64,
+        accept: bool,
+        offset: u64,
+    },
+    /// Part of an accepted file, for one recipient.
+    FileChunk {
+        id: u64,
+        to: String,
+        offset: u64,
+        /// Hex encoded, [`FILE_CHUNK_LEN`] bytes at most.
+        data: String,
+    },
+}
+
+/// Frames sent by the server to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Sent once the handshake is done, and whenever the client changes room.
+    Welcome {
+        nick: String,
+        room: String,
+    },
+    /// Chat message, stamped by the server. IDs increase with every message.
+    Message {
+        id: u64,
+        ts: DateTime<Utc>,
+        from: String,
+        room: String,
+        body: String,
+    },
+    System {
+        text: String,
+    },
+    /// Someone arrived in the receiver's room.
+    Joined {
+        nick: String,
+        room: String,
+    },
+    /// Someone left the receiver's room, or the server.
+    Left {
+        nick: String,
+        room: String,
+    },
+    /// Use of a slash command, sent to the bot that registered it.
+    Command {
+        from: String,
+        room: String,
+        name: String,
+        args: String,
+    },
+    /// Answer to [`ClientFrame::Names`], empty when nobody is in the room.
+    Names {
+        room: String,
+        nicks: Vec<String>,
+    },
+    /// Everyone currently online.
+    Users {
+        users: Vec<String>,
+    },
+    /// Rooms that currently have someone in them.
+    Rooms {
+        rooms: Vec<String>,
+    },
+    /// Slash commands the receiver may use, the server's and those of the
+    /// bots currently online.
+    Commands {
+        commands: Vec<CommandInfo>,
+    },
+    /// File proposed by `from`, to be answered with [`ClientFrame::FileAnswer`].
+    FileOffer {
+        from: String,
+        id: u64,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// Answer of `nick` to one of the receiver's offers.
+    FileAnswer {
+        nick: String,
+        id: u64,
+        accept: bool,
+        offset: u64,
+    },
+    FileChunk {
+        from: String,
+        id: u64,
+        offset: u64,
+        data: String,
+    },
+    /// One of the receiver's transfers to `nick` is over before the end: the
+    /// offer was refused by the server, or `nick` went away.
+    FileCancelled {
+        nick: String,
+        id: u64,
+        reason: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    Decrypt,
+    Malformed,
+    TooLong,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{e}"),
+            FrameError::Decrypt => f.write_str("Failed to decrypt frame"),
+            FrameError::Malformed => f.write_str("Malformed frame"),
+            FrameError::TooLong => f.write_str("Frame too long"),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Encrypts a frame into its wire form, trailing newline included.
+pub fn encode_frame<T: Serialize>(frame: &T, key: &[u8; 32]) -> String {
+    let json = serde_json::to_string(frame).expect("Frames always serialize.");
+    let (nonce, ciphertext) = encrypt(&json, key);
+    format!("{nonce}:{ciphertext}\n")
+}
+
+pub fn decode_frame<T: DeserializeOwned>(line: &str, key: &[u8; 32]) -> Result<T, FrameError> {
+    let json = decrypt_frame(line, key)?;
+    serde_json::from_str(&json).map_err(|_| FrameError::Malformed)
+}
+
+/// Decrypts a frame without parsing it, for what may not be JSON.
+pub fn decrypt_frame(line: &str, key: &[u8; 32]) -> Result<String, FrameError> {
+    let (nonce_hex, ciphertext_hex) = line.trim().split_once(':').ok_or(FrameError::Malformed)?;
+    decrypt(nonce_hex, ciphertext_hex, key).ok_or(FrameError::Decrypt)
+}
+
+pub fn write_frame<T: Serialize>(
+    writer: &mut impl Write,
+    frame: &T,
+    key: &[u8; 32],
+) -> io::Result<()> {
+    writer.write_all(encode_frame(frame, key).as_bytes())
+}
+
+/// Reads newline-delimited frames from a stream.
+pub struct FrameReader<R> {
+    reader: BufReader<R>,
+    line: String,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        FrameReader {
+            reader: BufReader::new(reader),
+            line: String::new(),
+        }
+    }
+
+    /// Returns the next frame, or `None` once the peer closed the connection.
+    /// Decryption and parsing errors only affect the current frame, so the
+    /// caller can keep reading after them.
+    pub fn read_frame<T: DeserializeOwned>(
+        &mut self,
+        key: &[u8; 32],
+    ) -> Result<Option<T>, FrameError> {
+        match self.read_text(key)? {
+            Some(json) => serde_json::from_str(&json).map_err(|_| FrameError::Malformed),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`FrameReader::read_frame`], but returns the decrypted text as is.
+    pub fn read_text(&mut self, key: &[u8; 32]) -> Result<Option<String>, FrameError> {
+        self.line.clear();
+        let read = (&mut self.reader)
+            .take(MAX_FRAME_LEN as u64)
+            .read_line(&mut self.line)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if !self.line.ends_with('\n') {
+            if read >= MAX_FRAME_LEN {
+                self.skip_line()?;
+                return Err(FrameError::TooLong);
+            }
+            return Ok(None);
+        }
+        decrypt_frame(&self.line, key).map(Some)
+    }
+
+    fn skip_line(&mut self) -> io::Result<()> {
+        loop {
+            let buffer = self.reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(());
+            }
+            if let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                self.reader.consume(pos + 1);
+                return Ok(());
+            }
+            let len = buffer.len();
+            self.reader.consume(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn frames_round_trip() {
+        let hello = ClientFrame::Hello {
+            nick: "alice".to_string(),
+            version: PROTOCOL_VERSION,
+            token: None,
+        };
+        let line = encode_frame(&hello, KEY);
+        assert!(line.ends_with('\n'));
+        match decode_frame(&line, KEY).unwrap() {
+            ClientFrame::Hello {
+                nick,
+                version,
+                token,
+            } => {
+                assert_eq!(nick, "alice");
+                assert_eq!(version, PROTOCOL_VERSION);
+                assert_eq!(token, None);
+            }
+            frame => panic!("Decoded {frame:?}"),
+        }
+
+        let message = ServerFrame::Message {
+            id: 7,
+            ts: Utc::now(),
+            from: "bob".to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            body: "Hi: there\nsecond line".to_string(),
+        };
+        let decoded: ServerFrame = decode_frame(&encode_frame(&message, KEY), KEY).unwrap();
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            serde_json::to_string(&message).unwrap()
+        );
+    }
+
+    #[test]
+    fn hello_without_version_is_version_zero() {
+        let hello = serde_json::from_str(r#"{"type":"hello","nick":"alice"}"#).unwrap();
+        assert!(matches!(hello, ClientFrame::Hello { version: 0, .. }));
+    }
+
+    #[test]
+    fn decoding_tells_errors_apart() {
+        let line = encode_frame(&ServerFrame::System { text: "hi".into() }, KEY);
+        let other_key = [0; 32];
+        assert!(matches!(
+            decode_frame::<ServerFrame>(&line, &other_key),
+            Err(FrameError::Decrypt)
+        ));
+        assert!(matches!(
+            decode_frame::<ServerFrame>("no separator", KEY),
+            Err(FrameError::Malformed)
+        ));
+        assert!(matches!(
+            decode_frame::<ClientFrame>(&line, KEY),
+            Err(FrameError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn longest_messages_fit_in_a_frame() {
+        let message = ServerFrame::Message {
+            id: u64::MAX,
+            ts: Utc::now(),
+            from: "\u{1}".repeat(32),
+            room: "r".repeat(32),
+            body: "\u{1}".repeat(MAX_MESSAGE_LEN),
+        };
+        let line = encode_frame(&message, KEY);
+        assert!(line.len() <= MAX_FRAME_LEN);
+        let mut reader = FrameReader::new(line.as_bytes());
+        assert!(matches!(
+            reader.read_frame(KEY),
+            Ok(Some(ServerFrame::Message { body, .. })) if body.len() == MAX_MESSAGE_LEN
+        ));
+
+        let plaintext = "x".repeat(MAX_PLAINTEXT_LEN - 2);
+        let line = encode_frame(&plaintext, KEY);
+        assert_eq!(line.len(), MAX_FRAME_LEN);
+        let mut reader = FrameReader::new(line.as_bytes());
+        assert!(matches!(reader.read_text(KEY), Ok(Some(json)) if json.len() == MAX_PLAINTEXT_LEN));
+    }
+
+    #[test]
+    fn reader_skips_frames_too_long() {
+        let mut input = "x".repeat(MAX_FRAME_LEN + 10);
+        input.push('\n');
+        input.push_str(&encode_frame(
+            &ServerFrame::System { text: "ok".into() },
+            KEY,
+        ));
+        let mut reader = FrameReader::new(input.as_bytes());
+        assert!(matches!(
+            reader.read_frame::<ServerFrame>(KEY),
+            Err(FrameError::TooLong)
+        ));
+        assert!(matches!(
+            reader.read_frame(KEY),
+            Ok(Some(ServerFrame::System { text })) if text == "ok"
+        ));
+        assert!(matches!(reader.read_frame::<ServerFrame>(KEY), Ok(None)));
+    }
+
+    #[test]
+    fn checksums_are_hex_sha256() {
+        assert!(valid_sha256(&"aB3f".repeat(16)));
+        assert!(!valid_sha256("../../etc/passwd"));
+        assert!(!valid_sha256(&"g".repeat(64)));
+        assert!(!valid_sha256(&"a".repeat(63)));
+    }
+}
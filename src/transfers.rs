@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+/// File being relayed from its sender to its recipients.
+struct Transfer {
+    size: u64,
+    /// `None` until the recipient accepts, then the offset of the next chunk.
+    recipients: HashMap<String, Option<u64>>,
+}
+
+/// File transfers in progress, by sender and ID. The server only checks that
+/// chunks come in order and stay within the announced size, the recipient's
+/// client checks the content.
+#[derive(Default)]
+pub struct Transfers {
+    transfers: HashMap<(String, u64), Transfer>,
+}
+
+impl Transfers {
+    /// Records an offer, replacing any other one from `from` with the same ID.
+    pub fn offer(&mut self, from: &str, id: u64, size: u64, recipients: &[String]) {
+        let transfer = Transfer {
+            size,
+            recipients: recipients.iter().map(|nick| (nick.clone(), None)).collect(),
+        };
+        self.transfers.insert((from.to_string(), id), transfer);
+    }
+
+    /// Records the answer of `nick` to the offer `id` of `from`.
+    pub fn answer(
+        &mut self,
+        from: &str,
+        id: u64,
+        nick: &str,
+        accept: bool,
+        offset: u64,
+    ) -> Result<(), String> {
+        let key = (from.to_string(), id);
+        let Some(transfer) = self.transfers.get_mut(&key) else {
+            return Err("This file is no longer offered.".into());
+        };
+        match transfer.recipients.get_mut(nick) {
+            Some(next @ None) if accept && offset <= transfer.size => *next = Some(offset),
+            Some(None) if accept => return Err("Cannot resume past the end of the file.".into()),
+            Some(None) => {
+                transfer.recipients.remove(nick);
+            }
+            Some(Some(_)) => return Err("You already accepted this file.".into()),
+            None => return Err("This file was not offered to you.".into()),
+        }
+        if accept && offset == transfer.size {
+            self.done(key, nick);
+        } else if transfer.recipients.is_empty() {
+            self.transfers.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Checks that a chunk of `len` bytes from `from` to `to` is the one
+    /// expected, and moves on to the next one.
+    pub fn chunk(
+        &mut self,
+        from: &str,
+        id: u64,
+        to: &str,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), String> {
+        let key = (from.to_string(), id);
+        let Some(transfer) = self.transfers.get_mut(&key) else {
+            return Err("Unknown transfer.".into());
+        };
+        let Some(Some(next)) = transfer.recipients.get_mut(to) else {
+            return Err(format!("{to} has not accepted this file."));
+        };
+        if offset != *next || offset + len > transfer.size {
+            return Err("Chunk out of order.".into());
+        }
+        *next += len;
+        if *next == transfer.size {
+            self.done(key, to);
+        }
+        Ok(())
+    }
+
+    /// Stops relaying the offer `id` of `from` to `to`.
+    pub fn cancel(&mut self, from: &str, id: u64, to: &str) {
+        self.done((from.to_string(), id), to);
+    }
+
+    /// Forgets what `nick` was sending, and what was sent to them. Returns the
+    /// senders and IDs of the transfers `nick` was receiving.
+    pub fn remove_client(&mut self, nick: &str) -> Vec<(String, u64)> {
+        self.transfers.retain(|(from, _), _| from != nick);
+        let mut cancelled = Vec::new();
+        for ((from, id), transfer) in &mut self.transfers {
+            if transfer.recipients.remove(nick).is_some() {
+                cancelled.push((from.clone(), *id));
+            }
+        }
+        self.transfers
+            .retain(|_, transfer| !transfer.recipients.is_empty());
+        cancelled
+    }
+
+    fn done(&mut self, key: (String, u64), nick: &str) {
+        if let Some(transfer) = self.transfers.get_mut(&key) {
+            transfer.recipients.remove(nick);
+            if transfer.recipients.is_empty() {
+                self.transfers.remove(&key);
+            }
+        }
+    }
+}
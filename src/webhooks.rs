@@ -0,0 +1,352 @@
+//! Outgoing webhooks, for automation that doesn't need a bot: chat events are
+//! POSTed as JSON to the URLs of the config's `[[webhooks]]`.
+
+use std::{
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread::{sleep, spawn},
+    time::Duration,
+};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use yarca::{backoff::Backoff, protocol::ServerFrame};
+
+use crate::Clients;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Retries after the first attempt, before the event is dropped.
+const MAX_RETRIES: u32 = 5;
+/// Events waiting for a hook, beyond which new ones are dropped.
+const QUEUE_LEN: usize = 256;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// Someone posted in a room.
+    Message,
+    /// A message named someone online, `@nick` or not.
+    Mention,
+    /// A message contained one of the hook's keywords.
+    Keyword,
+    Join,
+    Leave,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    url: String,
+    /// Events to send, all of them when empty.
+    #[serde(default)]
+    events: Vec<Event>,
+    /// Rooms to send events of, all of them when empty.
+    #[serde(default)]
+    rooms: Vec<String>,
+    /// Words that trigger `keyword` events, matched case insensitively.
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// Key of the HMAC-SHA256 of the body, sent as `X-Yarca-Signature: sha256=<hex>`.
+    secret: Option<String>,
+}
+
+impl Webhook {
+    fn wants(&self, event: Event, room: &str) -> bool {
+        (self.events.is_empty() || self.events.contains(&event))
+            && (self.rooms.is_empty()
+                || self
+                    .rooms
+                    .iter()
+                    .any(|wanted| wanted.trim_start_matches('#') == room))
+    }
+}
+
+/// Hook along with the queue of its worker thread, which delivers the events
+/// one at a time and stops once the queue is dropped.
+struct Worker {
+    hook: Webhook,
+    queue: SyncSender<Value>,
+}
+
+#[derive(Default)]
+pub struct Webhooks(Vec<Worker>);
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>) -> Self {
+        Webhooks(
+            hooks
+                .into_iter()
+                .map(|hook| {
+                    let (queue, events) = mpsc::sync_channel(QUEUE_LEN);
+                    let worker_hook = hook.clone();
+                    spawn(move || {
+                        for payload in events {
+                            deliver(&worker_hook, payload);
+                        }
+                    });
+                    Worker { hook, queue }
+                })
+                .collect(),
+        )
+    }
+
+    /// Sends the events `frame` stands for to the hooks that want them, in the
+    /// background. Messages may also be mentions of `clients` or have keywords.
+    pub fn notify(&self, frame: &ServerFrame, clients: &Clients) {
+        if self.0.is_empty() {
+            return;
+        }
+        match frame {
+            ServerFrame::Message {
+                id,
+                ts,
+                from,
+                room,
+                body,
+            } => {
+                let payload = |event: Event| {
+                    json!({
+                        "event": event,
+                        "id": id,
+                        "ts": ts,
+                        "room": room,
+                        "nick": from,
+                        "body": body,
+                    })
+                };
+                let mentioned = mentions(body, from, clients);
+                let lower = body.to_lowercase();
+                for worker in &self.0 {
+                    let hook = &worker.hook;
+                    if hook.wants(Event::Message, room) {
+                        worker.push(payload(Event::Message));
+                    }
+                    if !mentioned.is_empty() && hook.wants(Event::Mention, room) {
+                        let mut payload = payload(Event::Mention);
+                        payload["mentioned"] = json!(mentioned);
+                        worker.push(payload);
+                    }
+                    let keywords: Vec<&String> = hook
+                        .keywords
+                        .iter()
+                        .filter(|keyword| {
+                            !keyword.is_empty() && lower.contains(&keyword.to_lowercase())
+                        })
+                        .collect();
+                    if !keywords.is_empty() && hook.wants(Event::Keyword, room) {
+                        let mut payload = payload(Event::Keyword);
+                        payload["keywords"] = json!(keywords);
+                        worker.push(payload);
+                    }
+                }
+            }
+            ServerFrame::Joined { nick, room } | ServerFrame::Left { nick, room } => {
+                let event = match frame {
+                    ServerFrame::Joined { .. } => Event::Join,
+                    _ => Event::Leave,
+                };
+                for worker in self.0.iter().filter(|w| w.hook.wants(event, room)) {
+                    let payload = json!({
+                        "event": event,
+                        "ts": Utc::now(),
+                        "room": room,
+                        "nick": nick,
+                    });
+                    worker.push(payload);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Online nicknames named in `body` as whole words, but its sender's.
+fn mentions(body: &str, from: &str, clients: &Clients) -> Vec<String> {
+    let mut nicks: Vec<String> = body
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || c == '@'))
+        .map(|word| word.trim_start_matches('@'))
+        .filter(|word| *word != from && clients.contains_key(*word))
+        .map(String::from)
+        .collect();
+    nicks.sort();
+    nicks.dedup();
+    nicks
+}
+
+impl Worker {
+    /// Queues an event, dropping it if the hook is too far behind.
+    fn push(&self, payload: Value) {
+        if let Err(TrySendError::Full(payload)) = self.queue.try_send(payload) {
+            warn!(
+                "Webhook {} is {QUEUE_LEN} events behind, dropping the {} event.",
+                self.hook.url,
+                payload["event"].as_str().unwrap_or_default()
+            );
+        }
+    }
+}
+
+/// POSTs `payload` to the hook, retrying with a backoff on network errors and
+/// 5xx or 429 responses.
+fn deliver(hook: &Webhook, payload: Value) {
+    let event = payload["event"].as_str().unwrap_or_default().to_string();
+    let body = payload.to_string();
+    let signature = hook.secret.as_ref().map(|secret| sign(secret, &body));
+    let mut backoff = Backoff::new(
+        Duration::from_secs(1),
+        Duration::from_secs(60),
+        Some(MAX_RETRIES),
+    );
+    loop {
+        let mut request = ureq::post(&hook.url)
+            .timeout(TIMEOUT)
+            .set("Content-Type", "application/json")
+            .set("X-Yarca-Event", &event);
+        if let Some(signature) = &signature {
+            request = request.set("X-Yarca-Signature", signature);
+        }
+        let error = match request.send_string(&body) {
+            Ok(_) => {
+                debug!("Sent {event} event to webhook {}", hook.url);
+                return;
+            }
+            Err(ureq::Error::Status(code, _)) if code < 500 && code != 429 => {
+                warn!("Webhook {} refused the {event} event ({code}).", hook.url);
+                return;
+            }
+            Err(e) => e,
+        };
+        match backoff.next_delay() {
+            Some(delay) => {
+                debug!(
+                    "Webhook {} failed ({error}), retrying in {}s.",
+                    hook.url,
+                    delay.as_secs_f32()
+                );
+                sleep(delay);
+            }
+            None => {
+                warn!(
+                    "Webhook {} failed, dropping the {event} event: {error}",
+                    hook.url
+                );
+                return;
+            }
+        }
+    }
+}
+
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size.");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::Arc,
+    };
+
+    use super::*;
+    use crate::{Client, conn::Conn};
+
+    fn hook(url: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            events: Vec::new(),
+            rooms: Vec::new(),
+            keywords: Vec::new(),
+            secret: None,
+        }
+    }
+
+    #[test]
+    fn signatures_are_hmac_sha256() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn hooks_get_the_events_and_rooms_they_want() {
+        let mut hook = hook("http://localhost");
+        assert!(hook.wants(Event::Join, "general"));
+        hook.events = vec![Event::Message, Event::Mention];
+        hook.rooms = vec!["#ops".to_string(), "dev".to_string()];
+        assert!(hook.wants(Event::Message, "ops"));
+        assert!(hook.wants(Event::Mention, "dev"));
+        assert!(!hook.wants(Event::Join, "ops"));
+        assert!(!hook.wants(Event::Message, "general"));
+    }
+
+    #[test]
+    fn mentions_are_online_nicknames() {
+        let clients: Clients = ["alice", "bob", "carol-2"]
+            .into_iter()
+            .map(|nick| {
+                let client = Client {
+                    conn: Arc::new(Conn::Remote {
+                        server: "b".to_string(),
+                    }),
+                    room: "general".to_string(),
+                    connected: Utc::now(),
+                    operator: false,
+                };
+                (nick.to_string(), client)
+            })
+            .collect();
+        let body = "@bob, carol-2 and bobby: ask alice or @dave. Thanks @bob!";
+        assert_eq!(mentions(body, "alice", &clients), ["bob", "carol-2"]);
+        assert!(mentions("alice!", "alice", &clients).is_empty());
+    }
+
+    /// Answers one request on `listener` with `status`, returning its headers
+    /// and body.
+    fn answer(listener: &TcpListener, status: &str) -> (Vec<String>, String) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            headers.push(line.trim().to_string());
+        }
+        let len = headers
+            .iter()
+            .find_map(|header| header.strip_prefix("Content-Length: "))
+            .map_or(0, |len| len.parse().unwrap());
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).unwrap();
+        let response =
+            format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        reader.get_mut().write_all(response.as_bytes()).unwrap();
+        (headers, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut hook = hook(&format!("http://{}/hook", listener.local_addr().unwrap()));
+        hook.secret = Some("secret".to_string());
+        let payload = json!({ "event": "join", "room": "general", "nick": "alice" });
+        let delivery = spawn(move || deliver(&hook, payload));
+
+        let (_, first) = answer(&listener, "503 Service Unavailable");
+        let (headers, body) = answer(&listener, "200 OK");
+        delivery.join().unwrap();
+        assert_eq!(first, body);
+        assert!(headers.contains(&"X-Yarca-Event: join".to_string()));
+        let signature = format!("X-Yarca-Signature: {}", sign("secret", &body));
+        assert!(headers.contains(&signature), "{headers:?}");
+    }
+}
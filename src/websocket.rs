@@ -0,0 +1,222 @@
+//! WebSocket gateway, for browsers. Frames are the same JSON objects as over
+//! TCP, one per text message, without the encryption. Clients never see the
+//! key: they log in with the gateway token of their nickname, in the `token` of
+//! their `hello` or in an `Authorization: Bearer <token>` header.
+
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc,
+        mpsc::{self, Sender},
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+use tungstenite::{
+    Error, Message, WebSocket,
+    handshake::server::{ErrorResponse, Request, Response},
+    protocol::WebSocketConfig,
+};
+use yarca::protocol::{ClientFrame, MAX_PLAINTEXT_LEN, PROTOCOL_VERSION, ServerFrame};
+
+use crate::{
+    Refusal, ServerMessage, SharedTokens, accept,
+    conn::{Conn, QUEUE_LEN},
+    forward, log,
+    metrics::{self, Transport},
+    valid_nick,
+};
+
+/// How long a read waits before sending the frames queued for the client.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+enum Read {
+    Frame(ClientFrame),
+    Nothing,
+    Invalid(String),
+    Closed,
+}
+
+/// Accepts WebSocket clients on `addr`, in the background.
+pub fn listen(addr: &str, tokens: SharedTokens, tx: Sender<ServerMessage>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("WebSocket gateway listening on {addr}");
+    spawn(move || {
+        for stream in accept(&listener) {
+            match stream {
+                Ok(stream) => {
+                    let (tx, tokens) = (tx.clone(), tokens.clone());
+                    spawn(move || handle(stream, &tokens, &tx));
+                }
+                Err(e) => error!("Error accepting WebSocket connection: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle(stream: TcpStream, tokens: &SharedTokens, tx: &Sender<ServerMessage>) {
+    let ip = stream
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    debug!("New WebSocket connection {ip}");
+
+    let mut header_token = None;
+    // The error type is tungstenite's, an HTTP response.
+    #[allow(clippy::result_large_err)]
+    let read_token = |request: &Request, response: Response| {
+        header_token = request
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        Ok::<_, ErrorResponse>(response)
+    };
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(MAX_PLAINTEXT_LEN))
+        .max_frame_size(Some(MAX_PLAINTEXT_LEN));
+    let mut socket = match tungstenite::accept_hdr_with_config(stream, read_token, Some(config)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            metrics::HANDSHAKE_FAILURES.inc(Transport::WebSocket);
+            warn!("WebSocket handshake with {ip} failed: {e}");
+            return;
+        }
+    };
+    if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)) {
+        warn!("Cannot poll WebSocket of {ip}: {e}");
+        return;
+    }
+
+    let (username, token) = loop {
+        match read(&mut socket) {
+            Read::Frame(ClientFrame::Hello { version, .. }) if version != PROTOCOL_VERSION => {
+                metrics::HANDSHAKE_FAILURES.inc(Transport::WebSocket);
+                warn!("Refused handshake from {ip}: protocol version {version}.");
+                refuse(&mut socket, Refusal::Version(version).to_string());
+                return;
+            }
+            Read::Frame(ClientFrame::Hello { nick, token, .. }) if valid_nick(nick.trim()) => {
+                break (nick.trim().to_string(), token.or(header_token));
+            }
+            Read::Frame(ClientFrame::Hello { nick, .. }) if !nick.trim().is_empty() => {
+                metrics::HANDSHAKE_FAILURES.inc(Transport::WebSocket);
+                warn!("Refused handshake from {ip}: invalid nickname {nick:?}.");
+                refuse(&mut socket, Refusal::Nick.to_string());
+                return;
+            }
+            Read::Nothing => {}
+            Read::Frame(_) | Read::Invalid(_) => {
+                metrics::HANDSHAKE_FAILURES.inc(Transport::WebSocket);
+                warn!("Invalid handshake from {ip}. Disconnecting client.");
+                return;
+            }
+            Read::Closed => {
+                metrics::HANDSHAKE_FAILURES.inc(Transport::WebSocket);
+                warn!("Client {ip} disconnected before sending username.");
+                return;
+            }
+        }
+    };
+
+    let owner = token.and_then(|token| {
+        let tokens = tokens.read().unwrap();
+        tokens.owner(&token).map(String::from)
+    });
+    if owner.as_ref() != Some(&username) {
+        metrics::HANDSHAKE_FAILURES.inc(Transport::WebSocket);
+        warn!(
+            target: log::AUDIT,
+            event = "auth_failed",
+            via = "websocket",
+            nick = %username,
+            %ip,
+            "Wrong or missing WebSocket token"
+        );
+        refuse(
+            &mut socket,
+            "Wrong or missing token, it must be the gateway token of your nickname.".to_string(),
+        );
+        return;
+    }
+
+    let (frames, outgoing) = mpsc::sync_channel(QUEUE_LEN);
+    let conn = match Conn::gateway(frames, socket.get_ref()) {
+        Ok(conn) => Arc::new(conn),
+        Err(e) => {
+            warn!("Cannot set up the WebSocket of {ip}: {e}");
+            return;
+        }
+    };
+    let _ = tx.send(ServerMessage::NewClient(username.clone(), conn.clone()));
+
+    'connection: loop {
+        for frame in outgoing.try_iter() {
+            conn.dequeued();
+            let Some(frame) = frame else {
+                let _ = socket.close(None);
+                let _ = socket.flush();
+                break 'connection;
+            };
+            let json = serde_json::to_string(&frame).expect("Frames always serialize.");
+            metrics::BYTES_SENT.add(json.len() as u64);
+            if let Err(e) = socket.write(Message::text(json)) {
+                warn!("Error writing to client {username}: {e}");
+                break 'connection;
+            }
+        }
+        if let Err(e) = socket.flush() {
+            warn!("Error writing to client {username}: {e}");
+            break;
+        }
+
+        match read(&mut socket) {
+            Read::Frame(frame) => forward(tx, &username, frame),
+            Read::Nothing => {}
+            Read::Invalid(e) => warn!("{e} from {username}. Dropping message."),
+            Read::Closed => {
+                debug!("Client {username} disconnected.");
+                break;
+            }
+        }
+    }
+    let _ = tx.send(ServerMessage::ClientDisconnected(username, conn));
+}
+
+fn read(socket: &mut WebSocket<TcpStream>) -> Read {
+    match socket.read() {
+        Ok(Message::Text(text)) => {
+            metrics::BYTES_RECEIVED.add(text.len() as u64);
+            match serde_json::from_str(&text) {
+                Ok(frame) => Read::Frame(frame),
+                Err(e) => Read::Invalid(format!("Malformed frame ({e})")),
+            }
+        }
+        Ok(Message::Close(_)) => Read::Closed,
+        Ok(Message::Binary(_)) => Read::Invalid("Binary message".into()),
+        // Pings are answered by tungstenite on the next flush.
+        Ok(_) => Read::Nothing,
+        Err(Error::Io(e))
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Read::Nothing
+        }
+        Err(_) => Read::Closed,
+    }
+}
+
+/// Tells the client why it is turned down, then closes the connection.
+fn refuse(socket: &mut WebSocket<TcpStream>, text: String) {
+    let json =
+        serde_json::to_string(&ServerFrame::System { text }).expect("Frames always serialize.");
+    let _ = socket.send(Message::text(json));
+    let _ = socket.close(None);
+    let _ = socket.flush();
+}